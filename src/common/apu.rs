@@ -1,8 +1,18 @@
+use super::nes::Region;
+use super::state::*;
+
 #[derive(Default)]
 struct Divider {
     period: u8,
 }
 impl Divider {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.period);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.period = r.read_u8()?;
+        Ok(())
+    }
     fn clock(&mut self, decay_level_counter: &mut Option<&mut DecayLevelCounter>, loop_flag: bool, period: u8) {
         if self.period == 0 {
             self.load(period);
@@ -23,6 +33,13 @@ struct DecayLevelCounter {
     count: u8,
 }
 impl DecayLevelCounter {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.count);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.count = r.read_u8_max(15)?;
+        Ok(())
+    }
     fn clock(&mut self, loop_flag: bool) {
         if self.count == 0 {
             if loop_flag {
@@ -47,6 +64,23 @@ struct Envelope {
     constant_volume: bool,
 }
 impl Envelope {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.start);
+        self.divider.save_state(w);
+        self.decay_level_counter.save_state(w);
+        w.write_u8(self.output);
+        w.write_u8(self.volume);
+        w.write_bool(self.constant_volume);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.start = r.read_bool()?;
+        self.divider.load_state(r)?;
+        self.decay_level_counter.load_state(r)?;
+        self.output = r.read_u8_max(15)?;
+        self.volume = r.read_u8_max(15)?;
+        self.constant_volume = r.read_bool()?;
+        Ok(())
+    }
     fn clock(&mut self, loop_flag: bool) {
         if self.start {
             self.start = false;
@@ -76,6 +110,25 @@ struct Sweep {
     mute: bool,
 }
 impl Sweep {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.divider.save_state(w);
+        w.write_bool(self.reload_flag);
+        w.write_bool(self.enabled_flag);
+        w.write_u8(self.divider_period);
+        w.write_bool(self.negate_flag);
+        w.write_u8(self.shift_count);
+        w.write_bool(self.mute);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.divider.load_state(r)?;
+        self.reload_flag = r.read_bool()?;
+        self.enabled_flag = r.read_bool()?;
+        self.divider_period = r.read_u8()?;
+        self.negate_flag = r.read_bool()?;
+        self.shift_count = r.read_u8_max(7)?;
+        self.mute = r.read_bool()?;
+        Ok(())
+    }
     fn clock(&mut self, timer: u16, is_pulse_1: bool) -> u16 {
         let mut change_amount = (timer >> self.shift_count) as i16;
         if self.negate_flag {
@@ -139,6 +192,27 @@ impl Triangle {
         self.length_counter_halt = control_flag;
         self.liner_counter_reload_value = counter_reload_value;
     }
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.length_counter_halt);
+        w.write_u8(self.liner_counter_reload_value);
+        w.write_u8(self.liner_counter);
+        w.write_u16(self.timer);
+        w.write_u16(self.current_time);
+        w.write_u8(self.current_sequencer_position);
+        self.length_counter.save_state(w);
+        w.write_bool(self.liner_counter_reload_flag);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.length_counter_halt = r.read_bool()?;
+        self.liner_counter_reload_value = r.read_u8()?;
+        self.liner_counter = r.read_u8()?;
+        self.timer = r.read_u16()?;
+        self.current_time = r.read_u16()?;
+        self.current_sequencer_position = r.read_u8_max(31)?;
+        self.length_counter.load_state(r)?;
+        self.liner_counter_reload_flag = r.read_bool()?;
+        Ok(())
+    }
     #[inline(always)]
     fn clock(&mut self) {
         if self.current_time == 0 {
@@ -195,19 +269,64 @@ impl Default for LinearFeedbackShiftRegister {
     }
 }
 
+/// Timer periods and frame counter steps, which differ between NTSC and PAL APUs
+struct RegionTables {
+    noise_period: [u16; 0x10],
+    dmc_rate: [u16; 0x10],
+    /// APU cycles at which the frame counter clocks: 3 quarter frames, the end of the 4-step
+    /// sequence and the end of the 5-step sequence
+    frame_steps: [u16; 5],
+}
+
+const NTSC_TABLES: RegionTables = RegionTables {
+    noise_period: [
+        4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+    ],
+    dmc_rate: [
+        428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+    ],
+    frame_steps: [3728, 7456, 11185, 14914, 18640],
+};
+
+const PAL_TABLES: RegionTables = RegionTables {
+    noise_period: [4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778],
+    dmc_rate: [
+        398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+    ],
+    frame_steps: [4156, 8313, 12469, 16626, 20782],
+};
+
 #[derive(Default)]
 struct Noise {
     envelope: Envelope,
     shift_register: LinearFeedbackShiftRegister,
+    period_index: u8,
     timer: u16,
     current_time: u16,
     length_counter: LengthCounter,
     length_counter_halt: bool,
 }
 impl Noise {
-    const TIMER_PERIOD: [u16; 0x10] = [
-        4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
-    ];
+    fn save_state(&self, w: &mut StateWriter) {
+        self.envelope.save_state(w);
+        w.write_u16(self.shift_register.register);
+        w.write_bool(self.shift_register.mode_flag);
+        w.write_u8(self.period_index);
+        w.write_u16(self.current_time);
+        self.length_counter.save_state(w);
+        w.write_bool(self.length_counter_halt);
+    }
+    fn load_state(&mut self, r: &mut StateReader, tables: &RegionTables) -> Result<(), StateError> {
+        self.envelope.load_state(r)?;
+        self.shift_register.register = r.read_u16()?;
+        self.shift_register.mode_flag = r.read_bool()?;
+        self.period_index = r.read_u8_max(15)?;
+        self.timer = tables.noise_period[self.period_index as usize];
+        self.current_time = r.read_u16()?;
+        self.length_counter.load_state(r)?;
+        self.length_counter_halt = r.read_bool()?;
+        Ok(())
+    }
     #[inline(always)]
     fn clock(&mut self) {
         if self.current_time == 0 {
@@ -217,8 +336,9 @@ impl Noise {
             self.current_time -= 1;
         }
     }
-    fn set_timer_period(&mut self, rate: u8) {
-        self.timer = Noise::TIMER_PERIOD[rate as usize];
+    fn set_timer_period(&mut self, tables: &RegionTables, period_index: u8) {
+        self.period_index = period_index;
+        self.timer = tables.noise_period[period_index as usize];
     }
     fn clock_envelope(&mut self) {
         self.envelope.clock(self.length_counter_halt);
@@ -256,6 +376,29 @@ impl Pulse {
         [false, true, true, true, true, false, false, false],
         [true, false, false, true, true, true, true, true],
     ];
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.duty);
+        w.write_bool(self.length_counter_halt);
+        w.write_u16(self.timer);
+        w.write_u16(self.current_time);
+        w.write_u8(self.current_sequencer_position);
+        self.envelope.save_state(w);
+        self.sweep.save_state(w);
+        self.length_counter.save_state(w);
+        w.write_u8(self.last_output);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.duty = r.read_u8_max(3)?;
+        self.length_counter_halt = r.read_bool()?;
+        self.timer = r.read_u16()?;
+        self.current_time = r.read_u16()?;
+        self.current_sequencer_position = r.read_u8_max(7)?;
+        self.envelope.load_state(r)?;
+        self.sweep.load_state(r)?;
+        self.length_counter.load_state(r)?;
+        self.last_output = r.read_u8_max(15)?;
+        Ok(())
+    }
     fn clock_envelope(&mut self) {
         self.envelope.clock(self.length_counter_halt);
     }
@@ -327,8 +470,8 @@ impl Default for Dmc {
             irq_enabled: false,
             loop_flag: false,
             rate_index: 0,
-            timer: Self::RATE_TABLE[0],
-            current_time: Self::RATE_TABLE[0],
+            timer: NTSC_TABLES.dmc_rate[0],
+            current_time: NTSC_TABLES.dmc_rate[0],
             output_level: 0,
             sample_address: 0xC000,
             sample_length: 1,
@@ -343,11 +486,45 @@ impl Default for Dmc {
     }
 }
 impl Dmc {
-    const RATE_TABLE: [u16; 16] = [
-        428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
-    ];
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.loop_flag);
+        w.write_u8(self.rate_index);
+        w.write_u16(self.current_time);
+        w.write_u8(self.output_level);
+        w.write_u16(self.sample_address);
+        w.write_u16(self.sample_length);
+        w.write_u16(self.current_address);
+        w.write_u16(self.bytes_remaining);
+        w.write_bool(self.sample_buffer.is_some());
+        w.write_u8(self.sample_buffer.unwrap_or(0));
+        w.write_u8(self.shift_register);
+        w.write_u8(self.bits_remaining);
+        w.write_bool(self.silence_flag);
+        w.write_bool(self.interrupt_flag);
+    }
+    fn load_state(&mut self, r: &mut StateReader, tables: &RegionTables) -> Result<(), StateError> {
+        self.irq_enabled = r.read_bool()?;
+        self.loop_flag = r.read_bool()?;
+        self.rate_index = r.read_u8_max(15)?;
+        self.timer = tables.dmc_rate[self.rate_index as usize];
+        self.current_time = r.read_u16()?;
+        self.output_level = r.read_u8_max(127)?;
+        self.sample_address = r.read_u16()?;
+        self.sample_length = r.read_u16()?;
+        self.current_address = r.read_u16()?;
+        self.bytes_remaining = r.read_u16()?;
+        let has_sample = r.read_bool()?;
+        let sample = r.read_u8()?;
+        self.sample_buffer = if has_sample { Some(sample) } else { None };
+        self.shift_register = r.read_u8()?;
+        self.bits_remaining = r.read_u8()?;
+        self.silence_flag = r.read_bool()?;
+        self.interrupt_flag = r.read_bool()?;
+        Ok(())
+    }
 
-    fn clock(&mut self, prg_rom: &[u8]) {
+    fn clock(&mut self) {
         if self.current_time == 0 {
             self.current_time = self.timer;
             if !self.silence_flag {
@@ -369,27 +546,24 @@ impl Dmc {
                 } else {
                     self.silence_flag = true;
                 }
-                if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
-                    self.fetch_sample(prg_rom);
-                }
             }
         } else {
             self.current_time -= 1;
         }
     }
 
-    fn fetch_sample(&mut self, prg_rom: &[u8]) {
+    /// Address of the next sample byte while the buffer is empty. The CPU fetches it with DMA.
+    fn dma_address(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    fn load_sample(&mut self, value: u8) {
         if self.bytes_remaining > 0 {
-            let addr = self.current_address;
-            let mut rom_addr = (addr.wrapping_sub(0x8000)) as usize;
-            if !prg_rom.is_empty() && rom_addr >= prg_rom.len() {
-                rom_addr %= prg_rom.len();
-            }
-            self.sample_buffer = if prg_rom.is_empty() {
-                Some(0)
-            } else {
-                Some(prg_rom[rom_addr])
-            };
+            self.sample_buffer = Some(value);
             self.current_address = self.current_address.wrapping_add(1);
             if self.current_address == 0 {
                 self.current_address = 0x8000;
@@ -423,57 +597,51 @@ struct FrameCounter {
     interrupt_flag: bool,
 }
 impl FrameCounter {
-    fn clock(&mut self, pulse1: &mut Pulse, pulse2: &mut Pulse, triangle: &mut Triangle, noise: &mut Noise) {
-        match self.count {
-            3728 => {
-                //エンベローブ, 三角波線形カウンタ
-                pulse1.clock_envelope();
-                pulse2.clock_envelope();
-                noise.clock_envelope();
-                triangle.clock_linear_counter();
-            }
-            7456 => {
-                //エンベローブ, 三角波線形カウンタ
-                //長さカウンタ, スイープユニット
-                pulse1.clock_envelope();
-                pulse2.clock_envelope();
-                noise.clock_envelope();
-                triangle.clock_linear_counter();
-                pulse1.clock_length_counter();
-                pulse2.clock_length_counter();
-                noise.clock_length_counter();
-                pulse1.clock_sweep();
-                pulse2.clock_sweep();
-            }
-            11185 => {
-                //エンベローブ, 三角波線形カウンタ
-                pulse1.clock_envelope();
-                pulse2.clock_envelope();
-                noise.clock_envelope();
-                triangle.clock_linear_counter();
-            }
-            14914 => {
-                if !self.mode {
-                    //エンベローブ, 三角波線形カウンタ
-                    //長さカウンタ, スイープユニット
-                    //割り込み
-                    pulse1.clock_envelope();
-                    pulse2.clock_envelope();
-                    noise.clock_envelope();
-                    triangle.clock_linear_counter();
-                    pulse1.clock_length_counter();
-                    pulse2.clock_length_counter();
-                    noise.clock_length_counter();
-                    pulse1.clock_sweep();
-                    pulse2.clock_sweep();
-                    self.count = 0;
-                    self.interrupt_flag |= !self.interrupt_inhibit;
-                    return;
-                }
-            }
-            18640 => {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.mode);
+        w.write_bool(self.interrupt_inhibit);
+        w.write_u16(self.count);
+        w.write_bool(self.interrupt_flag);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.mode = r.read_bool()?;
+        self.interrupt_inhibit = r.read_bool()?;
+        self.count = r.read_u16()?;
+        self.interrupt_flag = r.read_bool()?;
+        Ok(())
+    }
+    fn clock(
+        &mut self,
+        steps: &[u16; 5],
+        pulse1: &mut Pulse,
+        pulse2: &mut Pulse,
+        triangle: &mut Triangle,
+        noise: &mut Noise,
+    ) {
+        let count = self.count;
+        if count == steps[0] || count == steps[2] {
+            //エンベローブ, 三角波線形カウンタ
+            pulse1.clock_envelope();
+            pulse2.clock_envelope();
+            noise.clock_envelope();
+            triangle.clock_linear_counter();
+        } else if count == steps[1] {
+            //エンベローブ, 三角波線形カウンタ
+            //長さカウンタ, スイープユニット
+            pulse1.clock_envelope();
+            pulse2.clock_envelope();
+            noise.clock_envelope();
+            triangle.clock_linear_counter();
+            pulse1.clock_length_counter();
+            pulse2.clock_length_counter();
+            noise.clock_length_counter();
+            pulse1.clock_sweep();
+            pulse2.clock_sweep();
+        } else if count == steps[3] {
+            if !self.mode {
                 //エンベローブ, 三角波線形カウンタ
                 //長さカウンタ, スイープユニット
+                //割り込み
                 pulse1.clock_envelope();
                 pulse2.clock_envelope();
                 noise.clock_envelope();
@@ -484,9 +652,23 @@ impl FrameCounter {
                 pulse1.clock_sweep();
                 pulse2.clock_sweep();
                 self.count = 0;
+                self.interrupt_flag |= !self.interrupt_inhibit;
                 return;
             }
-            _ => {}
+        } else if count == steps[4] {
+            //エンベローブ, 三角波線形カウンタ
+            //長さカウンタ, スイープユニット
+            pulse1.clock_envelope();
+            pulse2.clock_envelope();
+            noise.clock_envelope();
+            triangle.clock_linear_counter();
+            pulse1.clock_length_counter();
+            pulse2.clock_length_counter();
+            noise.clock_length_counter();
+            pulse1.clock_sweep();
+            pulse2.clock_sweep();
+            self.count = 0;
+            return;
         }
         self.count += 1;
     }
@@ -528,6 +710,15 @@ impl LengthCounter {
         10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, //00-0F
         12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30, //10-1F
     ];
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.length);
+        w.write_bool(self.enable);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.length = r.read_u8()?;
+        self.enable = r.read_bool()?;
+        Ok(())
+    }
     fn clock(&mut self, length_counter_halt: bool) {
         if self.enable {
             if !length_counter_halt && self.length != 0 {
@@ -590,6 +781,7 @@ pub struct Apu {
     frame_counter: FrameCounter,
     mixer: Mixer,
     clock_count: u8,
+    tables: &'static RegionTables,
 }
 
 impl Apu {
@@ -603,19 +795,25 @@ impl Apu {
             frame_counter: FrameCounter { mode: false, interrupt_inhibit: false, count: 0, interrupt_flag: false },
             mixer: Mixer::new(),
             clock_count: 0,
+            tables: &NTSC_TABLES,
         }
     }
 
     /// Single CPU cycle clock - returns the raw mixed output
     #[inline(always)]
-    pub fn clock(&mut self, prg_rom: &[u8]) -> f32 {
+    pub fn clock(&mut self) -> f32 {
         if self.clock_count == 0 {
-            self.frame_counter
-                .clock(&mut self.pulse1, &mut self.pulse2, &mut self.triangle, &mut self.noise);
+            self.frame_counter.clock(
+                &self.tables.frame_steps,
+                &mut self.pulse1,
+                &mut self.pulse2,
+                &mut self.triangle,
+                &mut self.noise,
+            );
             self.pulse1.clock();
             self.pulse2.clock();
             self.noise.clock();
-            self.dmc.clock(prg_rom);
+            self.dmc.clock();
         }
         self.triangle.clock();
 
@@ -668,7 +866,7 @@ impl Apu {
             0x0A => self.triangle.set_timer_low(value),
             0x0E => {
                 self.noise.shift_register.mode_flag = value & 0x80 == 0x80;
-                self.noise.set_timer_period(value & 0x0F);
+                self.noise.set_timer_period(self.tables, value & 0x0F);
             }
             0x03 => {
                 self.pulse1.length_counter.set_length((value & 0xF8) >> 3);
@@ -696,7 +894,7 @@ impl Apu {
                 self.dmc.irq_enabled = value & 0x80 != 0;
                 self.dmc.loop_flag = value & 0x40 != 0;
                 self.dmc.rate_index = value & 0x0F;
-                self.dmc.timer = Dmc::RATE_TABLE[self.dmc.rate_index as usize];
+                self.dmc.timer = self.tables.dmc_rate[self.dmc.rate_index as usize];
                 if !self.dmc.irq_enabled {
                     self.dmc.interrupt_flag = false;
                 }
@@ -739,6 +937,15 @@ impl Apu {
     }
 
     pub fn read(&mut self, addr: u8) -> u8 {
+        let value = self.peek(addr);
+        if addr == 0x15 {
+            self.frame_counter.interrupt_flag = false;
+        }
+        value
+    }
+
+    /// What reading a register would return, without clearing the frame interrupt flag
+    pub fn peek(&self, addr: u8) -> u8 {
         match addr {
             0x15 => {
                 let mut value: u8 = 0;
@@ -748,7 +955,6 @@ impl Apu {
                 if self.frame_counter.interrupt_flag {
                     value |= 1 << 6;
                 }
-                self.frame_counter.interrupt_flag = false;
                 if self.dmc.bytes_remaining > 0 {
                     value |= 1 << 4;
                 }
@@ -770,7 +976,63 @@ impl Apu {
         }
     }
 
+    /// Address the DMC wants to read a sample byte from
+    pub fn dmc_dma_address(&self) -> Option<u16> {
+        self.dmc.dma_address()
+    }
+
+    /// Hand the byte read by the DMC DMA to the sample buffer
+    pub fn dmc_dma_complete(&mut self, value: u8) {
+        self.dmc.load_sample(value);
+    }
+
     pub fn check_irq(&self) -> bool {
         self.frame_counter.interrupt_flag || self.dmc.interrupt_flag
     }
+
+    /// Use the rate tables of `region`. Dendy has NTSC tables (its APU runs at an NTSC-like clock).
+    pub fn set_region(&mut self, region: Region) {
+        self.tables = match region {
+            Region::Ntsc | Region::Dendy => &NTSC_TABLES,
+            Region::Pal => &PAL_TABLES,
+        };
+        self.noise.timer = self.tables.noise_period[self.noise.period_index as usize];
+        self.dmc.timer = self.tables.dmc_rate[self.dmc.rate_index as usize];
+    }
+
+    /// Timer period of the noise channel in APU cycles
+    #[cfg(test)]
+    pub fn noise_period(&self) -> u16 {
+        self.noise.timer
+    }
+
+    /// Reset button: all channels silenced via $4015, the triangle sequencer and frame counter
+    /// restarted and the DMC output level cut to its lowest bit
+    pub fn reset(&mut self) {
+        self.write(0x15, 0);
+        self.frame_counter.interrupt_flag = false;
+        self.frame_counter.count = 0;
+        self.triangle.current_sequencer_position = 0;
+        self.dmc.output_level &= 1;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.pulse1.save_state(w);
+        self.pulse2.save_state(w);
+        self.triangle.save_state(w);
+        self.noise.save_state(w);
+        self.dmc.save_state(w);
+        self.frame_counter.save_state(w);
+        w.write_u8(self.clock_count);
+    }
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.pulse1.load_state(r)?;
+        self.pulse2.load_state(r)?;
+        self.triangle.load_state(r)?;
+        self.noise.load_state(r, self.tables)?;
+        self.dmc.load_state(r, self.tables)?;
+        self.frame_counter.load_state(r)?;
+        self.clock_count = r.read_u8_max(1)?;
+        Ok(())
+    }
 }
@@ -1,7 +1,10 @@
 use super::apu::*;
-use super::nes::PadInputs;
+use super::debugger::{CpuRegisters, WatchList};
+use super::disasm;
+use super::nes::{PadInputs, RamPowerOnState, TraceCallback};
 use super::ppu::*;
 use super::rom::*;
+use super::state::*;
 use super::util::*;
 mod bus;
 
@@ -63,27 +66,264 @@ pub struct Cpu {
     bus: bus::Bus,
     op: u8,
     state: CpuState,
+    /// Cycle of the current sequence
     step: u8,
-    addr_l: u8,
-    addr_h: u8,
-    immediate_operand: u8,
-    is_immediate: bool,
-    is_accumulator: bool,
+    /// Effective address (the target while a branch crosses a page)
+    addr: u16,
+    /// Zero page pointer of (ind,X) and (ind),Y
+    pointer: u8,
+    /// Value carried between cycles (RMW value, branch offset, low byte of a jump or vector)
+    data: u8,
+    /// Indexing carried into the high byte of `addr`, which is only fixed by the next cycle
+    page_crossed: bool,
+    /// Interrupt vector, chosen when P is pushed
+    vector: u16,
     reset: bool,
+    /// NMI edge from the PPU, latched at the end of the cycle
     nmi: bool,
-    irq: bool,
-    addressing_overflow: bool,
-    suspend_cycle: u16,
+    need_nmi: bool,
+    prev_need_nmi: bool,
+    run_irq: bool,
+    prev_run_irq: bool,
+    dma: Dma,
+    /// Cycles since power on
+    cycles: u64,
+    /// Receives a nestest.log style line before each instruction
+    tracer: Option<TraceCallback>,
 }
 
 #[derive(Debug)]
 enum CpuState {
     Reset,
-    Nmi,
-    Irq,
-    ReadOpcode,
-    ReadOperand,
-    ExecuteInstruction,
+    /// NMI or IRQ (which one is decided when P is pushed)
+    Interrupt,
+    /// The next cycle fetches an opcode
+    Fetch,
+    Execute,
+}
+
+/// OAM DMA ($4014) and DMC sample fetches. Both wait for the CPU to reach a read cycle, halt it and then
+/// read on get (even) cycles; OAM DMA writes $2004 on put (odd) cycles.
+#[derive(Default)]
+struct Dma {
+    /// A transfer is waiting for the CPU to reach a read cycle
+    need_halt: bool,
+    /// The DMC fetch still needs its dummy cycle
+    need_dummy: bool,
+    /// The CPU is halted
+    running: bool,
+    dmc: bool,
+    oam: bool,
+    oam_page: u8,
+    /// Reads and writes done so far (512 in total)
+    oam_count: u16,
+    oam_value: u8,
+    /// Address the CPU was reading when it was halted. Idle DMA cycles read it again.
+    halt_addr: u16,
+}
+
+impl Dma {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.need_halt);
+        w.write_bool(self.need_dummy);
+        w.write_bool(self.running);
+        w.write_bool(self.dmc);
+        w.write_bool(self.oam);
+        w.write_u8(self.oam_page);
+        w.write_u16(self.oam_count);
+        w.write_u8(self.oam_value);
+        w.write_u16(self.halt_addr);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.need_halt = r.read_bool()?;
+        self.need_dummy = r.read_bool()?;
+        self.running = r.read_bool()?;
+        self.dmc = r.read_bool()?;
+        self.oam = r.read_bool()?;
+        self.oam_page = r.read_u8()?;
+        self.oam_count = r.read_u16()?;
+        self.oam_value = r.read_u8()?;
+        self.halt_addr = r.read_u16()?;
+        Ok(())
+    }
+    /// Drop a DMC fetch that hasn't read its byte yet. A pending OAM DMA keeps its halt.
+    fn cancel_dmc(&mut self) {
+        self.dmc = false;
+        self.need_dummy = false;
+        if !self.oam {
+            self.need_halt = false;
+        }
+    }
+}
+
+/// What the CPU does on one cycle. Every cycle is exactly one bus access (see `Cpu::address`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Cycle {
+    /// Read the opcode at PC
+    Fetch,
+    /// Read PC and ignore the value
+    DummyRead,
+    /// Read PC and skip the byte (BRK's padding)
+    SkipByte,
+    /// Read the operand at PC and run the instruction
+    Immediate,
+    /// Dummy read of PC, then run the implied or accumulator instruction
+    Implied,
+    ZeroPage,
+    /// Dummy read of the zero page address before the index is added
+    ZeroPageIndexed,
+    AbsoluteLow,
+    AbsoluteHigh,
+    /// High byte of an absolute address; the index is added to the low byte only
+    AbsoluteHighIndexed,
+    Pointer,
+    /// Dummy read of the pointer before X is added
+    PointerIndexed,
+    PointerLow,
+    PointerHigh,
+    /// High byte of an (ind),Y address; Y is added to the low byte only
+    PointerHighIndexed,
+    /// Read the indexed address before the carry is fixed. A read instruction that didn't cross a page
+    /// finishes here; the others use it as a dummy read.
+    PageCross,
+    Read,
+    Write,
+    ModifyRead,
+    /// Read-modify-write instructions write the unmodified value back first
+    ModifyDummyWrite,
+    ModifyWrite,
+    BranchOffset,
+    BranchTaken,
+    BranchPageCross,
+    /// High byte of JMP or JSR's operand; jumps
+    JumpHigh,
+    IndirectLow,
+    /// High byte of JMP's indirect target, read without carrying into the pointer's high byte
+    IndirectHigh,
+    StackDummyRead,
+    /// Dummy read of the stack, then increment SP (before a pull)
+    StackIncrement,
+    /// Dummy read of the stack, then decrement SP (the reset sequence doesn't write)
+    StackDecrement,
+    PushPch,
+    PushPcl,
+    PushP,
+    /// PHA and PHP
+    Push,
+    /// PLA and PLP
+    Pull,
+    PullP,
+    PullPcl,
+    PullPch,
+    /// RTS increments the pulled address
+    ReturnIncrement,
+    VectorLow,
+    VectorHigh,
+}
+
+impl Cycle {
+    fn is_write(self) -> bool {
+        matches!(
+            self,
+            Cycle::Write
+                | Cycle::ModifyDummyWrite
+                | Cycle::ModifyWrite
+                | Cycle::PushPch
+                | Cycle::PushPcl
+                | Cycle::PushP
+                | Cycle::Push
+        )
+    }
+}
+
+const RESET_CYCLES: [Cycle; 7] = [
+    Cycle::DummyRead,
+    Cycle::DummyRead,
+    Cycle::StackDecrement,
+    Cycle::StackDecrement,
+    Cycle::StackDecrement,
+    Cycle::VectorLow,
+    Cycle::VectorHigh,
+];
+
+//1サイクル目はオペコードの代わりのダミーリード
+const INTERRUPT_CYCLES: [Cycle; 7] = [
+    Cycle::DummyRead,
+    Cycle::DummyRead,
+    Cycle::PushPch,
+    Cycle::PushPcl,
+    Cycle::PushP,
+    Cycle::VectorLow,
+    Cycle::VectorHigh,
+];
+
+/// How an instruction with a memory operand uses it
+#[derive(PartialEq)]
+enum Operation {
+    Read,
+    Write,
+    /// Read-modify-write
+    Modify,
+}
+
+fn operation(instruction: Instruction) -> Operation {
+    match instruction {
+        Instruction::STA | Instruction::STX | Instruction::STY | Instruction::SAX => Operation::Write,
+        Instruction::ASL
+        | Instruction::LSR
+        | Instruction::ROL
+        | Instruction::ROR
+        | Instruction::INC
+        | Instruction::DEC
+        | Instruction::SLO
+        | Instruction::RLA
+        | Instruction::SRE
+        | Instruction::RRA
+        | Instruction::DCP
+        | Instruction::ISB => Operation::Modify,
+        _ => Operation::Read,
+    }
+}
+
+/// Cycles that compute the effective address of a memory operand
+fn addressing_cycles(mode: AddressingMode) -> &'static [Cycle] {
+    match mode {
+        AddressingMode::ZeroPage => &[Cycle::ZeroPage],
+        AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => &[Cycle::ZeroPage, Cycle::ZeroPageIndexed],
+        AddressingMode::Absolute => &[Cycle::AbsoluteLow, Cycle::AbsoluteHigh],
+        AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => {
+            &[Cycle::AbsoluteLow, Cycle::AbsoluteHighIndexed, Cycle::PageCross]
+        }
+        AddressingMode::IndirectX => &[
+            Cycle::Pointer,
+            Cycle::PointerIndexed,
+            Cycle::PointerLow,
+            Cycle::PointerHigh,
+        ],
+        AddressingMode::IndirectY => &[
+            Cycle::Pointer,
+            Cycle::PointerLow,
+            Cycle::PointerHighIndexed,
+            Cycle::PageCross,
+        ],
+        _ => &[],
+    }
+}
+
+fn operation_cycles(operation: Operation) -> &'static [Cycle] {
+    match operation {
+        Operation::Read => &[Cycle::Read],
+        Operation::Write => &[Cycle::Write],
+        Operation::Modify => &[Cycle::ModifyRead, Cycle::ModifyDummyWrite, Cycle::ModifyWrite],
+    }
+}
+
+/// The rest of the console as seen from the CPU bus
+struct Devices<'a> {
+    rom: Option<&'a mut Rom>,
+    apu: Option<&'a mut Apu>,
+    ppu: Option<&'a mut Ppu>,
+    pad: Option<&'a PadInputs>,
 }
 
 impl Cpu {
@@ -93,34 +333,86 @@ impl Cpu {
             x: 0,
             y: 0,
             pc: 0,
-            sp: 0xFD,
+            sp: 0x00, // the reset sequence leaves it at $FD
             p: ProcessorStatusRegister { n: false, v: false, b: true, d: false, i: true, z: false, c: false },
             bus: bus::Bus::new(),
             op: 0,
             state: CpuState::Reset,
             step: 0,
-            addr_l: 0,
-            addr_h: 0,
-            immediate_operand: 0,
-            is_immediate: false,
-            is_accumulator: false,
+            addr: 0,
+            pointer: 0,
+            data: 0,
+            page_crossed: false,
+            vector: 0xFFFC,
             reset: false,
             nmi: false,
-            irq: false,
-            addressing_overflow: false,
-            suspend_cycle: 0,
+            need_nmi: false,
+            prev_need_nmi: false,
+            run_irq: false,
+            prev_run_irq: false,
+            dma: Dma::default(),
+            cycles: 0,
+            tracer: None,
         }
     }
-    #[allow(dead_code)]
+    /// Run the reset sequence before the next instruction
     pub fn reset(&mut self) {
         self.reset = true;
+        //APUのリセットでDMCが止まるので、取得待ちのDMAも捨てる
+        self.dma.cancel_dmc();
+    }
+    /// Fill internal RAM with the power-on pattern
+    pub fn fill_ram(&mut self, pattern: RamPowerOnState) {
+        self.bus.fill_ram(pattern);
     }
     pub fn nmi(&mut self) {
         self.nmi = true;
     }
-    #[allow(dead_code)]
-    pub fn irq(&mut self) {
-        self.irq = true;
+    pub fn set_tracer(&mut self, tracer: Option<TraceCallback>) {
+        self.tracer = tracer;
+    }
+    pub fn take_tracer(&mut self) -> Option<TraceCallback> {
+        self.tracer.take()
+    }
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+    pub fn registers(&self) -> CpuRegisters {
+        CpuRegisters { a: self.a, x: self.x, y: self.y, p: self.p.read() & !0x10, sp: self.sp, pc: self.pc }
+    }
+    /// Read without side effects (see `Bus::peek`)
+    pub fn peek(&self, rom: &Rom, addr: u16) -> u8 {
+        self.bus.peek(rom, addr)
+    }
+    /// Internal RAM ($0000-$07FF)
+    pub fn ram(&self) -> &[u8] {
+        self.bus.ram()
+    }
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        self.bus.ram_mut()
+    }
+    pub fn watches_mut(&mut self) -> &mut WatchList {
+        &mut self.bus.watches
+    }
+    /// True between instructions: the next clock fetches an opcode
+    pub fn at_instruction_boundary(&self) -> bool {
+        matches!(self.state, CpuState::Fetch)
+            && !self.dma.need_halt
+            && !(self.dma.running && (self.dma.dmc || self.dma.oam))
+    }
+    /// True if the instruction at PC is JSR
+    pub fn at_subroutine_call(&self, rom: &Rom) -> bool {
+        matches!(
+            INSTRUCTION_SET[self.bus.peek(rom, self.pc) as usize].instruction,
+            Instruction::JSR
+        )
+    }
+    /// True if the last instruction fetched is RTS or RTI
+    pub fn is_returning(&self) -> bool {
+        matches!(
+            INSTRUCTION_SET[self.op as usize].instruction,
+            Instruction::RTS | Instruction::RTI
+        )
     }
 
     #[inline(always)]
@@ -129,1067 +421,788 @@ impl Cpu {
         self.p.z = value == 0;
     }
 
-    pub fn clock(&mut self, rom: &Rom, apu: &mut Apu, ppu: &mut Ppu, pad: &PadInputs) {
-        let rom = Some(rom);
-        let apu = &mut Some(apu);
-        let ppu = &mut Some(ppu);
-        let pad = Some(pad);
+    /// One CPU cycle: exactly one read or write on the bus, by the CPU or by DMA
+    pub fn clock(&mut self, rom: &mut Rom, apu: &mut Apu, ppu: &mut Ppu, pad: &PadInputs) {
+        let mut devices = Devices { rom: Some(rom), apu: Some(apu), ppu: Some(ppu), pad: Some(pad) };
+        self.cycles += 1;
+
+        let cycle = self.cycle_at(self.step).unwrap();
+        let finished = if self.dma_cycle(&mut devices, cycle) {
+            false
+        } else if cycle == Cycle::Fetch {
+            self.fetch(&mut devices);
+            false
+        } else {
+            let ended = self.run_cycle(&mut devices, cycle);
+            self.step += 1;
+            ended || self.cycle_at(self.step).is_none()
+        };
 
-        if self.suspend_cycle > 0 {
-            self.suspend_cycle -= 1;
-            return;
+        let irq = devices.apu.as_ref().unwrap().check_irq() || devices.rom.as_ref().unwrap().mapper.irq();
+        self.poll_interrupts(irq);
+        if finished {
+            self.end_instruction();
         }
+    }
 
-        match self.state {
-            CpuState::Reset => {
-                self.p.b = false;
-                self.p.i = true;
-                let addr_l = self.bus.read(rom, apu, ppu, pad, 0xFFFC);
-                let addr_h = self.bus.read(rom, apu, ppu, pad, 0xFFFD);
-                self.pc = get_addr(addr_h, addr_l);
-                self.state = CpuState::ReadOpcode;
-                self.step = 0;
-                return;
-            }
-            CpuState::Nmi => {
-                self.p.b = false;
-                self.push((self.pc >> 8) as u8);
-                self.push(self.pc as u8);
-                self.push(self.p.read());
-                self.p.i = true;
-                let addr_l = self.bus.read(rom, apu, ppu, pad, 0xFFFA);
-                let addr_h = self.bus.read(rom, apu, ppu, pad, 0xFFFB);
-                self.pc = get_addr(addr_h, addr_l);
-                self.state = CpuState::ReadOpcode;
-                self.step = 0;
-                return;
-            }
-            CpuState::Irq => {
-                //Iフラグはチェック済み
-                self.p.b = false;
-                self.push((self.pc >> 8) as u8);
-                self.push(self.pc as u8);
-                self.push(self.p.read());
-                self.p.i = true;
-                let addr_l = self.bus.read(rom, apu, ppu, pad, 0xFFFE);
-                let addr_h = self.bus.read(rom, apu, ppu, pad, 0xFFFF);
-                self.pc = get_addr(addr_h, addr_l);
-                self.state = CpuState::ReadOpcode;
-                self.step = 0;
-                return;
-            }
-            CpuState::ReadOpcode => {
-                //割り込みチェック
-                if self.reset {
-                    self.reset = false;
-                    self.state = CpuState::Reset;
-                    self.step = 0;
-                    return;
-                }
-                if self.nmi {
-                    self.nmi = false;
-                    self.state = CpuState::Nmi;
-                    self.step = 0;
-                    return;
-                }
-                self.irq |= apu.as_ref().unwrap().check_irq();
-                if self.irq {
-                    self.irq = false;
-                    if !self.p.i {
-                        self.state = CpuState::Irq;
-                        self.step = 0;
-                        return;
-                    }
-                }
-                self.is_immediate = false;
-                self.is_accumulator = false;
-                self.addressing_overflow = false;
-                self.op = self.bus.read(rom, apu, ppu, pad, self.pc);
-                let addressing_mode = &INSTRUCTION_SET[self.op as usize].mode;
+    /// Interrupt lines are sampled at the end of every cycle. An instruction is followed by an interrupt
+    /// if one was pending at the end of its second to last cycle.
+    fn poll_interrupts(&mut self, irq: bool) {
+        self.prev_need_nmi = self.need_nmi;
+        if self.nmi {
+            self.nmi = false;
+            self.need_nmi = true;
+        }
+        self.prev_run_irq = self.run_irq;
+        self.run_irq = irq && !self.p.i;
+    }
 
-                //ブレークポイント
-                let break_point: Option<u16> = None; //Some(0x8155);
-                if break_point.is_some() && break_point.unwrap() == self.pc {
-                    println!("Hit break point"); //break here
-                }
+    fn end_instruction(&mut self) {
+        self.step = 0;
+        self.state = if self.reset {
+            self.reset = false;
+            self.vector = 0xFFFC;
+            CpuState::Reset
+        } else if self.prev_need_nmi || self.prev_run_irq {
+            CpuState::Interrupt
+        } else {
+            CpuState::Fetch
+        };
+    }
 
-                //ログ出力
-                let output_log = false;
-                if output_log {
-                    let instruction = &INSTRUCTION_SET[self.op as usize].instruction;
-                    let operand_1 = self.bus.read(rom, apu, ppu, pad, self.pc + 1);
-                    let operand_2 = self.bus.read(rom, apu, ppu, pad, self.pc + 2);
-                    match addressing_mode {
-                        AddressingMode::Accumulator | AddressingMode::Implied => {
-                            println!(
-                                "{:04X}  {:02X}        {:?} {:<27} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
-                                self.pc,
-                                self.op,
-                                instruction,
-                                "",
-                                self.a,
-                                self.x,
-                                self.y,
-                                self.p.read(),
-                                self.sp
-                            );
-                        }
-                        AddressingMode::Immediate
-                        | AddressingMode::ZeroPage
-                        | AddressingMode::ZeroPageX
-                        | AddressingMode::ZeroPageY
-                        | AddressingMode::Relative
-                        | AddressingMode::IndirectX
-                        | AddressingMode::IndirectY => {
-                            let operand = match addressing_mode {
-                                AddressingMode::Immediate => format!("#${:02X}", operand_1),
-                                AddressingMode::ZeroPage | AddressingMode::Relative => format!("${:02X}", operand_1),
-                                AddressingMode::ZeroPageX => format!("${:02X},X", operand_1),
-                                AddressingMode::ZeroPageY => format!("${:02X},Y", operand_1),
-                                AddressingMode::IndirectX => format!("(${:02X},X)", operand_1),
-                                AddressingMode::IndirectY => format!("(${:02X}),Y", operand_1),
-                                _ => panic!(),
-                            };
-                            println!(
-                                "{:04X}  {:02X} {:02X}     {:?} {:<27} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
-                                self.pc,
-                                self.op,
-                                operand_1,
-                                instruction,
-                                operand,
-                                self.a,
-                                self.x,
-                                self.y,
-                                self.p.read(),
-                                self.sp
-                            );
-                        }
-                        AddressingMode::Absolute
-                        | AddressingMode::AbsoluteX
-                        | AddressingMode::AbsoluteY
-                        | AddressingMode::Indirect => {
-                            let operand = match addressing_mode {
-                                AddressingMode::Absolute => format!("${:02X}{:02X}", operand_2, operand_1),
-                                AddressingMode::AbsoluteX => format!("${:02X}{:02X},X", operand_2, operand_1),
-                                AddressingMode::AbsoluteY => format!("${:02X}{:02X},Y", operand_2, operand_1),
-                                AddressingMode::Indirect => format!("(${:02X}{:02X})", operand_2, operand_1),
-                                _ => panic!(),
-                            };
-                            println!(
-                "{:04X}  {:02X} {:02X} {:02X}  {:?} {:<27} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
-                self.pc,
-                self.op,
-                operand_1,
-                operand_2,
-                instruction,
-                operand,
-                self.a,
-                self.x,
-                self.y,
-                self.p.read(),
-                self.sp
-              );
-                        }
-                    }
+    /// Cycle `step` of the current sequence, None past its end
+    fn cycle_at(&self, step: u8) -> Option<Cycle> {
+        let step = step as usize;
+        let (instruction, mode) = decode_opcode(self.op);
+        let sequence: &[Cycle] = match self.state {
+            CpuState::Fetch => &[Cycle::Fetch],
+            CpuState::Reset => &RESET_CYCLES,
+            CpuState::Interrupt => &INTERRUPT_CYCLES,
+            CpuState::Execute => match (instruction, mode) {
+                (Instruction::BRK, _) => &[
+                    Cycle::SkipByte,
+                    Cycle::PushPch,
+                    Cycle::PushPcl,
+                    Cycle::PushP,
+                    Cycle::VectorLow,
+                    Cycle::VectorHigh,
+                ],
+                (Instruction::JSR, _) => &[
+                    Cycle::AbsoluteLow,
+                    Cycle::StackDummyRead,
+                    Cycle::PushPch,
+                    Cycle::PushPcl,
+                    Cycle::JumpHigh,
+                ],
+                (Instruction::RTS, _) => &[
+                    Cycle::DummyRead,
+                    Cycle::StackIncrement,
+                    Cycle::PullPcl,
+                    Cycle::PullPch,
+                    Cycle::ReturnIncrement,
+                ],
+                (Instruction::RTI, _) => &[
+                    Cycle::DummyRead,
+                    Cycle::StackIncrement,
+                    Cycle::PullP,
+                    Cycle::PullPcl,
+                    Cycle::PullPch,
+                ],
+                (Instruction::PHA | Instruction::PHP, _) => &[Cycle::DummyRead, Cycle::Push],
+                (Instruction::PLA | Instruction::PLP, _) => &[Cycle::DummyRead, Cycle::StackIncrement, Cycle::Pull],
+                (Instruction::JMP, AddressingMode::Absolute) => &[Cycle::AbsoluteLow, Cycle::JumpHigh],
+                (Instruction::JMP, _) => &[
+                    Cycle::AbsoluteLow,
+                    Cycle::AbsoluteHigh,
+                    Cycle::IndirectLow,
+                    Cycle::IndirectHigh,
+                ],
+                (_, AddressingMode::Implied | AddressingMode::Accumulator) => &[Cycle::Implied],
+                (_, AddressingMode::Immediate) => &[Cycle::Immediate],
+                (_, AddressingMode::Relative) => &[Cycle::BranchOffset, Cycle::BranchTaken, Cycle::BranchPageCross],
+                _ => {
+                    let addressing = addressing_cycles(mode);
+                    return match step.checked_sub(addressing.len()) {
+                        None => Some(addressing[step]),
+                        Some(step) => operation_cycles(operation(instruction)).get(step).copied(),
+                    };
                 }
+            },
+        };
+        sequence.get(step).copied()
+    }
 
-                self.state = match addressing_mode {
-                    AddressingMode::Accumulator => {
-                        self.is_accumulator = true;
-                        CpuState::ExecuteInstruction
-                    }
-                    AddressingMode::Implied | AddressingMode::Relative => CpuState::ExecuteInstruction,
-                    AddressingMode::Immediate => {
-                        self.pc += 1;
-                        self.immediate_operand = self.bus.read(rom, apu, ppu, pad, self.pc);
-                        self.is_immediate = true;
-                        CpuState::ExecuteInstruction
-                    }
-                    _ => CpuState::ReadOperand,
-                };
+    /// Bus address of `cycle`
+    fn address(&self, cycle: Cycle) -> u16 {
+        match cycle {
+            Cycle::ZeroPageIndexed
+            | Cycle::PageCross
+            | Cycle::Read
+            | Cycle::Write
+            | Cycle::ModifyRead
+            | Cycle::ModifyDummyWrite
+            | Cycle::ModifyWrite
+            | Cycle::IndirectLow => self.addr,
+            //JMP ($xxFF)は上位バイトを同じページの先頭から読む
+            Cycle::IndirectHigh => (self.addr & 0xFF00) | (self.addr.wrapping_add(1) & 0x00FF),
+            Cycle::PointerIndexed | Cycle::PointerLow => self.pointer as u16,
+            Cycle::PointerHigh | Cycle::PointerHighIndexed => self.pointer.wrapping_add(1) as u16,
+            Cycle::StackDummyRead
+            | Cycle::StackIncrement
+            | Cycle::StackDecrement
+            | Cycle::PushPch
+            | Cycle::PushPcl
+            | Cycle::PushP
+            | Cycle::Push
+            | Cycle::Pull
+            | Cycle::PullP
+            | Cycle::PullPcl
+            | Cycle::PullPch => 0x0100 | self.sp as u16,
+            Cycle::VectorLow => self.vector,
+            Cycle::VectorHigh => self.vector.wrapping_add(1),
+            _ => self.pc,
+        }
+    }
 
-                self.pc += 1;
-                self.step = 0;
-                return;
-            }
-            CpuState::ReadOperand => match INSTRUCTION_SET[self.op as usize].mode {
-                AddressingMode::Absolute => match self.step {
-                    0 => {
-                        self.addr_l = self.bus.read(rom, apu, ppu, pad, self.pc);
-                        self.pc += 1;
-                    }
-                    1 => {
-                        self.addr_h = self.bus.read(rom, apu, ppu, pad, self.pc);
-                        self.state = CpuState::ExecuteInstruction;
-                        self.step = 0;
-                        self.pc += 1;
-                        return;
-                    }
-                    _ => panic!(),
-                },
-                AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => match self.step {
-                    0 => {
-                        let (result, overflow) = self.bus.read(rom, apu, ppu, pad, self.pc).overflowing_add(
-                            match INSTRUCTION_SET[self.op as usize].mode {
-                                AddressingMode::AbsoluteX => self.x,
-                                AddressingMode::AbsoluteY => self.y,
-                                _ => panic!(),
-                            },
-                        );
-                        self.addr_l = result;
-                        self.addressing_overflow = overflow;
-                        self.pc += 1;
-                    }
-                    1 => {
-                        self.addr_h = self.bus.read(rom, apu, ppu, pad, self.pc);
-                        if !self.addressing_overflow {
-                            self.state = CpuState::ExecuteInstruction;
-                            self.step = 0;
-                            self.pc += 1;
-                            return;
-                        }
-                    }
-                    2 => {
-                        self.addr_h = self.addr_h.wrapping_add(1);
-                        self.state = CpuState::ExecuteInstruction;
-                        self.step = 0;
-                        self.pc += 1;
-                        return;
-                    }
-                    _ => panic!(),
-                },
-                AddressingMode::ZeroPage => {
-                    self.addr_h = 0x00;
-                    self.addr_l = self.bus.read(rom, apu, ppu, pad, self.pc);
-                    self.state = CpuState::ExecuteInstruction;
-                    self.step = 0;
-                    self.pc += 1;
-                    return;
+    fn read(&mut self, devices: &mut Devices, addr: u16) -> u8 {
+        self.bus
+            .read(&mut devices.rom, &mut devices.apu, &mut devices.ppu, devices.pad, addr)
+    }
+    fn write(&mut self, devices: &mut Devices, addr: u16, value: u8) {
+        self.bus
+            .write(&mut devices.rom, &mut devices.apu, &mut devices.ppu, addr, value);
+        if addr == 0x4014 {
+            self.dma.oam = true;
+            self.dma.oam_page = value;
+            self.dma.oam_count = 0;
+            self.dma.need_halt = true;
+        }
+    }
+
+    /// Run a DMA cycle in place of `cycle` if a transfer is due. Returns false when the CPU gets the bus.
+    fn dma_cycle(&mut self, devices: &mut Devices, cycle: Cycle) -> bool {
+        if !self.dma.dmc && devices.apu.as_ref().unwrap().dmc_dma_address().is_some() {
+            self.dma.dmc = true;
+            self.dma.need_halt = true;
+            self.dma.need_dummy = true;
+        } else if self.dma.dmc && devices.apu.as_ref().unwrap().dmc_dma_address().is_none() {
+            //取得前に$4015でDMCが止められたらDMAを取り消す
+            self.dma.cancel_dmc();
+        }
+        if !self.dma.running {
+            //書き込みサイクルでは止まれない
+            if !self.dma.need_halt || cycle.is_write() {
+                return false;
+            }
+            self.dma.need_halt = false;
+            self.dma.running = true;
+            self.dma.halt_addr = self.address(cycle);
+            self.read(devices, self.dma.halt_addr);
+            return true;
+        }
+        if !self.dma.dmc && !self.dma.oam {
+            self.dma.running = false;
+            return false;
+        }
+
+        let dmc_ready = self.dma.dmc && !self.dma.need_halt && !self.dma.need_dummy;
+        //OAM DMA中のサイクルはDMCのhalt/dummyサイクルを兼ねる
+        if self.dma.need_halt {
+            self.dma.need_halt = false;
+        } else if self.dma.need_dummy {
+            self.dma.need_dummy = false;
+        }
+        //このサイクルの番号(0始まり)が偶数ならget
+        let get = (self.cycles - 1).is_multiple_of(2);
+        if get && dmc_ready {
+            let addr = devices.apu.as_ref().unwrap().dmc_dma_address().unwrap();
+            let value = self.read(devices, addr);
+            devices.apu.as_mut().unwrap().dmc_dma_complete(value);
+            self.dma.dmc = false;
+        } else if get && self.dma.oam {
+            let addr = get_addr(self.dma.oam_page, (self.dma.oam_count / 2) as u8);
+            self.dma.oam_value = self.read(devices, addr);
+            self.dma.oam_count += 1;
+        } else if !get && self.dma.oam && self.dma.oam_count % 2 == 1 {
+            self.write(devices, 0x2004, self.dma.oam_value);
+            self.dma.oam_count += 1;
+            if self.dma.oam_count == 512 {
+                self.dma.oam = false;
+            }
+        } else if !matches!(self.dma.halt_addr, 0x4016 | 0x4017) {
+            //コントローラーは連続した読み込みを1回としか数えないので、止まる前の読み込みだけが効く
+            self.read(devices, self.dma.halt_addr);
+        }
+        true
+    }
+
+    fn fetch(&mut self, devices: &mut Devices) {
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer(&self.trace_line(devices.rom.as_ref().unwrap(), devices.ppu.as_ref().unwrap()));
+            self.tracer = Some(tracer);
+        }
+        self.op = self.read(devices, self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        self.state = CpuState::Execute;
+        self.step = 0;
+    }
+
+    /// Run one cycle of the current sequence. Returns true if the instruction ends early
+    /// (an untaken branch, or a read that didn't cross a page).
+    fn run_cycle(&mut self, devices: &mut Devices, cycle: Cycle) -> bool {
+        let addr = self.address(cycle);
+        let instruction = decode_opcode(self.op).0;
+        match cycle {
+            Cycle::Fetch => unreachable!(),
+            Cycle::DummyRead | Cycle::StackDummyRead => {
+                self.read(devices, addr);
+            }
+            Cycle::SkipByte | Cycle::ReturnIncrement => {
+                self.read(devices, addr);
+                self.pc = self.pc.wrapping_add(1);
+            }
+            Cycle::Immediate => {
+                let value = self.read(devices, addr);
+                self.pc = self.pc.wrapping_add(1);
+                self.operate(value);
+            }
+            Cycle::Implied => {
+                self.read(devices, addr);
+                self.implied();
+            }
+            Cycle::ZeroPage | Cycle::AbsoluteLow => {
+                self.addr = self.read(devices, addr) as u16;
+                self.pc = self.pc.wrapping_add(1);
+            }
+            Cycle::ZeroPageIndexed => {
+                self.read(devices, addr);
+                self.addr = (self.addr as u8).wrapping_add(self.index()) as u16;
+            }
+            Cycle::AbsoluteHigh => {
+                let high = self.read(devices, addr);
+                self.pc = self.pc.wrapping_add(1);
+                self.addr = get_addr(high, self.addr as u8);
+            }
+            Cycle::AbsoluteHighIndexed | Cycle::PointerHighIndexed => {
+                let high = self.read(devices, addr);
+                if cycle == Cycle::AbsoluteHighIndexed {
+                    self.pc = self.pc.wrapping_add(1);
                 }
-                AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => match self.step {
-                    0 => {
-                        self.addr_h = 0x00;
-                        self.addr_l = self.bus.read(rom, apu, ppu, pad, self.pc).wrapping_add(
-                            match INSTRUCTION_SET[self.op as usize].mode {
-                                AddressingMode::ZeroPageX => self.x,
-                                AddressingMode::ZeroPageY => self.y,
-                                _ => panic!(),
-                            },
-                        );
-                    }
-                    1 => {
-                        self.state = CpuState::ExecuteInstruction;
-                        self.step = 0;
-                        self.pc += 1;
-                        return;
-                    }
-                    _ => panic!(),
-                },
-                AddressingMode::Indirect => match self.step {
-                    0 => {
-                        self.addr_l = self.bus.read(rom, apu, ppu, pad, self.pc);
-                        self.pc += 1;
-                    }
-                    1 => {
-                        self.addr_h = self.bus.read(rom, apu, ppu, pad, self.pc);
-                    }
-                    2 => {
-                        let addr = get_addr(self.addr_h, self.addr_l);
-                        let addr2 = get_addr(self.addr_h, self.addr_l.wrapping_add(1));
-                        self.addr_l = self.bus.read(rom, apu, ppu, pad, addr);
-                        self.addr_h = self.bus.read(rom, apu, ppu, pad, addr2);
-                        self.state = CpuState::ExecuteInstruction;
-                        self.step = 0;
-                        self.pc += 1;
-                        return;
-                    }
-                    _ => panic!(),
-                },
-                AddressingMode::IndirectX => match self.step {
-                    0 => {
-                        self.addr_l = self.bus.read(rom, apu, ppu, pad, self.pc).wrapping_add(self.x);
-                    }
-                    1 => {
-                        self.addr_h = 0x00;
-                    }
-                    2 => {
-                        let addr = get_addr(self.addr_h, self.addr_l);
-                        let addr2 = get_addr(self.addr_h, self.addr_l.wrapping_add(1));
-                        self.addr_l = self.bus.read(rom, apu, ppu, pad, addr);
-                        self.addr_h = self.bus.read(rom, apu, ppu, pad, addr2); //3サイクル目でやるのが正解っぽい気がする
-                    }
-                    3 => {
-                        self.state = CpuState::ExecuteInstruction;
-                        self.step = 0;
-                        self.pc += 1;
-                        return;
-                    }
-                    _ => panic!(),
-                },
-                AddressingMode::IndirectY => match self.step {
-                    0 => {
-                        self.addr_l = self.bus.read(rom, apu, ppu, pad, self.pc);
-                    }
-                    1 => {
-                        let addr = get_addr(0x00, self.addr_l);
-                        let addr2 = get_addr(0x00, self.addr_l.wrapping_add(1));
-                        self.addr_l = self.bus.read(rom, apu, ppu, pad, addr);
-                        self.addr_h = self.bus.read(rom, apu, ppu, pad, addr2);
-                    }
-                    2 => {
-                        let (result, overflow) = self.addr_l.overflowing_add(self.y);
-                        self.addr_l = result;
-                        if !overflow {
-                            self.state = CpuState::ExecuteInstruction;
-                            self.step = 0;
-                            self.pc += 1;
-                            return;
-                        }
-                    }
-                    3 => {
-                        self.addr_h = self.addr_h.wrapping_add(1);
-                        self.state = CpuState::ExecuteInstruction;
-                        self.step = 0;
-                        self.pc += 1;
-                        return;
-                    }
-                    _ => panic!(),
-                },
-                _ => panic!(),
-            },
-            CpuState::ExecuteInstruction => {
-                match INSTRUCTION_SET[self.op as usize].instruction {
-                    //演算
-                    Instruction::ADC => {
-                        let operand = if self.is_immediate {
-                            self.immediate_operand
-                        } else {
-                            let addr = get_addr(self.addr_h, self.addr_l);
-                            self.bus.read(rom, apu, ppu, pad, addr)
-                        };
-                        let (result, overflow) = self.a.overflowing_add(operand);
-                        let (result2, overflow2) = result.overflowing_add(if self.p.c { 1 } else { 0 });
-                        self.p.v = ((self.a ^ result2) & (operand ^ result2) & 0x80) != 0;
-                        self.p.c = overflow || overflow2;
-                        self.a = result2;
-                        self.set_nz(self.a);
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    Instruction::SBC => {
-                        let operand = if self.is_immediate {
-                            self.immediate_operand
-                        } else {
-                            let addr = get_addr(self.addr_h, self.addr_l);
-                            self.bus.read(rom, apu, ppu, pad, addr)
-                        };
-                        let (result, overflow) = self.a.overflowing_sub(operand);
-                        let (result2, overflow2) = result.overflowing_sub(if self.p.c { 0 } else { 1 });
-                        self.p.v = ((self.a ^ operand) & (self.a ^ result2) & 0x80) != 0;
-                        self.p.c = !(overflow || overflow2);
-                        self.a = result2;
-                        self.set_nz(self.a);
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    //論理演算
-                    Instruction::AND => {
-                        let operand = if self.is_immediate {
-                            self.immediate_operand
-                        } else {
-                            self.bus.read(rom, apu, ppu, pad, get_addr(self.addr_h, self.addr_l))
-                        };
-                        self.a &= operand;
-                        self.set_nz(self.a);
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    Instruction::ORA => {
-                        let operand = if self.is_immediate {
-                            self.immediate_operand
-                        } else {
-                            self.bus.read(rom, apu, ppu, pad, get_addr(self.addr_h, self.addr_l))
-                        };
-                        self.a |= operand;
-                        self.set_nz(self.a);
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    Instruction::EOR => {
-                        let operand = if self.is_immediate {
-                            self.immediate_operand
-                        } else {
-                            self.bus.read(rom, apu, ppu, pad, get_addr(self.addr_h, self.addr_l))
-                        };
-                        self.a ^= operand;
-                        self.set_nz(self.a);
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    //シフト、ローテーション
-                    Instruction::ASL => {
-                        let addr = get_addr(self.addr_h, self.addr_l);
-                        let mut operand = if self.is_accumulator {
-                            self.a
-                        } else {
-                            self.bus.read(rom, apu, ppu, pad, addr)
-                        };
-                        self.p.c = (operand & 0x80) != 0;
-                        operand <<= 1;
-                        self.set_nz(operand);
-                        if self.is_accumulator {
-                            self.a = operand;
-                        } else {
-                            self.suspend_cycle += self.bus.write(apu, ppu, addr, operand);
-                        }
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    Instruction::LSR => {
-                        let addr = get_addr(self.addr_h, self.addr_l);
-                        let mut operand = if self.is_accumulator {
-                            self.a
-                        } else {
-                            self.bus.read(rom, apu, ppu, pad, addr)
-                        };
-                        self.p.c = (operand & 0x01) != 0;
-                        operand >>= 1;
-                        self.set_nz(operand);
-                        if self.is_accumulator {
-                            self.a = operand;
-                        } else {
-                            self.suspend_cycle += self.bus.write(apu, ppu, addr, operand);
-                        }
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    Instruction::ROL => {
-                        let addr = get_addr(self.addr_h, self.addr_l);
-                        let mut operand = if self.is_accumulator {
-                            self.a
-                        } else {
-                            self.bus.read(rom, apu, ppu, pad, addr)
-                        };
-                        let carry = self.p.c;
-                        self.p.c = (operand & 0x80) != 0;
-                        operand <<= 1;
-                        if carry {
-                            operand |= 1;
-                        }
-                        self.set_nz(operand);
-                        if self.is_accumulator {
-                            self.a = operand;
-                        } else {
-                            self.suspend_cycle += self.bus.write(apu, ppu, addr, operand);
-                        }
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    Instruction::ROR => {
-                        let addr = get_addr(self.addr_h, self.addr_l);
-                        let mut operand = if self.is_accumulator {
-                            self.a
-                        } else {
-                            self.bus.read(rom, apu, ppu, pad, addr)
-                        };
-                        let carry = self.p.c;
-                        self.p.c = (operand & 0x01) != 0;
-                        operand >>= 1;
-                        if carry {
-                            operand |= 0x80;
-                        }
-                        self.set_nz(operand);
-                        if self.is_accumulator {
-                            self.a = operand;
-                        } else {
-                            self.suspend_cycle += self.bus.write(apu, ppu, addr, operand);
-                        }
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    //条件分岐
-                    Instruction::BCC
-                    | Instruction::BCS
-                    | Instruction::BEQ
-                    | Instruction::BNE
-                    | Instruction::BVC
-                    | Instruction::BVS
-                    | Instruction::BPL
-                    | Instruction::BMI => {
-                        let jump = self.step != 0
-                            || match INSTRUCTION_SET[self.op as usize].instruction {
-                                Instruction::BCC => !self.p.c,
-                                Instruction::BCS => self.p.c,
-                                Instruction::BEQ => self.p.z,
-                                Instruction::BNE => !self.p.z,
-                                Instruction::BVC => !self.p.v,
-                                Instruction::BVS => self.p.v,
-                                Instruction::BPL => !self.p.n,
-                                Instruction::BMI => self.p.n,
-                                _ => panic!(),
-                            };
-                        if jump {
-                            match self.step {
-                                0 => {
-                                    let offset = self.bus.read(rom, apu, ppu, pad, self.pc) as i8 as i16;
-                                    let last_pc = self.pc;
-                                    self.pc += 1;
-                                    let page_cross = (last_pc & 0xFF00) != (self.pc & 0xFF00);
-                                    let last_pc2 = self.pc;
-                                    self.pc = ((self.pc as i16) + offset) as u16;
-                                    let page_cross2 = (last_pc2 & 0xFF00) != (self.pc & 0xFF00);
-                                    if !page_cross && !page_cross2 {
-                                        self.state = CpuState::ReadOpcode;
-                                        self.step = 0;
-                                        return;
-                                    }
-                                }
-                                1 => {
-                                    self.state = CpuState::ReadOpcode;
-                                    self.step = 0;
-                                    return;
-                                }
-                                _ => panic!(),
-                            }
-                        } else {
-                            self.pc += 1;
-                            self.state = CpuState::ReadOpcode;
-                            self.step = 0;
-                            return;
-                        }
-                    }
-                    //ビット検査
-                    Instruction::BIT => {
-                        let operand = if self.is_immediate {
-                            self.immediate_operand
-                        } else {
-                            self.bus.read(rom, apu, ppu, pad, get_addr(self.addr_h, self.addr_l))
-                        };
-                        let result = self.a & operand;
-                        self.p.n = (operand & 0x80) != 0;
-                        self.p.v = (operand & 0x40) != 0;
-                        self.p.z = result == 0;
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    //ジャンプ
-                    Instruction::JMP => match self.step {
-                        0 => {
-                            self.pc &= 0xFF00;
-                            self.pc |= self.addr_l as u16;
-                        }
-                        1 => {
-                            self.pc &= 0x00FF;
-                            self.pc |= (self.addr_h as u16) << 8;
-                            self.state = CpuState::ReadOpcode;
-                            self.step = 0;
-                            return;
-                        }
-                        _ => panic!(),
-                    },
-                    Instruction::JSR => {
-                        let pc = self.pc.wrapping_sub(1);
-                        match self.step {
-                            0 => self.push((pc >> 8) as u8),
-                            1 => self.push(pc as u8),
-                            2 => {
-                                self.pc = get_addr(self.addr_h, self.addr_l);
-                                self.state = CpuState::ReadOpcode;
-                                self.step = 0;
-                                return;
-                            }
-                            _ => panic!(),
-                        }
-                    }
-                    Instruction::RTS => match self.step {
-                        0 | 1 => {}
-                        2 => {
-                            self.pc &= 0xFF00;
-                            self.pc |= self.pop() as u16;
-                        }
-                        3 => {
-                            self.pc &= 0x00FF;
-                            self.pc |= (self.pop() as u16) << 8;
-                        }
-                        4 => {
-                            self.pc += 1;
-                            self.state = CpuState::ReadOpcode;
-                            self.step = 0;
-                            return;
-                        }
-                        _ => panic!(),
-                    },
-                    //割り込み
-                    Instruction::BRK => match self.step {
-                        0 => {
-                            if self.p.i {
-                                self.state = CpuState::ReadOpcode;
-                                self.step = 0;
-                                return;
-                            }
-                            self.p.b = true;
-                            self.pc += 1;
-                        }
-                        1 => self.push((self.pc >> 8) as u8),
-                        2 => self.push(self.pc as u8),
-                        3 => self.push(self.p.read()),
-                        4 => {
-                            self.p.i = true;
-                            self.pc &= 0x00FF;
-                            self.pc |= (self.bus.read(rom, apu, ppu, pad, 0xFFFE) as u16) << 8;
-                        }
-                        5 => {
-                            self.pc &= 0xFF00;
-                            self.pc |= self.bus.read(rom, apu, ppu, pad, 0xFFFF) as u16;
-                            self.state = CpuState::ReadOpcode;
-                            self.step = 0;
-                            return;
-                        }
-                        _ => panic!(),
-                    },
-                    Instruction::RTI => match self.step {
-                        0 | 1 => {}
-                        2 => {
-                            let result = self.pop();
-                            self.p.write(result);
-                        }
-                        3 => {
-                            self.pc &= 0xFF00;
-                            self.pc |= self.pop() as u16;
-                        }
-                        4 => {
-                            self.pc &= 0x00FF;
-                            self.pc |= (self.pop() as u16) << 8;
-                            self.state = CpuState::ReadOpcode;
-                            self.step = 0;
-                            return;
-                        }
-                        _ => panic!(),
-                    },
-                    //比較
-                    Instruction::CMP | Instruction::CPX | Instruction::CPY => {
-                        let operand = if self.is_immediate {
-                            self.immediate_operand
+                let (low, carry) = (self.addr as u8).overflowing_add(self.index());
+                self.page_crossed = carry;
+                self.addr = get_addr(high, low);
+            }
+            Cycle::Pointer => {
+                self.pointer = self.read(devices, addr);
+                self.pc = self.pc.wrapping_add(1);
+            }
+            Cycle::PointerIndexed => {
+                self.read(devices, addr);
+                self.pointer = self.pointer.wrapping_add(self.x);
+            }
+            Cycle::PointerLow => {
+                self.addr = self.read(devices, addr) as u16;
+            }
+            Cycle::PointerHigh => {
+                let high = self.read(devices, addr);
+                self.addr = get_addr(high, self.addr as u8);
+            }
+            Cycle::PageCross => {
+                let value = self.read(devices, addr);
+                if self.page_crossed {
+                    self.addr = self.addr.wrapping_add(0x100);
+                } else if operation(instruction) == Operation::Read {
+                    self.operate(value);
+                    return true;
+                }
+            }
+            Cycle::Read => {
+                let value = self.read(devices, addr);
+                self.operate(value);
+            }
+            Cycle::Write => {
+                let value = match instruction {
+                    Instruction::STX => self.x,
+                    Instruction::STY => self.y,
+                    Instruction::SAX => self.a & self.x,
+                    _ => self.a,
+                };
+                self.write(devices, addr, value);
+            }
+            Cycle::ModifyRead => {
+                self.data = self.read(devices, addr);
+            }
+            Cycle::ModifyDummyWrite => {
+                self.write(devices, addr, self.data);
+                self.data = self.modify(self.data);
+            }
+            Cycle::ModifyWrite => {
+                self.write(devices, addr, self.data);
+            }
+            Cycle::BranchOffset => {
+                self.data = self.read(devices, addr);
+                self.pc = self.pc.wrapping_add(1);
+                if !self.branch_taken() {
+                    return true;
+                }
+            }
+            Cycle::BranchTaken => {
+                //ページをまたがずに分岐した場合、直前に発生したIRQは次の命令の後まで待たされる
+                if self.run_irq && !self.prev_run_irq {
+                    self.run_irq = false;
+                }
+                self.read(devices, addr);
+                let target = self.pc.wrapping_add(self.data as i8 as u16);
+                if (target & 0xFF00) == (self.pc & 0xFF00) {
+                    self.pc = target;
+                    return true;
+                }
+                self.pc = (self.pc & 0xFF00) | (target & 0x00FF);
+                self.addr = target;
+            }
+            Cycle::BranchPageCross => {
+                self.read(devices, addr);
+                self.pc = self.addr;
+            }
+            Cycle::JumpHigh => {
+                let high = self.read(devices, addr);
+                self.pc = get_addr(high, self.addr as u8);
+            }
+            Cycle::IndirectLow => {
+                self.data = self.read(devices, addr);
+            }
+            Cycle::IndirectHigh => {
+                let high = self.read(devices, addr);
+                self.pc = get_addr(high, self.data);
+            }
+            Cycle::StackIncrement => {
+                self.read(devices, addr);
+                self.sp = self.sp.wrapping_add(1);
+            }
+            Cycle::StackDecrement => {
+                self.read(devices, addr);
+                self.sp = self.sp.wrapping_sub(1);
+            }
+            Cycle::PushPch | Cycle::PushPcl | Cycle::PushP | Cycle::Push => {
+                let value = match cycle {
+                    Cycle::PushPch => (self.pc >> 8) as u8,
+                    Cycle::PushPcl => self.pc as u8,
+                    Cycle::PushP => {
+                        //ここまでにNMIが来ていればBRKやIRQもNMIのベクタに飛ぶ
+                        self.vector = if self.need_nmi {
+                            self.need_nmi = false;
+                            0xFFFA
                         } else {
-                            self.bus.read(rom, apu, ppu, pad, get_addr(self.addr_h, self.addr_l))
+                            0xFFFE
                         };
-                        let reg = match INSTRUCTION_SET[self.op as usize].instruction {
-                            Instruction::CMP => self.a,
-                            Instruction::CPX => self.x,
-                            Instruction::CPY => self.y,
-                            _ => panic!(),
-                        };
-                        let (result, overflow) = reg.overflowing_sub(operand);
-                        self.set_nz(result);
-                        self.p.c = !overflow;
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
+                        let brk = matches!(self.state, CpuState::Execute);
+                        (self.p.read() & !0x10) | if brk { 0x10 } else { 0 }
                     }
-                    //インクリメント、デクリメント
-                    Instruction::INC | Instruction::DEC => match self.step {
-                        0 => {}
-                        1 => {
-                            let operand = if self.is_immediate {
-                                self.immediate_operand
-                            } else {
-                                self.bus.read(rom, apu, ppu, pad, get_addr(self.addr_h, self.addr_l))
-                            };
-                            let operand = match INSTRUCTION_SET[self.op as usize].instruction {
-                                Instruction::INC => operand.wrapping_add(1),
-                                Instruction::DEC => operand.wrapping_sub(1),
-                                _ => panic!(),
-                            };
-                            self.set_nz(operand);
-                            let addr = get_addr(self.addr_h, self.addr_l);
-                            self.suspend_cycle += self.bus.write(apu, ppu, addr, operand);
-                        }
-                        2 => {
-                            self.state = CpuState::ReadOpcode;
-                            self.step = 0;
-                            return;
-                        }
-                        _ => panic!(),
+                    _ => match instruction {
+                        Instruction::PHA => self.a,
+                        _ => self.p.read() | 0b0011_0000,
                     },
-                    Instruction::INX | Instruction::DEX => {
-                        self.x = match INSTRUCTION_SET[self.op as usize].instruction {
-                            Instruction::INX => self.x.wrapping_add(1),
-                            Instruction::DEX => self.x.wrapping_sub(1),
-                            _ => panic!(),
-                        };
-                        self.set_nz(self.x);
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    Instruction::INY | Instruction::DEY => {
-                        self.y = match INSTRUCTION_SET[self.op as usize].instruction {
-                            Instruction::INY => self.y.wrapping_add(1),
-                            Instruction::DEY => self.y.wrapping_sub(1),
-                            _ => panic!(),
-                        };
-                        self.set_nz(self.y);
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    //フラグ操作
-                    Instruction::CLC => {
-                        self.p.c = false;
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    Instruction::SEC => {
-                        self.p.c = true;
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    Instruction::CLI => {
-                        self.p.i = false;
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    Instruction::SEI => {
-                        self.p.i = true;
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    Instruction::CLD => {
-                        self.p.d = false;
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    Instruction::SED => {
-                        self.p.d = true;
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    Instruction::CLV => {
-                        self.p.v = false;
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    //ロード
-                    Instruction::LDA => {
-                        self.a = if self.is_immediate {
-                            self.immediate_operand
-                        } else {
-                            self.bus.read(rom, apu, ppu, pad, get_addr(self.addr_h, self.addr_l))
-                        };
+                };
+                self.write(devices, addr, value);
+                self.sp = self.sp.wrapping_sub(1);
+            }
+            Cycle::Pull => {
+                let value = self.read(devices, addr);
+                if instruction == Instruction::PLA {
+                    self.a = value;
+                    self.set_nz(value);
+                } else {
+                    self.p.write(value & 0b1110_1111); //割り込みじゃないのでBフラグを落とす
+                }
+            }
+            Cycle::PullP => {
+                let value = self.read(devices, addr);
+                self.p.write(value & 0b1110_1111);
+                self.sp = self.sp.wrapping_add(1);
+            }
+            Cycle::PullPcl => {
+                let low = self.read(devices, addr);
+                self.pc = (self.pc & 0xFF00) | low as u16;
+                self.sp = self.sp.wrapping_add(1);
+            }
+            Cycle::PullPch => {
+                let high = self.read(devices, addr);
+                self.pc = get_addr(high, self.pc as u8);
+            }
+            Cycle::VectorLow => {
+                self.data = self.read(devices, addr);
+                self.p.b = false;
+                self.p.i = true;
+            }
+            Cycle::VectorHigh => {
+                let high = self.read(devices, addr);
+                self.pc = get_addr(high, self.data);
+            }
+        }
+        false
+    }
+
+    /// Index register of the current addressing mode
+    fn index(&self) -> u8 {
+        match decode_opcode(self.op).1 {
+            AddressingMode::ZeroPageY | AddressingMode::AbsoluteY | AddressingMode::IndirectY => self.y,
+            _ => self.x,
+        }
+    }
+
+    fn branch_taken(&self) -> bool {
+        match decode_opcode(self.op).0 {
+            Instruction::BCC => !self.p.c,
+            Instruction::BCS => self.p.c,
+            Instruction::BEQ => self.p.z,
+            Instruction::BNE => !self.p.z,
+            Instruction::BVC => !self.p.v,
+            Instruction::BVS => self.p.v,
+            Instruction::BPL => !self.p.n,
+            _ => self.p.n,
+        }
+    }
+
+    /// Read instructions (and the immediate forms)
+    fn operate(&mut self, value: u8) {
+        match decode_opcode(self.op).0 {
+            Instruction::ADC => self.add(value),
+            //A - M - (1 - C) = A + !M + C
+            Instruction::SBC => self.add(!value),
+            Instruction::AND => {
+                self.a &= value;
+                self.set_nz(self.a);
+            }
+            Instruction::ORA => {
+                self.a |= value;
+                self.set_nz(self.a);
+            }
+            Instruction::EOR => {
+                self.a ^= value;
+                self.set_nz(self.a);
+            }
+            Instruction::BIT => {
+                self.p.n = (value & 0x80) != 0;
+                self.p.v = (value & 0x40) != 0;
+                self.p.z = (self.a & value) == 0;
+            }
+            Instruction::CMP => self.compare(self.a, value),
+            Instruction::CPX => self.compare(self.x, value),
+            Instruction::CPY => self.compare(self.y, value),
+            Instruction::LDA => {
+                self.a = value;
+                self.set_nz(value);
+            }
+            Instruction::LDX => {
+                self.x = value;
+                self.set_nz(value);
+            }
+            Instruction::LDY => {
+                self.y = value;
+                self.set_nz(value);
+            }
+            // LAX: LDA + LDX
+            Instruction::LAX => {
+                self.a = value;
+                self.x = value;
+                self.set_nz(value);
+            }
+            // Multi-byte NOPs read their operand and do nothing
+            _ => {}
+        }
+    }
+
+    /// Read-modify-write instructions: returns the value to write back
+    fn modify(&mut self, value: u8) -> u8 {
+        let instruction = decode_opcode(self.op).0;
+        match instruction {
+            Instruction::INC => {
+                let result = value.wrapping_add(1);
+                self.set_nz(result);
+                result
+            }
+            Instruction::DEC => {
+                let result = value.wrapping_sub(1);
+                self.set_nz(result);
+                result
+            }
+            // DCP: DEC + CMP
+            Instruction::DCP => {
+                let result = value.wrapping_sub(1);
+                self.compare(self.a, result);
+                result
+            }
+            // ISB: INC + SBC
+            Instruction::ISB => {
+                let result = value.wrapping_add(1);
+                self.add(!result);
+                result
+            }
+            _ => {
+                let result = self.shift(instruction, value);
+                match instruction {
+                    // SLO: ASL + ORA
+                    Instruction::SLO => {
+                        self.a |= result;
                         self.set_nz(self.a);
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
                     }
-                    Instruction::LDX => {
-                        self.x = if self.is_immediate {
-                            self.immediate_operand
-                        } else {
-                            self.bus.read(rom, apu, ppu, pad, get_addr(self.addr_h, self.addr_l))
-                        };
-                        self.set_nz(self.x);
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    Instruction::LDY => {
-                        self.y = if self.is_immediate {
-                            self.immediate_operand
-                        } else {
-                            self.bus.read(rom, apu, ppu, pad, get_addr(self.addr_h, self.addr_l))
-                        };
-                        self.set_nz(self.y);
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    //ストア
-                    Instruction::STA | Instruction::STX | Instruction::STY => {
-                        let addr = get_addr(self.addr_h, self.addr_l);
-                        let val = match INSTRUCTION_SET[self.op as usize].instruction {
-                            Instruction::STA => self.a,
-                            Instruction::STX => self.x,
-                            Instruction::STY => self.y,
-                            _ => panic!(),
-                        };
-                        self.suspend_cycle += self.bus.write(apu, ppu, addr, val);
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    //レジスタ間転送
-                    Instruction::TAX => {
-                        self.x = self.a;
-                        self.set_nz(self.x);
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    Instruction::TSX => {
-                        self.x = self.sp;
-                        self.set_nz(self.x);
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    Instruction::TXA => {
-                        self.a = self.x;
+                    // RLA: ROL + AND
+                    Instruction::RLA => {
+                        self.a &= result;
                         self.set_nz(self.a);
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
                     }
-                    Instruction::TYA => {
-                        self.a = self.y;
+                    // SRE: LSR + EOR
+                    Instruction::SRE => {
+                        self.a ^= result;
                         self.set_nz(self.a);
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    Instruction::TAY => {
-                        self.y = self.a;
-                        self.set_nz(self.y);
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
                     }
-                    Instruction::TXS => {
-                        self.sp = self.x;
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    //スタック
-                    Instruction::PHA | Instruction::PHP => match self.step {
-                        0 => {}
-                        1 => {
-                            self.push(match INSTRUCTION_SET[self.op as usize].instruction {
-                                Instruction::PHA => self.a,
-                                Instruction::PHP => self.p.read() | 0b00110000,
-                                _ => panic!(),
-                            });
-                            self.state = CpuState::ReadOpcode;
-                            self.step = 0;
-                        }
-                        _ => panic!(),
-                    },
-                    Instruction::PLA => match self.step {
-                        0 | 1 => {}
-                        2 => {
-                            self.a = self.pop();
-                            self.set_nz(self.a);
-                            self.state = CpuState::ReadOpcode;
-                            self.step = 0;
-                        }
-                        _ => panic!(),
-                    },
-                    Instruction::PLP => match self.step {
-                        0 | 1 => {}
-                        2 => {
-                            let result = self.pop() & 0b1110_1111; //割り込みじゃないのでBフラグを落とす
-                            self.p.write(result);
-                            self.state = CpuState::ReadOpcode;
-                            self.step = 0;
-                        }
-                        _ => panic!(),
-                    },
-                    Instruction::NOP => {
-                        // Multi-byte NOPs: consume the addressing mode bytes but do nothing
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                    }
-                    // ===== Unofficial Opcodes =====
-                    // LAX: LDA + LDX
-                    Instruction::LAX => {
-                        let operand = if self.is_immediate {
-                            self.immediate_operand
-                        } else {
-                            self.bus.read(rom, apu, ppu, pad, get_addr(self.addr_h, self.addr_l))
-                        };
-                        self.a = operand;
-                        self.x = operand;
-                        self.set_nz(operand);
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    // SAX: Store A & X
-                    Instruction::SAX => {
-                        let addr = get_addr(self.addr_h, self.addr_l);
-                        self.suspend_cycle += self.bus.write(apu, ppu, addr, self.a & self.x);
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                        return;
-                    }
-                    // DCP: DEC + CMP
-                    Instruction::DCP => match self.step {
-                        0 => {}
-                        1 => {
-                            let addr = get_addr(self.addr_h, self.addr_l);
-                            let mut operand = self.bus.read(rom, apu, ppu, pad, addr);
-                            operand = operand.wrapping_sub(1);
-                            self.suspend_cycle += self.bus.write(apu, ppu, addr, operand);
-                            let (result, overflow) = self.a.overflowing_sub(operand);
-                            self.set_nz(result);
-                            self.p.c = !overflow;
-                        }
-                        2 => {
-                            self.state = CpuState::ReadOpcode;
-                            self.step = 0;
-                            return;
-                        }
-                        _ => panic!(),
-                    },
-                    // ISB (ISC): INC + SBC
-                    Instruction::ISB => match self.step {
-                        0 => {}
-                        1 => {
-                            let addr = get_addr(self.addr_h, self.addr_l);
-                            let mut operand = self.bus.read(rom, apu, ppu, pad, addr);
-                            operand = operand.wrapping_add(1);
-                            self.suspend_cycle += self.bus.write(apu, ppu, addr, operand);
-                            let (result, overflow) = self.a.overflowing_sub(operand);
-                            let (result2, overflow2) = result.overflowing_sub(if self.p.c { 0 } else { 1 });
-                            self.p.v = ((self.a ^ operand) & (self.a ^ result2) & 0x80) != 0;
-                            self.p.c = !(overflow || overflow2);
-                            self.a = result2;
-                            self.set_nz(self.a);
-                        }
-                        2 => {
-                            self.state = CpuState::ReadOpcode;
-                            self.step = 0;
-                            return;
-                        }
-                        _ => panic!(),
-                    },
-                    // SLO: ASL + ORA
-                    Instruction::SLO => match self.step {
-                        0 => {}
-                        1 => {
-                            let addr = get_addr(self.addr_h, self.addr_l);
-                            let mut operand = self.bus.read(rom, apu, ppu, pad, addr);
-                            self.p.c = (operand & 0x80) != 0;
-                            operand <<= 1;
-                            self.suspend_cycle += self.bus.write(apu, ppu, addr, operand);
-                            self.a |= operand;
-                            self.set_nz(self.a);
-                        }
-                        2 => {
-                            self.state = CpuState::ReadOpcode;
-                            self.step = 0;
-                            return;
-                        }
-                        _ => panic!(),
-                    },
-                    // RLA: ROL + AND
-                    Instruction::RLA => match self.step {
-                        0 => {}
-                        1 => {
-                            let addr = get_addr(self.addr_h, self.addr_l);
-                            let mut operand = self.bus.read(rom, apu, ppu, pad, addr);
-                            let carry = self.p.c;
-                            self.p.c = (operand & 0x80) != 0;
-                            operand <<= 1;
-                            if carry {
-                                operand |= 1;
-                            }
-                            self.suspend_cycle += self.bus.write(apu, ppu, addr, operand);
-                            self.a &= operand;
-                            self.set_nz(self.a);
-                        }
-                        2 => {
-                            self.state = CpuState::ReadOpcode;
-                            self.step = 0;
-                            return;
-                        }
-                        _ => panic!(),
-                    },
-                    // SRE: LSR + EOR
-                    Instruction::SRE => match self.step {
-                        0 => {}
-                        1 => {
-                            let addr = get_addr(self.addr_h, self.addr_l);
-                            let mut operand = self.bus.read(rom, apu, ppu, pad, addr);
-                            self.p.c = (operand & 0x01) != 0;
-                            operand >>= 1;
-                            self.suspend_cycle += self.bus.write(apu, ppu, addr, operand);
-                            self.a ^= operand;
-                            self.set_nz(self.a);
-                        }
-                        2 => {
-                            self.state = CpuState::ReadOpcode;
-                            self.step = 0;
-                            return;
-                        }
-                        _ => panic!(),
-                    },
                     // RRA: ROR + ADC
-                    Instruction::RRA => match self.step {
-                        0 => {}
-                        1 => {
-                            let addr = get_addr(self.addr_h, self.addr_l);
-                            let mut operand = self.bus.read(rom, apu, ppu, pad, addr);
-                            let carry = self.p.c;
-                            self.p.c = (operand & 0x01) != 0;
-                            operand >>= 1;
-                            if carry {
-                                operand |= 0x80;
-                            }
-                            self.suspend_cycle += self.bus.write(apu, ppu, addr, operand);
-                            let (result, ov) = self.a.overflowing_add(operand);
-                            let (result2, ov2) = result.overflowing_add(if self.p.c { 1 } else { 0 });
-                            self.p.v = ((self.a ^ result2) & (operand ^ result2) & 0x80) != 0;
-                            self.p.c = ov || ov2;
-                            self.a = result2;
-                            self.set_nz(self.a);
-                        }
-                        2 => {
-                            self.state = CpuState::ReadOpcode;
-                            self.step = 0;
-                            return;
-                        }
-                        _ => panic!(),
-                    },
-                    Instruction::Undefined => {
-                        // Treat as NOP
-                        self.state = CpuState::ReadOpcode;
-                        self.step = 0;
-                    }
+                    Instruction::RRA => self.add(result),
+                    _ => self.set_nz(result),
                 }
+                result
             }
         }
-        self.step += 1;
     }
 
-    fn push(&mut self, value: u8) {
-        self.suspend_cycle += self.bus.write(&mut None, &mut None, 0x0100 | self.sp as u16, value);
-        self.sp = self.sp.wrapping_sub(1);
+    /// Implied and accumulator instructions
+    fn implied(&mut self) {
+        let instruction = decode_opcode(self.op).0;
+        match instruction {
+            Instruction::ASL | Instruction::LSR | Instruction::ROL | Instruction::ROR => {
+                self.a = self.shift(instruction, self.a);
+                self.set_nz(self.a);
+            }
+            Instruction::INX => {
+                self.x = self.x.wrapping_add(1);
+                self.set_nz(self.x);
+            }
+            Instruction::DEX => {
+                self.x = self.x.wrapping_sub(1);
+                self.set_nz(self.x);
+            }
+            Instruction::INY => {
+                self.y = self.y.wrapping_add(1);
+                self.set_nz(self.y);
+            }
+            Instruction::DEY => {
+                self.y = self.y.wrapping_sub(1);
+                self.set_nz(self.y);
+            }
+            Instruction::CLC => self.p.c = false,
+            Instruction::SEC => self.p.c = true,
+            Instruction::CLI => self.p.i = false,
+            Instruction::SEI => self.p.i = true,
+            Instruction::CLD => self.p.d = false,
+            Instruction::SED => self.p.d = true,
+            Instruction::CLV => self.p.v = false,
+            Instruction::TAX => {
+                self.x = self.a;
+                self.set_nz(self.x);
+            }
+            Instruction::TXA => {
+                self.a = self.x;
+                self.set_nz(self.a);
+            }
+            Instruction::TAY => {
+                self.y = self.a;
+                self.set_nz(self.y);
+            }
+            Instruction::TYA => {
+                self.a = self.y;
+                self.set_nz(self.a);
+            }
+            Instruction::TSX => {
+                self.x = self.sp;
+                self.set_nz(self.x);
+            }
+            Instruction::TXS => self.sp = self.x,
+            // NOP, and undefined opcodes are treated as NOP
+            _ => {}
+        }
+    }
+
+    /// ASL, LSR, ROL or ROR (also the first half of SLO, SRE, RLA and RRA). Sets the carry only.
+    fn shift(&mut self, instruction: Instruction, value: u8) -> u8 {
+        let carry = self.p.c as u8;
+        match instruction {
+            Instruction::ASL | Instruction::SLO => {
+                self.p.c = (value & 0x80) != 0;
+                value << 1
+            }
+            Instruction::LSR | Instruction::SRE => {
+                self.p.c = (value & 0x01) != 0;
+                value >> 1
+            }
+            Instruction::ROL | Instruction::RLA => {
+                self.p.c = (value & 0x80) != 0;
+                (value << 1) | carry
+            }
+            _ => {
+                self.p.c = (value & 0x01) != 0;
+                (value >> 1) | (carry << 7)
+            }
+        }
+    }
+
+    fn add(&mut self, value: u8) {
+        let sum = self.a as u16 + value as u16 + self.p.c as u16;
+        let result = sum as u8;
+        self.p.v = ((self.a ^ result) & (value ^ result) & 0x80) != 0;
+        self.p.c = sum > 0xFF;
+        self.a = result;
+        self.set_nz(result);
     }
-    fn pop(&mut self) -> u8 {
-        self.sp = self.sp.wrapping_add(1);
-        self.bus.read(None, &mut None, &mut None, None, 0x0100 | self.sp as u16)
+
+    fn compare(&mut self, register: u8, value: u8) {
+        let (result, borrow) = register.overflowing_sub(value);
+        self.set_nz(result);
+        self.p.c = !borrow;
+    }
+
+    /// The instruction at pc as a line of nestest.log: address, bytes, disassembly with the
+    /// effective address and value, registers, PPU scanline and dot, and cycle count
+    fn trace_line(&self, rom: &Rom, ppu: &Ppu) -> String {
+        let peek = |addr: u16| self.bus.peek(rom, addr);
+        let decoded = disasm::decode(peek, self.pc);
+        let base = decoded.operand_text();
+        let zero_page_pointer = |pointer: u8| get_addr(peek(pointer.wrapping_add(1) as u16), peek(pointer as u16));
+        let indexed = |register: u8| match decoded.mode {
+            AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
+                (decoded.operand as u8).wrapping_add(register) as u16
+            }
+            _ => decoded.operand.wrapping_add(register as u16),
+        };
+        let operand = match decoded.mode {
+            AddressingMode::ZeroPage => format!("{} = {:02X}", base, peek(decoded.operand)),
+            AddressingMode::ZeroPageX => {
+                let addr = indexed(self.x);
+                format!("{} @ {:02X} = {:02X}", base, addr, peek(addr))
+            }
+            AddressingMode::ZeroPageY => {
+                let addr = indexed(self.y);
+                format!("{} @ {:02X} = {:02X}", base, addr, peek(addr))
+            }
+            AddressingMode::Absolute => match decoded.instruction {
+                Instruction::JMP | Instruction::JSR => base,
+                _ => format!("{} = {:02X}", base, peek(decoded.operand)),
+            },
+            AddressingMode::AbsoluteX => {
+                let addr = indexed(self.x);
+                format!("{} @ {:04X} = {:02X}", base, addr, peek(addr))
+            }
+            AddressingMode::AbsoluteY => {
+                let addr = indexed(self.y);
+                format!("{} @ {:04X} = {:02X}", base, addr, peek(addr))
+            }
+            AddressingMode::Indirect => {
+                //上位バイトはページをまたがずに読む
+                let pointer = decoded.operand;
+                let addr_h = peek((pointer & 0xFF00) | (pointer.wrapping_add(1) & 0x00FF));
+                format!("{} = {:04X}", base, get_addr(addr_h, peek(pointer)))
+            }
+            AddressingMode::IndirectX => {
+                let pointer = (decoded.operand as u8).wrapping_add(self.x);
+                let addr = zero_page_pointer(pointer);
+                format!("{} @ {:02X} = {:04X} = {:02X}", base, pointer, addr, peek(addr))
+            }
+            AddressingMode::IndirectY => {
+                let pointer = zero_page_pointer(decoded.operand as u8);
+                let addr = pointer.wrapping_add(self.y as u16);
+                format!("{} = {:04X} @ {:04X} = {:02X}", base, pointer, addr, peek(addr))
+            }
+            _ => base,
+        };
+        let bytes: Vec<String> = decoded.bytes().iter().map(|byte| format!("{:02X}", byte)).collect();
+        let (scanline, dot) = ppu.position();
+        format!(
+            "{:04X}  {:<8} {}{} {:<27} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+            self.pc,
+            bytes.join(" "),
+            if decoded.unofficial { '*' } else { ' ' },
+            decoded.mnemonic(),
+            operand,
+            self.a,
+            self.x,
+            self.y,
+            self.p.read(),
+            self.sp,
+            scanline,
+            dot,
+            //このクロックの分はまだ実行されていない
+            self.cycles - 1
+        )
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.a);
+        w.write_u8(self.x);
+        w.write_u8(self.y);
+        w.write_u16(self.pc);
+        w.write_u8(self.sp);
+        w.write_u8(self.p.read());
+        w.write_u8(self.op);
+        w.write_u8(match self.state {
+            CpuState::Reset => 0,
+            CpuState::Interrupt => 1,
+            CpuState::Fetch => 2,
+            CpuState::Execute => 3,
+        });
+        w.write_u8(self.step);
+        w.write_u16(self.addr);
+        w.write_u8(self.pointer);
+        w.write_u8(self.data);
+        w.write_bool(self.page_crossed);
+        w.write_u16(self.vector);
+        w.write_bool(self.reset);
+        w.write_bool(self.nmi);
+        w.write_bool(self.need_nmi);
+        w.write_bool(self.prev_need_nmi);
+        w.write_bool(self.run_irq);
+        w.write_bool(self.prev_run_irq);
+        self.dma.save_state(w);
+        w.write_u64(self.cycles);
+        self.bus.save_state(w);
+    }
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.a = r.read_u8()?;
+        self.x = r.read_u8()?;
+        self.y = r.read_u8()?;
+        self.pc = r.read_u16()?;
+        self.sp = r.read_u8()?;
+        self.p.write(r.read_u8()?);
+        self.op = r.read_u8()?;
+        self.state = match r.read_u8()? {
+            0 => CpuState::Reset,
+            1 => CpuState::Interrupt,
+            2 => CpuState::Fetch,
+            3 => CpuState::Execute,
+            _ => return Err(StateError::InvalidData),
+        };
+        self.step = r.read_u8()?;
+        self.addr = r.read_u16()?;
+        self.pointer = r.read_u8()?;
+        self.data = r.read_u8()?;
+        self.page_crossed = r.read_bool()?;
+        self.vector = r.read_u16()?;
+        self.reset = r.read_bool()?;
+        self.nmi = r.read_bool()?;
+        self.need_nmi = r.read_bool()?;
+        self.prev_need_nmi = r.read_bool()?;
+        self.run_irq = r.read_bool()?;
+        self.prev_run_irq = r.read_bool()?;
+        self.dma.load_state(r)?;
+        self.cycles = r.read_u64()?;
+        //シーケンスの途中で壊れたステートを読むとcycle_atがNoneを返して落ちる
+        if self.cycle_at(self.step).is_none() {
+            return Err(StateError::InvalidData);
+        }
+        self.bus.load_state(r)
     }
 }
 
-#[derive(Clone, Copy)]
-enum AddressingMode {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressingMode {
     Implied,
     Accumulator,
     Immediate,
@@ -1205,8 +1218,8 @@ enum AddressingMode {
     IndirectY,
 }
 
-#[derive(Debug, Clone, Copy)]
-enum Instruction {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
     ADC,
     SBC,
     AND,
@@ -1275,6 +1288,12 @@ enum Instruction {
     Undefined,
 }
 
+/// Instruction and addressing mode of an opcode
+pub fn decode_opcode(op: u8) -> (Instruction, AddressingMode) {
+    let definition = &INSTRUCTION_SET[op as usize];
+    (definition.instruction, definition.mode)
+}
+
 struct InstructionDefinition {
     mode: AddressingMode,
     instruction: Instruction,
@@ -1,7 +1,9 @@
 use super::super::apu::*;
-use super::super::nes::{PadInput, PadInputs};
+use super::super::debugger::{Access, WatchList};
+use super::super::nes::{PadInput, PadInputs, RamPowerOnState};
 use super::super::ppu::*;
 use super::super::rom::*;
+use super::super::state::*;
 
 struct WRam {
     memory: Box<[u8; 0x800]>,
@@ -18,31 +20,22 @@ impl WRam {
     }
 }
 
-/// Extended RAM ($6000-$7FFF) for battery-backed save RAM and mapper work RAM
-struct ExtRam {
-    memory: Box<[u8; 0x2000]>,
-}
-
-impl ExtRam {
-    fn new() -> Self {
-        ExtRam { memory: Box::new([0; 0x2000]) }
-    }
-    #[inline(always)]
-    fn read(&self, addr: u16) -> u8 {
-        self.memory[(addr - 0x6000) as usize]
-    }
-    #[inline(always)]
-    fn write(&mut self, addr: u16, value: u8) {
-        self.memory[(addr - 0x6000) as usize] = value;
-    }
-}
-
 struct Pad {
     read_cycle: u8,
     strobe: bool,
 }
 
 impl Pad {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.read_cycle);
+        w.write_bool(self.strobe);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.read_cycle = r.read_u8()?;
+        self.strobe = r.read_bool()?;
+        Ok(())
+    }
+
     #[inline(always)]
     fn read(&mut self, input: &PadInput) -> u8 {
         if self.strobe {
@@ -78,30 +71,43 @@ impl Pad {
 
 pub struct Bus {
     w_ram: WRam,
-    ext_ram: ExtRam,
     pad1: Pad,
     pad2: Pad,
+    pub watches: WatchList,
 }
 
 impl Bus {
     pub fn new() -> Bus {
         Bus {
             w_ram: WRam { memory: Box::new([0; 0x800]) },
-            ext_ram: ExtRam::new(),
             pad1: Pad { read_cycle: 0, strobe: false },
             pad2: Pad { read_cycle: 0, strobe: false },
+            watches: WatchList::default(),
         }
     }
+    pub fn fill_ram(&mut self, pattern: RamPowerOnState) {
+        pattern.fill(&mut self.w_ram.memory[..]);
+    }
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.w_ram.memory[..]);
+        self.pad1.save_state(w);
+        self.pad2.save_state(w);
+    }
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes_into(&mut self.w_ram.memory[..])?;
+        self.pad1.load_state(r)?;
+        self.pad2.load_state(r)
+    }
     #[inline(always)]
     pub fn read(
         &mut self,
-        rom: Option<&Rom>,
+        rom: &mut Option<&mut Rom>,
         apu: &mut Option<&mut Apu>,
         ppu: &mut Option<&mut Ppu>,
         inputs: Option<&PadInputs>,
         addr: u16,
     ) -> u8 {
-        match addr {
+        let value = match addr {
             0x0000..=0x1FFF => {
                 let addr = addr & 0x07FF;
                 self.w_ram.read(addr)
@@ -109,7 +115,7 @@ impl Bus {
             0x2000..=0x3FFF => {
                 //PPU
                 let addr = (addr & 0x7) as u8;
-                ppu.as_mut().unwrap().read(rom.unwrap(), addr)
+                ppu.as_mut().unwrap().read(rom.as_mut().unwrap(), addr)
             }
             0x4000..=0x4015 => {
                 //APU
@@ -118,27 +124,35 @@ impl Bus {
             }
             0x4016 => self.pad1.read(&inputs.unwrap().pad1),
             0x4017 => self.pad2.read(&inputs.unwrap().pad2),
-            0x4018..=0x401F => 0,                       // Open bus / test mode
-            0x4020..=0x5FFF => 0,                       // 拡張ROM - return open bus
-            0x6000..=0x7FFF => self.ext_ram.read(addr), //拡張RAM
-
-            0x8000..=0xFFFF => {
-                //PRG-ROM
-                let r = rom.unwrap();
-                let prog = r.get_prog();
-                let mut offset = (addr - 0x8000) as usize;
-                if offset >= prog.len() && !prog.is_empty() {
-                    offset %= prog.len();
-                }
-                if prog.is_empty() {
-                    0
-                } else {
-                    prog[offset]
-                }
-            }
+            0x4018..=0x401F => 0,                                           // Open bus / test mode
+            0x4020..=0xFFFF => rom.as_ref().unwrap().mapper.cpu_read(addr), //カートリッジ
+        };
+        self.watches.check(addr, value, Access::Read);
+        value
+    }
+    pub fn ram(&self) -> &[u8] {
+        &self.w_ram.memory[..]
+    }
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.w_ram.memory[..]
+    }
+    /// Read without side effects. PPU, APU and I/O registers read as $FF.
+    pub fn peek(&self, rom: &Rom, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x1FFF => self.w_ram.read(addr & 0x07FF),
+            0x2000..=0x401F => 0xFF,
+            0x4020..=0xFFFF => rom.mapper.cpu_read(addr),
         }
     }
-    pub fn write(&mut self, apu: &mut Option<&mut Apu>, ppu: &mut Option<&mut Ppu>, addr: u16, value: u8) -> u16 {
+    pub fn write(
+        &mut self,
+        rom: &mut Option<&mut Rom>,
+        apu: &mut Option<&mut Apu>,
+        ppu: &mut Option<&mut Ppu>,
+        addr: u16,
+        value: u8,
+    ) {
+        self.watches.check(addr, value, Access::Write);
         match addr {
             0x0000..=0x1FFF => {
                 let addr = addr & 0x07FF;
@@ -147,25 +161,9 @@ impl Bus {
             0x2000..=0x3FFF => {
                 //PPU
                 let addr = (addr & 0xFF) as u8;
-                ppu.as_mut().unwrap().write(addr, value);
-            }
-            0x4014 => {
-                //DMA
-                let addr = (value as u16) << 8;
-                // Need to read from our own bus - but we can't borrow rom/apu/ppu again
-                // So we'll read WRAM directly for DMA from page 0/1, or ROM for high pages
-                let mut data = [0u8; 0x100];
-                for i in 0..=0xFFu16 {
-                    let src = addr + i;
-                    data[i as usize] = match src {
-                        0x0000..=0x1FFF => self.w_ram.read(src & 0x07FF),
-                        0x6000..=0x7FFF => self.ext_ram.read(src),
-                        _ => 0, // DMA from other sources is uncommon
-                    };
-                }
-                ppu.as_mut().unwrap().dma_write(&data);
-                return 513;
+                ppu.as_mut().unwrap().write(rom.as_mut().unwrap(), addr, value);
             }
+            0x4014 => {} //OAM DMAはCPUが実行する
             0x4016 => {
                 self.pad1.set_strobe((value & 0b1) == 0b1);
                 self.pad2.set_strobe((value & 0b1) == 0b1);
@@ -175,11 +173,8 @@ impl Bus {
                 let addr = addr as u8;
                 apu.as_mut().unwrap().write(addr, value);
             }
-            0x4018..=0x401F => {}                               // Test mode
-            0x4020..=0x5FFF => {}                               //拡張ROM
-            0x6000..=0x7FFF => self.ext_ram.write(addr, value), //拡張RAM
-            0x8000..=0xFFFF => {} // PRG-ROM writes (used by mappers, ignored for mapper 0)
+            0x4018..=0x401F => {}                                                   // Test mode
+            0x4020..=0xFFFF => rom.as_mut().unwrap().mapper.cpu_write(addr, value), //カートリッジ
         }
-        0
     }
 }
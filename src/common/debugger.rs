@@ -0,0 +1,217 @@
+use std::ops::RangeInclusive;
+
+/// A debugger run gives up after this many frames (10 seconds of NTSC time), e.g. when stepping out of a
+/// routine that never returns
+pub const MAX_RUN_FRAMES: u32 = 600;
+
+/// Identifies a breakpoint or watchpoint added to `Nes`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BreakpointId(u32);
+
+/// Address space a watchpoint looks at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemorySpace {
+    /// CPU bus ($0000-$FFFF). Mirrors are separate addresses: watching $0000 doesn't catch $0800.
+    Cpu,
+    /// PPU bus ($0000-$3FFF) as accessed by the CPU through PPUDATA ($2007). Rendering fetches aren't watched.
+    Ppu,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    /// Either one (only used when adding a watchpoint)
+    ReadWrite,
+}
+
+impl Access {
+    fn includes(self, access: Access) -> bool {
+        self == Access::ReadWrite || self == access
+    }
+}
+
+/// CPU registers. `p` has bit 5 set and B clear, as pushed by an interrupt.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CpuRegisters {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+    pub pc: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Register {
+    A,
+    X,
+    Y,
+    P,
+    Sp,
+    Pc,
+}
+
+impl Register {
+    fn value(self, registers: &CpuRegisters) -> u16 {
+        match self {
+            Register::A => registers.a as u16,
+            Register::X => registers.x as u16,
+            Register::Y => registers.y as u16,
+            Register::P => registers.p as u16,
+            Register::Sp => registers.sp as u16,
+            Register::Pc => registers.pc,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// `register` compared to `value`, e.g. `X >= $10`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Condition {
+    pub register: Register,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+impl Condition {
+    pub fn is_met(&self, registers: &CpuRegisters) -> bool {
+        let register = self.register.value(registers);
+        match self.comparison {
+            Comparison::Equal => register == self.value,
+            Comparison::NotEqual => register != self.value,
+            Comparison::Less => register < self.value,
+            Comparison::LessOrEqual => register <= self.value,
+            Comparison::Greater => register > self.value,
+            Comparison::GreaterOrEqual => register >= self.value,
+        }
+    }
+}
+
+/// Why a debugger run (`Nes::step_*`) stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    /// The step finished
+    Done,
+    /// An execution breakpoint; the instruction at `pc` hasn't run yet
+    Breakpoint { id: BreakpointId, pc: u16 },
+    /// A watched address was accessed. The CPU may be in the middle of an instruction.
+    Watchpoint {
+        id: BreakpointId,
+        space: MemorySpace,
+        addr: u16,
+        value: u8,
+        access: Access,
+    },
+    /// Ran for `MAX_RUN_FRAMES` frames without finishing
+    Timeout,
+}
+
+/// Execution breakpoints, checked before every instruction
+#[derive(Default)]
+pub struct Debugger {
+    next_id: u32,
+    breakpoints: Vec<(BreakpointId, Option<u16>, Option<Condition>)>,
+}
+
+impl Debugger {
+    /// Allocate an id (shared with the watchpoints)
+    pub fn new_id(&mut self) -> BreakpointId {
+        self.next_id += 1;
+        BreakpointId(self.next_id)
+    }
+
+    /// Break at `addr` (None: any address) when `condition` (None: always) is met
+    pub fn add(&mut self, addr: Option<u16>, condition: Option<Condition>) -> BreakpointId {
+        let id = self.new_id();
+        self.breakpoints.push((id, addr, condition));
+        id
+    }
+
+    pub fn remove(&mut self, id: BreakpointId) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|&(other, _, _)| other != id);
+        self.breakpoints.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// First breakpoint hit by the instruction about to run
+    pub fn check(&self, registers: &CpuRegisters) -> Option<BreakpointId> {
+        self.breakpoints
+            .iter()
+            .find(|(_, addr, condition)| {
+                addr.is_none_or(|addr| addr == registers.pc)
+                    && condition.is_none_or(|condition| condition.is_met(registers))
+            })
+            .map(|&(id, _, _)| id)
+    }
+}
+
+/// An access that hit a watchpoint
+pub struct WatchHit {
+    id: BreakpointId,
+    addr: u16,
+    value: u8,
+    access: Access,
+}
+
+impl WatchHit {
+    pub fn stop_reason(&self, space: MemorySpace) -> StopReason {
+        StopReason::Watchpoint { id: self.id, space, addr: self.addr, value: self.value, access: self.access }
+    }
+}
+
+/// Watchpoints on one address space, checked by its bus on every access.
+/// Only the first hit is kept until it's taken.
+#[derive(Default)]
+pub struct WatchList {
+    watchpoints: Vec<(BreakpointId, RangeInclusive<u16>, Access)>,
+    hit: Option<WatchHit>,
+}
+
+impl WatchList {
+    pub fn add(&mut self, id: BreakpointId, addrs: RangeInclusive<u16>, access: Access) {
+        self.watchpoints.push((id, addrs, access));
+    }
+
+    pub fn remove(&mut self, id: BreakpointId) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|(other, _, _)| *other != id);
+        self.watchpoints.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.watchpoints.clear();
+        self.hit = None;
+    }
+
+    #[inline(always)]
+    pub fn check(&mut self, addr: u16, value: u8, access: Access) {
+        if self.watchpoints.is_empty() || self.hit.is_some() {
+            return;
+        }
+        if let Some((id, _, _)) = self
+            .watchpoints
+            .iter()
+            .find(|(_, addrs, watched)| addrs.contains(&addr) && watched.includes(access))
+        {
+            self.hit = Some(WatchHit { id: *id, addr, value, access });
+        }
+    }
+
+    pub fn take_hit(&mut self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+}
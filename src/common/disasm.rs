@@ -0,0 +1,209 @@
+//! 6502 disassembler built on the CPU's opcode table, with ca65 source output for ROM analysis
+
+use super::cpu::decode_opcode;
+pub use super::cpu::{AddressingMode, Instruction};
+use std::collections::HashSet;
+use std::fmt;
+
+/// One decoded instruction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Decoded {
+    pub addr: u16,
+    pub opcode: u8,
+    pub instruction: Instruction,
+    pub mode: AddressingMode,
+    /// Operand bytes as a little-endian value (0 if there are none)
+    pub operand: u16,
+    /// Not one of the 151 official opcodes. Undefined opcodes decode as a single unofficial byte.
+    pub unofficial: bool,
+}
+
+impl Decoded {
+    /// Bytes including the opcode
+    pub fn size(&self) -> u16 {
+        1 + operand_size(self.mode)
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        [self.opcode, self.operand as u8, (self.operand >> 8) as u8][..self.size() as usize].to_vec()
+    }
+
+    /// Address of the following instruction
+    pub fn next_addr(&self) -> u16 {
+        self.addr.wrapping_add(self.size())
+    }
+
+    /// Upper case mnemonic as in nestest.log (`ISB` for ISC), or `???` for undefined opcodes
+    pub fn mnemonic(&self) -> String {
+        match self.instruction {
+            Instruction::Undefined => "???".into(),
+            instruction => format!("{:?}", instruction),
+        }
+    }
+
+    /// Destination of a branch, JSR or absolute JMP
+    pub fn branch_target(&self) -> Option<u16> {
+        match (self.instruction, self.mode) {
+            (_, AddressingMode::Relative) => Some(self.next_addr().wrapping_add(self.operand as i8 as u16)),
+            (Instruction::JMP | Instruction::JSR, AddressingMode::Absolute) => Some(self.operand),
+            _ => None,
+        }
+    }
+
+    /// Operand in nestest.log syntax, e.g. `($10),Y`. Branches show their target address.
+    pub fn operand_text(&self) -> String {
+        match self.mode {
+            AddressingMode::Implied => String::new(),
+            AddressingMode::Accumulator => "A".into(),
+            AddressingMode::Immediate => format!("#${:02X}", self.operand),
+            AddressingMode::ZeroPage => format!("${:02X}", self.operand),
+            AddressingMode::ZeroPageX => format!("${:02X},X", self.operand),
+            AddressingMode::ZeroPageY => format!("${:02X},Y", self.operand),
+            AddressingMode::Relative => format!("${:04X}", self.branch_target().unwrap()),
+            AddressingMode::Absolute => format!("${:04X}", self.operand),
+            AddressingMode::AbsoluteX => format!("${:04X},X", self.operand),
+            AddressingMode::AbsoluteY => format!("${:04X},Y", self.operand),
+            AddressingMode::Indirect => format!("(${:04X})", self.operand),
+            AddressingMode::IndirectX => format!("(${:02X},X)", self.operand),
+            AddressingMode::IndirectY => format!("(${:02X}),Y", self.operand),
+        }
+    }
+
+    /// Line of ca65 source (without a label). Instructions ca65 wouldn't assemble back into the same
+    /// bytes become `.byte`: undefined opcodes, unofficial NOPs and $EB (SBC #imm).
+    fn ca65_line(&self, labels: &HashSet<u16>) -> String {
+        let mnemonic = match self.instruction {
+            Instruction::Undefined | Instruction::NOP if self.unofficial => None,
+            Instruction::SBC if self.unofficial => None,
+            Instruction::ISB => Some("isc".into()),
+            _ => Some(self.mnemonic().to_lowercase()),
+        };
+        let Some(mnemonic) = mnemonic else {
+            let bytes: Vec<String> = self.bytes().iter().map(|byte| format!("${:02X}", byte)).collect();
+            return format!("{:<8}{}", ".byte", bytes.join(","));
+        };
+        let target = |addr: u16| {
+            if labels.contains(&addr) {
+                format!("L{:04X}", addr)
+            } else {
+                format!("${:04X}", addr)
+            }
+        };
+        //ゼロページに収まるアドレスも絶対アドレッシングのままにする
+        let absolute = if self.operand < 0x100 { "a:" } else { "" };
+        let operand = match self.mode {
+            AddressingMode::Relative => target(self.branch_target().unwrap()),
+            AddressingMode::Absolute => match self.branch_target() {
+                Some(addr) => target(addr),
+                None => format!("{}${:04X}", absolute, self.operand),
+            },
+            AddressingMode::AbsoluteX => format!("{}${:04X},x", absolute, self.operand),
+            AddressingMode::AbsoluteY => format!("{}${:04X},y", absolute, self.operand),
+            AddressingMode::Accumulator => "a".into(),
+            _ => self.operand_text().replace(",X", ",x").replace(",Y", ",y"),
+        };
+        format!("{:<8}{}", mnemonic, operand).trim_end().into()
+    }
+}
+
+impl fmt::Display for Decoded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operand = self.operand_text();
+        if operand.is_empty() {
+            write!(f, "{}", self.mnemonic())
+        } else {
+            write!(f, "{} {}", self.mnemonic(), operand)
+        }
+    }
+}
+
+fn operand_size(mode: AddressingMode) -> u16 {
+    match mode {
+        AddressingMode::Implied | AddressingMode::Accumulator => 0,
+        AddressingMode::Absolute | AddressingMode::AbsoluteX | AddressingMode::AbsoluteY | AddressingMode::Indirect => {
+            2
+        }
+        _ => 1,
+    }
+}
+
+/// Decode the instruction at `addr`, reading memory through `peek`
+pub fn decode(peek: impl Fn(u16) -> u8, addr: u16) -> Decoded {
+    let opcode = peek(addr);
+    let (instruction, mode) = decode_opcode(opcode);
+    let operand = match operand_size(mode) {
+        0 => 0,
+        1 => peek(addr.wrapping_add(1)) as u16,
+        _ => peek(addr.wrapping_add(1)) as u16 | (peek(addr.wrapping_add(2)) as u16) << 8,
+    };
+    let unofficial = match instruction {
+        Instruction::LAX
+        | Instruction::SAX
+        | Instruction::DCP
+        | Instruction::ISB
+        | Instruction::SLO
+        | Instruction::RLA
+        | Instruction::SRE
+        | Instruction::RRA
+        | Instruction::Undefined => true,
+        Instruction::NOP => opcode != 0xEA,
+        _ => opcode == 0xEB,
+    };
+    Decoded { addr, opcode, instruction, mode, operand, unofficial }
+}
+
+/// Decode the bytes from `start` through `end` of live memory. An instruction running past `end` is
+/// returned as undefined single bytes.
+pub fn disassemble_memory(peek: impl Fn(u16) -> u8, start: u16, end: u16) -> Vec<Decoded> {
+    let mut instructions = Vec::new();
+    let mut addr = start as u32;
+    while addr <= end as u32 {
+        let mut decoded = decode(&peek, addr as u16);
+        if addr + decoded.size() as u32 > end as u32 + 1 {
+            decoded = Decoded {
+                instruction: Instruction::Undefined,
+                mode: AddressingMode::Implied,
+                operand: 0,
+                unofficial: true,
+                ..decoded
+            };
+        }
+        addr += decoded.size() as u32;
+        instructions.push(decoded);
+    }
+    instructions
+}
+
+/// Decode `code` loaded at `origin`, e.g. a PRG-ROM bank
+pub fn disassemble(code: &[u8], origin: u16) -> Vec<Decoded> {
+    let code = &code[..code.len().min(0x10000 - origin as usize)];
+    if code.is_empty() {
+        return Vec::new();
+    }
+    let peek = |addr: u16| code.get(addr.wrapping_sub(origin) as usize).copied().unwrap_or(0);
+    disassemble_memory(peek, origin, origin + (code.len() - 1) as u16)
+}
+
+/// ca65 source (`.setcpu "6502X"`) for consecutive instructions that assembles back into the same bytes.
+/// Branch and jump targets that are instructions in the list get `Lxxxx` labels.
+pub fn to_ca65(instructions: &[Decoded]) -> String {
+    let starts: HashSet<u16> = instructions.iter().map(|decoded| decoded.addr).collect();
+    let labels: HashSet<u16> = instructions
+        .iter()
+        .filter_map(|decoded| decoded.branch_target())
+        .filter(|addr| starts.contains(addr))
+        .collect();
+    let mut source = String::from(".setcpu \"6502X\"\n");
+    if let Some(first) = instructions.first() {
+        source += &format!(".org ${:04X}\n", first.addr);
+    }
+    for decoded in instructions {
+        let label = if labels.contains(&decoded.addr) {
+            format!("L{:04X}:", decoded.addr)
+        } else {
+            String::new()
+        };
+        source += &format!("{:<8}{}\n", label, decoded.ca65_line(&labels));
+    }
+    source
+}
@@ -1,8 +1,16 @@
 mod apu;
 mod cpu;
+mod debugger;
+pub mod disasm;
+mod mapper;
 pub mod nes;
+pub mod ntsc;
+pub mod palette;
 mod ppu;
+mod rewind;
 mod rom;
+pub mod scale;
+mod state;
 pub mod util;
 
 #[cfg(test)]
@@ -0,0 +1,172 @@
+use super::rom::{MirroringMode, RomError};
+use super::state::*;
+mod axrom;
+mod cnrom;
+mod gxrom;
+mod mmc1;
+mod mmc3;
+mod nrom;
+mod uxrom;
+
+/// Memory mounted on the cartridge board (PRG-ROM, CHR-ROM/RAM and PRG-RAM)
+#[derive(Clone)]
+pub struct CartridgeMemory {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    prg_ram: Vec<u8>,
+    prg_ram_dirty: bool,
+}
+
+impl CartridgeMemory {
+    /// CHR-RAM of `chr_ram_size` bytes is mounted only when there is no CHR-ROM
+    pub fn new(prg_rom: &[u8], chr_rom: &[u8], prg_ram_size: usize, chr_ram_size: usize) -> Self {
+        let chr_is_ram = chr_rom.is_empty();
+        CartridgeMemory {
+            prg_rom: prg_rom.to_vec(),
+            chr: if chr_is_ram {
+                vec![0; chr_ram_size]
+            } else {
+                chr_rom.to_vec()
+            },
+            chr_is_ram,
+            prg_ram: vec![0; prg_ram_size],
+            prg_ram_dirty: false,
+        }
+    }
+    pub fn prg_rom(&self) -> &[u8] {
+        &self.prg_rom
+    }
+    /// CHR-ROM contents (empty for boards with CHR-RAM)
+    pub fn chr_rom(&self) -> &[u8] {
+        if self.chr_is_ram {
+            &[]
+        } else {
+            &self.chr
+        }
+    }
+    pub fn has_chr_ram(&self) -> bool {
+        self.chr_is_ram
+    }
+    /// Read PRG-ROM at an absolute offset; offsets past the end wrap around
+    #[inline(always)]
+    pub fn read_prg(&self, offset: usize) -> u8 {
+        if self.prg_rom.is_empty() {
+            0
+        } else {
+            self.prg_rom[offset % self.prg_rom.len()]
+        }
+    }
+    /// Read CHR-ROM/RAM at an absolute offset; offsets past the end wrap around
+    #[inline(always)]
+    pub fn read_chr(&self, offset: usize) -> u8 {
+        if self.chr.is_empty() {
+            0
+        } else {
+            self.chr[offset % self.chr.len()]
+        }
+    }
+    /// Write CHR at an absolute offset; ignored for CHR-ROM
+    #[inline(always)]
+    pub fn write_chr(&mut self, offset: usize, value: u8) {
+        if self.chr_is_ram && !self.chr.is_empty() {
+            let len = self.chr.len();
+            self.chr[offset % len] = value;
+        }
+    }
+    #[inline(always)]
+    pub fn read_prg_ram(&self, offset: usize) -> u8 {
+        if self.prg_ram.is_empty() {
+            0
+        } else {
+            self.prg_ram[offset % self.prg_ram.len()]
+        }
+    }
+    #[inline(always)]
+    pub fn write_prg_ram(&mut self, offset: usize, value: u8) {
+        if !self.prg_ram.is_empty() {
+            let len = self.prg_ram.len();
+            let ram = &mut self.prg_ram[offset % len];
+            if *ram != value {
+                *ram = value;
+                self.prg_ram_dirty = true;
+            }
+        }
+    }
+    pub fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+    /// Overwrite PRG-RAM from the start; extra bytes are ignored
+    pub fn load_prg_ram(&mut self, data: &[u8]) {
+        let len = data.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+        self.prg_ram_dirty = false;
+    }
+    /// True if PRG-RAM changed since it was loaded or the flag was last cleared
+    pub fn is_prg_ram_dirty(&self) -> bool {
+        self.prg_ram_dirty
+    }
+    pub fn clear_prg_ram_dirty(&mut self) {
+        self.prg_ram_dirty = false;
+    }
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.prg_ram);
+        w.write_bytes(if self.chr_is_ram { &self.chr } else { &[] });
+    }
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let prg_ram = self.prg_ram.clone();
+        r.read_bytes_into(&mut self.prg_ram)?;
+        self.prg_ram_dirty |= prg_ram != self.prg_ram;
+        if self.chr_is_ram {
+            r.read_bytes_into(&mut self.chr)
+        } else {
+            r.read_bytes_into(&mut [])
+        }
+    }
+}
+
+/// Cartridge board logic. Every CPU access to $4020-$FFFF and every PPU access to
+/// the pattern tables ($0000-$1FFF) goes through the mapper.
+pub trait Mapper {
+    /// CPU read from cartridge space ($4020-$FFFF)
+    fn cpu_read(&self, addr: u16) -> u8;
+    /// CPU write to cartridge space ($4020-$FFFF)
+    fn cpu_write(&mut self, addr: u16, value: u8);
+    /// PPU read from pattern table space ($0000-$1FFF)
+    fn ppu_read(&self, addr: u16) -> u8;
+    /// PPU write to pattern table space ($0000-$1FFF)
+    fn ppu_write(&mut self, addr: u16, value: u8);
+    /// Current nametable mirroring
+    fn mirroring(&self) -> MirroringMode;
+    /// State of the cartridge IRQ line
+    fn irq(&self) -> bool {
+        false
+    }
+    /// Called once per CPU cycle
+    fn clock_cpu(&mut self) {}
+    /// Called with every address the PPU puts on its bus (rendering fetches and $2006/$2007 accesses),
+    /// so the board can watch PPU A12
+    fn notify_ppu_addr(&mut self, _addr: u16) {}
+    fn memory(&self) -> &CartridgeMemory;
+    fn memory_mut(&mut self) -> &mut CartridgeMemory;
+    /// Append board registers and RAM to a save state
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>;
+}
+
+pub fn create_mapper(
+    mapper: u16,
+    memory: CartridgeMemory,
+    mirroring: MirroringMode,
+) -> Result<Box<dyn Mapper>, RomError> {
+    match mapper {
+        0 => Ok(Box::new(nrom::Nrom::new(memory, mirroring))),
+        1 => Ok(Box::new(mmc1::Mmc1::new(memory))),
+        2 => Ok(Box::new(uxrom::Uxrom::new(memory, mirroring))),
+        3 => Ok(Box::new(cnrom::Cnrom::new(memory, mirroring))),
+        4 => Ok(Box::new(mmc3::Mmc3::new(memory, mirroring))),
+        7 => Ok(Box::new(axrom::Axrom::new(memory))),
+        66 => Ok(Box::new(gxrom::Gxrom::new(memory, mirroring))),
+        _ => Err(RomError::UnsupportedMapper(mapper)),
+    }
+}
@@ -0,0 +1,63 @@
+use super::super::rom::MirroringMode;
+use super::super::state::*;
+use super::*;
+
+/// Mapper 7 (AxROM): switchable 32K PRG bank and single-screen mirroring
+pub struct Axrom {
+    memory: CartridgeMemory,
+    prg_bank: u8,
+    upper_screen: bool,
+}
+
+impl Axrom {
+    pub fn new(memory: CartridgeMemory) -> Self {
+        Axrom { memory, prg_bank: 0, upper_screen: false }
+    }
+}
+
+impl Mapper for Axrom {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x8000..=0xFFFF => self
+                .memory
+                .read_prg(self.prg_bank as usize * 0x8000 + (addr - 0x8000) as usize),
+            _ => 0,
+        }
+    }
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        if addr >= 0x8000 {
+            self.prg_bank = value & 0x0F;
+            self.upper_screen = value & 0x10 != 0;
+        }
+    }
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.memory.read_chr(addr as usize)
+    }
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        self.memory.write_chr(addr as usize, value);
+    }
+    fn mirroring(&self) -> MirroringMode {
+        if self.upper_screen {
+            MirroringMode::SingleScreenUpper
+        } else {
+            MirroringMode::SingleScreenLower
+        }
+    }
+    fn memory(&self) -> &CartridgeMemory {
+        &self.memory
+    }
+    fn memory_mut(&mut self) -> &mut CartridgeMemory {
+        &mut self.memory
+    }
+    fn save_state(&self, w: &mut StateWriter) {
+        self.memory.save_state(w);
+        w.write_u8(self.prg_bank);
+        w.write_bool(self.upper_screen);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.memory.load_state(r)?;
+        self.prg_bank = r.read_u8_max(0x0F)?;
+        self.upper_screen = r.read_bool()?;
+        Ok(())
+    }
+}
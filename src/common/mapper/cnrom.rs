@@ -0,0 +1,56 @@
+use super::super::rom::MirroringMode;
+use super::super::state::*;
+use super::*;
+
+/// Mapper 3 (CNROM): fixed PRG-ROM, switchable 8K CHR-ROM bank
+pub struct Cnrom {
+    memory: CartridgeMemory,
+    mirroring: MirroringMode,
+    chr_bank: u8,
+}
+
+impl Cnrom {
+    pub fn new(memory: CartridgeMemory, mirroring: MirroringMode) -> Self {
+        Cnrom { memory, mirroring, chr_bank: 0 }
+    }
+}
+
+impl Mapper for Cnrom {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x8000..=0xFFFF => self.memory.read_prg((addr - 0x8000) as usize),
+            _ => 0,
+        }
+    }
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        if addr >= 0x8000 {
+            // Bus conflict: the ROM drives the data bus at the same time
+            self.chr_bank = value & self.cpu_read(addr) & 0x03;
+        }
+    }
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.memory.read_chr(self.chr_bank as usize * 0x2000 + addr as usize)
+    }
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        self.memory
+            .write_chr(self.chr_bank as usize * 0x2000 + addr as usize, value);
+    }
+    fn mirroring(&self) -> MirroringMode {
+        self.mirroring
+    }
+    fn memory(&self) -> &CartridgeMemory {
+        &self.memory
+    }
+    fn memory_mut(&mut self) -> &mut CartridgeMemory {
+        &mut self.memory
+    }
+    fn save_state(&self, w: &mut StateWriter) {
+        self.memory.save_state(w);
+        w.write_u8(self.chr_bank);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.memory.load_state(r)?;
+        self.chr_bank = r.read_u8_max(0x03)?;
+        Ok(())
+    }
+}
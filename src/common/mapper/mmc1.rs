@@ -0,0 +1,179 @@
+use super::super::rom::MirroringMode;
+use super::super::state::*;
+use super::*;
+
+/// Mapper 1 (MMC1 / SxROM)
+pub struct Mmc1 {
+    memory: CartridgeMemory,
+    shift_register: u8,
+    shift_count: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+    cycle: u64,
+    last_write_cycle: Option<u64>,
+}
+
+impl Mmc1 {
+    pub fn new(memory: CartridgeMemory) -> Self {
+        Mmc1 {
+            memory,
+            shift_register: 0,
+            shift_count: 0,
+            control: 0x0C,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            cycle: 0,
+            last_write_cycle: None,
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank & 0x10 == 0
+    }
+
+    /// 256K PRG block selected by CHR bank 0 bit 4 (SUROM, 512K PRG-ROM)
+    fn prg_outer_bank(&self) -> usize {
+        if self.memory.prg_rom().len() > 0x40000 {
+            (self.chr_bank_0 & 0x10) as usize
+        } else {
+            0
+        }
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        if self.control & 0x10 == 0 {
+            //8K
+            ((self.chr_bank_0 & 0x1E) as usize) * 0x1000 + addr as usize
+        } else {
+            //4K×2
+            let bank = if addr < 0x1000 {
+                self.chr_bank_0
+            } else {
+                self.chr_bank_1
+            };
+            (bank as usize) * 0x1000 + (addr & 0x0FFF) as usize
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x8000..=0x9FFF => self.control = value,
+            0xA000..=0xBFFF => self.chr_bank_0 = value,
+            0xC000..=0xDFFF => self.chr_bank_1 = value,
+            _ => self.prg_bank = value,
+        }
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => self.memory.read_prg_ram((addr - 0x6000) as usize),
+            0x8000..=0xFFFF => {
+                let last_bank = (self.memory.prg_rom().len() / 0x4000).saturating_sub(1) & 0x0F;
+                let bank = (self.prg_bank & 0x0F) as usize;
+                let bank = match (self.control >> 2) & 0b11 {
+                    0 | 1 => (bank & !1) | ((addr as usize >> 14) & 1),
+                    2 => {
+                        if addr < 0xC000 {
+                            0
+                        } else {
+                            bank
+                        }
+                    }
+                    _ => {
+                        if addr < 0xC000 {
+                            bank
+                        } else {
+                            last_bank
+                        }
+                    }
+                };
+                let bank = bank | self.prg_outer_bank();
+                self.memory.read_prg(bank * 0x4000 + (addr & 0x3FFF) as usize)
+            }
+            _ => 0,
+        }
+    }
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => self.memory.write_prg_ram((addr - 0x6000) as usize, value),
+            0x8000..=0xFFFF => {
+                // The serial port ignores a write on the cycle right after another one
+                // (the dummy write of read-modify-write instructions)
+                let consecutive = self.last_write_cycle == Some(self.cycle.wrapping_sub(1));
+                self.last_write_cycle = Some(self.cycle);
+                if consecutive {
+                    return;
+                }
+                if value & 0x80 != 0 {
+                    self.shift_register = 0;
+                    self.shift_count = 0;
+                    self.control |= 0x0C;
+                    return;
+                }
+                self.shift_register |= (value & 1) << self.shift_count;
+                self.shift_count += 1;
+                if self.shift_count == 5 {
+                    let value = self.shift_register;
+                    self.write_register(addr, value);
+                    self.shift_register = 0;
+                    self.shift_count = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.memory.read_chr(self.chr_offset(addr))
+    }
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        self.memory.write_chr(self.chr_offset(addr), value);
+    }
+    fn mirroring(&self) -> MirroringMode {
+        match self.control & 0b11 {
+            0 => MirroringMode::SingleScreenLower,
+            1 => MirroringMode::SingleScreenUpper,
+            2 => MirroringMode::Vertical,
+            _ => MirroringMode::Horizontal,
+        }
+    }
+    fn clock_cpu(&mut self) {
+        self.cycle = self.cycle.wrapping_add(1);
+    }
+    fn memory(&self) -> &CartridgeMemory {
+        &self.memory
+    }
+    fn memory_mut(&mut self) -> &mut CartridgeMemory {
+        &mut self.memory
+    }
+    fn save_state(&self, w: &mut StateWriter) {
+        self.memory.save_state(w);
+        w.write_u8(self.shift_register);
+        w.write_u8(self.shift_count);
+        w.write_u8(self.control);
+        w.write_u8(self.chr_bank_0);
+        w.write_u8(self.chr_bank_1);
+        w.write_u8(self.prg_bank);
+        w.write_u64(self.cycle);
+        w.write_bool(self.last_write_cycle.is_some());
+        w.write_u64(self.last_write_cycle.unwrap_or(0));
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.memory.load_state(r)?;
+        self.shift_register = r.read_u8_max(0x1F)?;
+        self.shift_count = r.read_u8_max(4)?;
+        self.control = r.read_u8_max(0x1F)?;
+        self.chr_bank_0 = r.read_u8_max(0x1F)?;
+        self.chr_bank_1 = r.read_u8_max(0x1F)?;
+        self.prg_bank = r.read_u8_max(0x1F)?;
+        self.cycle = r.read_u64()?;
+        let has_last_write = r.read_bool()?;
+        let last_write_cycle = r.read_u64()?;
+        self.last_write_cycle = if has_last_write { Some(last_write_cycle) } else { None };
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+use super::super::rom::MirroringMode;
+use super::super::state::*;
+use super::*;
+
+/// Mapper 4 (MMC3 / TxROM)
+pub struct Mmc3 {
+    memory: CartridgeMemory,
+    four_screen: bool,
+    bank_select: u8,
+    registers: [u8; 8],
+    horizontal_mirroring: bool,
+    prg_ram_protect: u8,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    a12: bool,
+    a12_low_cycles: u8,
+}
+
+impl Mmc3 {
+    /// A12 must stay low for this many CPU cycles before a rising edge clocks the counter
+    const A12_FILTER_CYCLES: u8 = 3;
+
+    pub fn new(memory: CartridgeMemory, mirroring: MirroringMode) -> Self {
+        Mmc3 {
+            memory,
+            four_screen: mirroring == MirroringMode::FourScreen,
+            bank_select: 0,
+            registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            horizontal_mirroring: mirroring == MirroringMode::Horizontal,
+            prg_ram_protect: 0x80,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            a12: false,
+            a12_low_cycles: 0,
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let bank_count = (self.memory.prg_rom().len() / 0x2000).max(1);
+        let second_last = bank_count.saturating_sub(2);
+        let prg_mode = self.bank_select & 0x40 != 0;
+        let bank = match (addr >> 13) & 0b11 {
+            0 => {
+                if prg_mode {
+                    second_last
+                } else {
+                    self.registers[6] as usize
+                }
+            }
+            1 => self.registers[7] as usize,
+            2 => {
+                if prg_mode {
+                    self.registers[6] as usize
+                } else {
+                    second_last
+                }
+            }
+            _ => bank_count - 1,
+        };
+        (bank & 0x3F) * 0x2000 + (addr & 0x1FFF) as usize
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        // CHR A12 inversion swaps the 2K and 1K halves
+        let addr = if self.bank_select & 0x80 != 0 {
+            addr ^ 0x1000
+        } else {
+            addr
+        };
+        match addr {
+            0x0000..=0x07FF => (self.registers[0] & 0xFE) as usize * 0x400 + (addr & 0x07FF) as usize,
+            0x0800..=0x0FFF => (self.registers[1] & 0xFE) as usize * 0x400 + (addr & 0x07FF) as usize,
+            _ => {
+                let register = 2 + ((addr - 0x1000) >> 10) as usize;
+                self.registers[register] as usize * 0x400 + (addr & 0x03FF) as usize
+            }
+        }
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_protect & 0x80 != 0 => self.memory.read_prg_ram((addr - 0x6000) as usize),
+            0x8000..=0xFFFF => self.memory.read_prg(self.prg_offset(addr)),
+            _ => 0,
+        }
+    }
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        let even = addr & 1 == 0;
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_protect & 0xC0 == 0x80 => {
+                self.memory.write_prg_ram((addr - 0x6000) as usize, value)
+            }
+            0x8000..=0x9FFF => {
+                if even {
+                    self.bank_select = value;
+                } else {
+                    self.registers[(self.bank_select & 0b111) as usize] = value;
+                }
+            }
+            0xA000..=0xBFFF => {
+                if even {
+                    self.horizontal_mirroring = value & 1 != 0;
+                } else {
+                    self.prg_ram_protect = value;
+                }
+            }
+            0xC000..=0xDFFF => {
+                if even {
+                    self.irq_latch = value;
+                } else {
+                    self.irq_counter = 0;
+                    self.irq_reload = true;
+                }
+            }
+            0xE000..=0xFFFF => {
+                if even {
+                    self.irq_enabled = false;
+                    self.irq_pending = false;
+                } else {
+                    self.irq_enabled = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.memory.read_chr(self.chr_offset(addr))
+    }
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        self.memory.write_chr(self.chr_offset(addr), value);
+    }
+    fn mirroring(&self) -> MirroringMode {
+        if self.four_screen {
+            MirroringMode::FourScreen
+        } else if self.horizontal_mirroring {
+            MirroringMode::Horizontal
+        } else {
+            MirroringMode::Vertical
+        }
+    }
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+    fn clock_cpu(&mut self) {
+        if !self.a12 {
+            self.a12_low_cycles = self.a12_low_cycles.saturating_add(1);
+        }
+    }
+    fn notify_ppu_addr(&mut self, addr: u16) {
+        let a12 = addr & 0x1000 != 0;
+        if a12 && !self.a12 && self.a12_low_cycles >= Self::A12_FILTER_CYCLES {
+            self.clock_irq_counter();
+        }
+        if !a12 && self.a12 {
+            self.a12_low_cycles = 0;
+        }
+        self.a12 = a12;
+    }
+    fn memory(&self) -> &CartridgeMemory {
+        &self.memory
+    }
+    fn memory_mut(&mut self) -> &mut CartridgeMemory {
+        &mut self.memory
+    }
+    fn save_state(&self, w: &mut StateWriter) {
+        self.memory.save_state(w);
+        w.write_u8(self.bank_select);
+        for register in &self.registers {
+            w.write_u8(*register);
+        }
+        w.write_bool(self.horizontal_mirroring);
+        w.write_u8(self.prg_ram_protect);
+        w.write_u8(self.irq_latch);
+        w.write_u8(self.irq_counter);
+        w.write_bool(self.irq_reload);
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.irq_pending);
+        w.write_bool(self.a12);
+        w.write_u8(self.a12_low_cycles);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.memory.load_state(r)?;
+        self.bank_select = r.read_u8()?;
+        for register in self.registers.iter_mut() {
+            *register = r.read_u8()?;
+        }
+        self.horizontal_mirroring = r.read_bool()?;
+        self.prg_ram_protect = r.read_u8()?;
+        self.irq_latch = r.read_u8()?;
+        self.irq_counter = r.read_u8()?;
+        self.irq_reload = r.read_bool()?;
+        self.irq_enabled = r.read_bool()?;
+        self.irq_pending = r.read_bool()?;
+        self.a12 = r.read_bool()?;
+        self.a12_low_cycles = r.read_u8()?;
+        Ok(())
+    }
+}
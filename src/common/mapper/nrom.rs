@@ -0,0 +1,51 @@
+use super::super::rom::MirroringMode;
+use super::super::state::*;
+use super::*;
+
+/// Mapper 0 (NROM): fixed 16K/32K PRG-ROM and 8K CHR
+pub struct Nrom {
+    memory: CartridgeMemory,
+    mirroring: MirroringMode,
+}
+
+impl Nrom {
+    pub fn new(memory: CartridgeMemory, mirroring: MirroringMode) -> Self {
+        Nrom { memory, mirroring }
+    }
+}
+
+impl Mapper for Nrom {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.memory.read_prg_ram((addr - 0x6000) as usize),
+            0x8000..=0xFFFF => self.memory.read_prg((addr - 0x8000) as usize),
+            _ => 0,
+        }
+    }
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        if let 0x6000..=0x7FFF = addr {
+            self.memory.write_prg_ram((addr - 0x6000) as usize, value);
+        }
+    }
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.memory.read_chr(addr as usize)
+    }
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        self.memory.write_chr(addr as usize, value);
+    }
+    fn mirroring(&self) -> MirroringMode {
+        self.mirroring
+    }
+    fn memory(&self) -> &CartridgeMemory {
+        &self.memory
+    }
+    fn memory_mut(&mut self) -> &mut CartridgeMemory {
+        &mut self.memory
+    }
+    fn save_state(&self, w: &mut StateWriter) {
+        self.memory.save_state(w);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.memory.load_state(r)
+    }
+}
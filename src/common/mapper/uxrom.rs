@@ -0,0 +1,61 @@
+use super::super::rom::MirroringMode;
+use super::super::state::*;
+use super::*;
+
+/// Mapper 2 (UxROM): switchable 16K PRG bank at $8000, last bank fixed at $C000
+pub struct Uxrom {
+    memory: CartridgeMemory,
+    mirroring: MirroringMode,
+    prg_bank: u8,
+}
+
+impl Uxrom {
+    pub fn new(memory: CartridgeMemory, mirroring: MirroringMode) -> Self {
+        Uxrom { memory, mirroring, prg_bank: 0 }
+    }
+}
+
+impl Mapper for Uxrom {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x8000..=0xBFFF => self
+                .memory
+                .read_prg(self.prg_bank as usize * 0x4000 + (addr & 0x3FFF) as usize),
+            0xC000..=0xFFFF => {
+                let last_bank = (self.memory.prg_rom().len() / 0x4000).saturating_sub(1);
+                self.memory.read_prg(last_bank * 0x4000 + (addr & 0x3FFF) as usize)
+            }
+            _ => 0,
+        }
+    }
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        if addr >= 0x8000 {
+            // Bus conflict: the ROM drives the data bus at the same time
+            self.prg_bank = value & self.cpu_read(addr) & 0x0F;
+        }
+    }
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.memory.read_chr(addr as usize)
+    }
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        self.memory.write_chr(addr as usize, value);
+    }
+    fn mirroring(&self) -> MirroringMode {
+        self.mirroring
+    }
+    fn memory(&self) -> &CartridgeMemory {
+        &self.memory
+    }
+    fn memory_mut(&mut self) -> &mut CartridgeMemory {
+        &mut self.memory
+    }
+    fn save_state(&self, w: &mut StateWriter) {
+        self.memory.save_state(w);
+        w.write_u8(self.prg_bank);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.memory.load_state(r)?;
+        self.prg_bank = r.read_u8_max(0x0F)?;
+        Ok(())
+    }
+}
@@ -1,23 +1,106 @@
 use super::apu::*;
 use super::cpu::*;
+use super::debugger::*;
+use super::disasm::{self, Decoded};
 use super::ppu::*;
+use super::rewind::*;
 use super::rom::*;
+use super::state::*;
+use std::ops::RangeInclusive;
 
-/// PPU clocks per CPU clock
-const PPU_CLOCKS_PER_CPU: u8 = 3;
+pub use super::debugger::{
+    Access, BreakpointId, Comparison, Condition, CpuRegisters, MemorySpace, Register, StopReason, MAX_RUN_FRAMES,
+};
+pub use super::rom::{ConsoleType, HeaderFormat, MirroringMode, RomError, RomHeader, Timing};
+pub use super::state::{StateError, STATE_VERSION};
 
-/// CPU clock rate / target sample rate — used for downsampling
-const CPU_CLOCK_RATE: f64 = 1_789_773.0;
+/// Target sample rate — used for downsampling
 const TARGET_SAMPLE_RATE: f64 = 44_100.0;
-const CYCLES_PER_SAMPLE: f64 = CPU_CLOCK_RATE / TARGET_SAMPLE_RATE;
 
-/// Maximum audio samples per frame (slightly over 44100/60 ≈ 735 + margin)
-const MAX_SAMPLES_PER_FRAME: usize = 750;
+/// Maximum audio samples per frame (slightly over 44100/50 = 882 + margin)
+const MAX_SAMPLES_PER_FRAME: usize = 900;
+
+/// Receives one line of CPU trace output
+pub type TraceCallback = Box<dyn FnMut(&str)>;
+
+/// Console timing variant
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Region {
+    /// NTSC (RP2A03/RP2C02)
+    Ntsc,
+    /// PAL (RP2A07/RP2C07)
+    Pal,
+    /// Famiclone with an NTSC-like CPU and APU running a 312-line frame (UA6527P/UA6538)
+    Dendy,
+}
+
+impl Region {
+    pub const ALL: [Region; 3] = [Region::Ntsc, Region::Pal, Region::Dendy];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Region::Ntsc => "NTSC",
+            Region::Pal => "PAL",
+            Region::Dendy => "Dendy",
+        }
+    }
+
+    /// Region matching the header timing. Multi-region games run as NTSC.
+    pub fn from_timing(timing: Timing) -> Self {
+        match timing {
+            Timing::Ntsc | Timing::MultiRegion => Region::Ntsc,
+            Timing::Pal => Region::Pal,
+            Timing::Dendy => Region::Dendy,
+        }
+    }
+
+    /// CPU clock rate in Hz
+    pub fn cpu_clock_rate(&self) -> f64 {
+        match self {
+            Region::Ntsc => 1_789_773.0,
+            Region::Pal => 1_662_607.0,
+            Region::Dendy => 1_773_448.0,
+        }
+    }
+
+    /// PPU clocks per CPU clock as (numerator, denominator): 3 or 3.2
+    pub(crate) fn ppu_clocks_per_cpu(&self) -> (u8, u8) {
+        match self {
+            Region::Ntsc | Region::Dendy => (3, 1),
+            Region::Pal => (16, 5),
+        }
+    }
+
+    /// Scanlines per frame, including the pre-render line
+    pub fn scanlines(&self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    /// Scanline at whose second dot the vblank flag is set
+    pub(crate) fn vblank_line(&self) -> u16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            //Dendyはポストレンダーが51ライン続いてからVblankが20ライン
+            Region::Dendy => 291,
+        }
+    }
+
+    /// Frames per second
+    pub fn frame_rate(&self) -> f64 {
+        let (numerator, denominator) = self.ppu_clocks_per_cpu();
+        self.cpu_clock_rate() * numerator as f64 / denominator as f64 / (341.0 * self.scanlines() as f64)
+    }
+}
 
 pub struct Nes {
     cpu: Cpu,
     ppu: Ppu,
     rom: Rom,
+    region: Region,
+    /// Progress towards the next CPU clock, in 1/denominator PPU clocks (see `Region::ppu_clocks_per_cpu`)
     clock_count: u8,
     apu: Apu,
     last_nmi: bool,
@@ -27,6 +110,45 @@ pub struct Nes {
     resample_fraction: f64,
     sample_accumulator: f64,
     sample_count: u32,
+    rewind: Option<Rewind>,
+    debugger: Debugger,
+}
+
+/// Contents of the console's internal RAM (and non-battery cartridge RAM) after power on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RamPowerOnState {
+    /// All $00
+    Zero,
+    /// All $FF
+    Ones,
+    /// Four $00 bytes and four $FF bytes, repeated
+    Alternating,
+    /// Pseudo-random bytes from the given seed
+    Random(u32),
+}
+
+impl RamPowerOnState {
+    pub fn fill(&self, ram: &mut [u8]) {
+        match *self {
+            RamPowerOnState::Zero => ram.fill(0x00),
+            RamPowerOnState::Ones => ram.fill(0xFF),
+            RamPowerOnState::Alternating => {
+                for (i, byte) in ram.iter_mut().enumerate() {
+                    *byte = if i & 4 == 0 { 0x00 } else { 0xFF };
+                }
+            }
+            RamPowerOnState::Random(seed) => {
+                // xorshift32 (a zero seed would get stuck at zero)
+                let mut x = seed.max(1);
+                for byte in ram.iter_mut() {
+                    x ^= x << 13;
+                    x ^= x >> 17;
+                    x ^= x << 5;
+                    *byte = x as u8;
+                }
+            }
+        }
+    }
 }
 
 pub struct PadInputs {
@@ -53,12 +175,15 @@ impl Nes {
     pub fn get_version() -> String {
         env!("CARGO_PKG_VERSION").into()
     }
-    pub fn new(rom: &[u8]) -> Result<Self, String> {
+    /// Load a ROM. The region is chosen from the header's timing (see `Region::from_timing`).
+    pub fn new(rom: &[u8]) -> Result<Self, RomError> {
         let rom = Rom::load(rom)?;
-        let nes = Nes {
+        let region = Region::from_timing(rom.header.timing);
+        let mut nes = Nes {
             cpu: Cpu::new(),
-            ppu: Ppu::new(rom.mirroring, rom.has_chr_ram()),
+            ppu: Ppu::new(),
             rom,
+            region,
             clock_count: 0,
             apu: Apu::new(),
             last_nmi: false,
@@ -66,29 +191,34 @@ impl Nes {
             resample_fraction: 0.0,
             sample_accumulator: 0.0,
             sample_count: 0,
+            rewind: None,
+            debugger: Debugger::default(),
         };
+        nes.set_region(region);
 
         Ok(nes)
     }
 
     /// Single PPU-clock step. Returns (end_frame, apu_sample).
-    /// Called at PPU rate (3× CPU rate).
+    /// Called at PPU rate (3× or 3.2× CPU rate depending on the region).
     pub fn clock(&mut self, pad: &PadInputs) -> (bool, Option<f32>) {
         let mut apu_out = None;
-        if self.clock_count == 0 {
-            self.cpu.clock(&self.rom, &mut self.apu, &mut self.ppu, pad);
-            let value = self.apu.clock(self.rom.get_prog());
+        if self.cpu_clock_due() {
+            self.cpu.clock(&mut self.rom, &mut self.apu, &mut self.ppu, pad);
+            self.rom.mapper.clock_cpu();
+            let value = self.apu.clock();
             apu_out = Some(value);
         }
 
-        let (end_frame, nmi) = self.ppu.clock(&self.rom);
+        let (end_frame, nmi) = self.ppu.clock(&mut self.rom);
         if nmi && nmi != self.last_nmi {
             self.cpu.nmi();
         }
         self.last_nmi = nmi;
 
-        self.clock_count += 1;
-        self.clock_count %= PPU_CLOCKS_PER_CPU;
+        if end_frame {
+            self.end_frame();
+        }
 
         (end_frame, apu_out)
     }
@@ -98,19 +228,21 @@ impl Nes {
     /// The returned slice borrows from the internal buffer and is valid until the next call.
     pub fn clock_frame(&mut self, pad: &PadInputs) -> &[f32] {
         let mut sample_idx: usize = 0;
+        let cycles_per_sample = self.region.cpu_clock_rate() / TARGET_SAMPLE_RATE;
 
         loop {
-            if self.clock_count == 0 {
-                self.cpu.clock(&self.rom, &mut self.apu, &mut self.ppu, pad);
+            if self.cpu_clock_due() {
+                self.cpu.clock(&mut self.rom, &mut self.apu, &mut self.ppu, pad);
+                self.rom.mapper.clock_cpu();
 
                 // Clock APU inline to maintain correct timing with CPU
-                let sample = self.apu.clock(self.rom.get_prog()) as f64;
+                let sample = self.apu.clock() as f64;
                 self.sample_accumulator += sample;
                 self.sample_count += 1;
                 self.resample_fraction += 1.0;
 
-                if self.resample_fraction >= CYCLES_PER_SAMPLE {
-                    self.resample_fraction -= CYCLES_PER_SAMPLE;
+                if self.resample_fraction >= cycles_per_sample {
+                    self.resample_fraction -= cycles_per_sample;
                     if sample_idx < MAX_SAMPLES_PER_FRAME {
                         let avg = (self.sample_accumulator / self.sample_count as f64) as f32;
                         self.audio_buf[sample_idx] = avg;
@@ -121,16 +253,14 @@ impl Nes {
                 }
             }
 
-            let (end_frame, nmi) = self.ppu.clock(&self.rom);
+            let (end_frame, nmi) = self.ppu.clock(&mut self.rom);
             if nmi && nmi != self.last_nmi {
                 self.cpu.nmi();
             }
             self.last_nmi = nmi;
 
-            self.clock_count += 1;
-            self.clock_count %= PPU_CLOCKS_PER_CPU;
-
             if end_frame {
+                self.end_frame();
                 break;
             }
         }
@@ -138,7 +268,388 @@ impl Nes {
         &self.audio_buf[..sample_idx]
     }
 
-    pub fn get_screen(&self) -> &[u8; 256 * 240] {
+    /// Advance `clock_count` by one PPU clock; true if the CPU runs on this one
+    #[inline(always)]
+    fn cpu_clock_due(&mut self) -> bool {
+        let (numerator, denominator) = self.region.ppu_clocks_per_cpu();
+        let due = self.clock_count < denominator;
+        if due {
+            self.clock_count += numerator;
+        }
+        self.clock_count -= denominator;
+        due
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Switch the console timing. Takes effect immediately, but games expect to start on the
+    /// console they run on, so follow it with `power_cycle`.
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.clock_count = 0;
+        self.ppu.set_region(region);
+        self.apu.set_region(region);
+    }
+
+    /// Header of the loaded cartridge
+    pub fn get_rom_header(&self) -> &RomHeader {
+        &self.rom.header
+    }
+
+    /// Battery-backed PRG-RAM, or None if the cartridge has no battery
+    pub fn save_ram(&self) -> Option<&[u8]> {
+        let ram = self.rom.mapper.memory().prg_ram();
+        if self.rom.header.has_battery && !ram.is_empty() {
+            Some(ram)
+        } else {
+            None
+        }
+    }
+
+    /// Restore battery-backed PRG-RAM (e.g. from a .sav file) and clear the dirty flag.
+    /// Does nothing if the cartridge has no battery.
+    pub fn load_save_ram(&mut self, data: &[u8]) {
+        if self.rom.header.has_battery {
+            self.rom.mapper.memory_mut().load_prg_ram(data);
+        }
+    }
+
+    /// True if the save RAM changed since it was loaded or the flag was last cleared
+    pub fn is_save_ram_dirty(&self) -> bool {
+        self.rom.header.has_battery && self.rom.mapper.memory().is_prg_ram_dirty()
+    }
+
+    /// Call after persisting save_ram()
+    pub fn clear_save_ram_dirty(&mut self) {
+        self.rom.mapper.memory_mut().clear_prg_ram_dirty();
+    }
+
+    fn rom_fingerprint(&self) -> u32 {
+        let memory = self.rom.mapper.memory();
+        rom_fingerprint(memory.prg_rom(), memory.chr_rom())
+    }
+
+    /// Snapshot the whole machine. See `state.rs` for the format.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new(self.rom_fingerprint());
+        w.write_u8(self.region as u8);
+        w.write_u8(self.clock_count);
+        w.write_bool(self.last_nmi);
+        w.write_f64(self.resample_fraction);
+        w.write_f64(self.sample_accumulator);
+        w.write_u32(self.sample_count);
+        self.cpu.save_state(&mut w);
+        self.ppu.save_state(&mut w);
+        self.apu.save_state(&mut w);
+        self.rom.mapper.save_state(&mut w);
+        w.into_inner()
+    }
+
+    /// Restore a snapshot taken by `save_state` with the same ROM.
+    /// On error the machine is left unchanged.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let backup = self.save_state();
+        let result = self.restore_state(state);
+        if result.is_err() {
+            self.restore_state(&backup).unwrap();
+        }
+        result
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(state, self.rom_fingerprint())?;
+        let region = Region::ALL[r.read_u8_max(Region::ALL.len() as u8 - 1)? as usize];
+        self.set_region(region);
+        let (numerator, _) = region.ppu_clocks_per_cpu();
+        self.clock_count = r.read_u8_max(numerator - 1)?;
+        self.last_nmi = r.read_bool()?;
+        self.resample_fraction = r.read_f64()?;
+        self.sample_accumulator = r.read_f64()?;
+        self.sample_count = r.read_u32()?;
+        self.cpu.load_state(&mut r)?;
+        self.ppu.load_state(&mut r)?;
+        self.apu.load_state(&mut r)?;
+        self.rom.mapper.load_state(&mut r)?;
+        r.finish()
+    }
+
+    /// Press the reset button. RAM, VRAM and the cartridge keep their contents.
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.apu.reset();
+        self.ppu.reset();
+    }
+
+    /// Turn the console off and on again. Everything is reinitialized except battery-backed RAM;
+    /// internal RAM is filled with `ram_pattern`.
+    pub fn power_cycle(&mut self, ram_pattern: RamPowerOnState) {
+        let tracer = self.cpu.take_tracer();
+        let cpu_watches = std::mem::take(self.cpu.watches_mut());
+        self.cpu = Cpu::new();
+        self.cpu.set_tracer(tracer);
+        *self.cpu.watches_mut() = cpu_watches;
+        self.cpu.fill_ram(ram_pattern);
+        let sprite_limit = self.ppu.sprite_limit();
+        let ppu_watches = std::mem::take(self.ppu.watches_mut());
+        self.ppu = Ppu::new();
+        self.ppu.set_sprite_limit(sprite_limit);
+        *self.ppu.watches_mut() = ppu_watches;
+        self.apu = Apu::new();
+        self.rom.power_cycle(ram_pattern);
+        self.set_region(self.region);
+        self.last_nmi = false;
+        self.resample_fraction = 0.0;
+        self.sample_accumulator = 0.0;
+        self.sample_count = 0;
+    }
+
+    /// Enable or disable the 8 sprites per line limit (enabled by default, like hardware).
+    /// Disabling it removes sprite flicker but some games rely on the limit to hide sprites.
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.ppu.set_sprite_limit(enabled);
+    }
+
+    /// Call `callback` with a line in nestest.log format (including the PPU scanline/dot and CYC
+    /// columns) before every instruction. `None` stops tracing.
+    pub fn set_trace_callback(&mut self, callback: Option<TraceCallback>) {
+        self.cpu.set_tracer(callback);
+    }
+
+    /// Write a line in nestest.log format to `writer` before every instruction. Write errors are ignored.
+    pub fn set_trace_writer(&mut self, mut writer: impl std::io::Write + 'static) {
+        self.set_trace_callback(Some(Box::new(move |line| {
+            let _ = writeln!(writer, "{}", line);
+        })));
+    }
+
+    /// What the CPU would read at `addr`, without side effects (pad shift, clearing vblank, advancing
+    /// the PPUDATA buffer...). The controller ports read as 0.
+    pub fn peek_cpu(&self, addr: u16) -> u8 {
+        match addr {
+            0x2000..=0x3FFF => self.ppu.peek(&self.rom, (addr & 0x7) as u8),
+            0x4000..=0x401F => self.apu.peek(addr as u8),
+            _ => self.cpu.peek(&self.rom, addr),
+        }
+    }
+
+    /// Read the PPU address space ($0000-$3FFF, mirrored above) without side effects
+    pub fn peek_ppu(&self, addr: u16) -> u8 {
+        self.ppu.peek_v_ram(&self.rom, addr)
+    }
+
+    /// Overwrite internal RAM ($0000-$1FFF) or cartridge PRG-RAM ($6000-$7FFF) without going through the
+    /// bus. Returns false for other addresses (registers and ROM), which are left alone.
+    pub fn poke_cpu(&mut self, addr: u16, value: u8) -> bool {
+        match addr {
+            0x0000..=0x1FFF => {
+                self.cpu.ram_mut()[(addr & 0x07FF) as usize] = value;
+                true
+            }
+            0x6000..=0x7FFF if !self.rom.mapper.memory().prg_ram().is_empty() => {
+                self.rom
+                    .mapper
+                    .memory_mut()
+                    .write_prg_ram((addr - 0x6000) as usize, value);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Internal RAM ($0000-$07FF)
+    pub fn wram(&self) -> &[u8] {
+        self.cpu.ram()
+    }
+
+    /// Sprite memory (256 bytes)
+    pub fn oam(&self) -> &[u8] {
+        self.ppu.oam()
+    }
+
+    /// Palette RAM as seen at $3F00-$3F1F
+    pub fn palette_ram(&self) -> [u8; 0x20] {
+        std::array::from_fn(|i| self.peek_ppu(0x3F00 + i as u16))
+    }
+
+    /// The four nametables at $2000-$2FFF, after mirroring
+    pub fn nametables(&self) -> Vec<u8> {
+        (0x2000..0x3000).map(|addr| self.peek_ppu(addr)).collect()
+    }
+
+    /// The pattern tables at $0000-$1FFF, as currently banked in
+    pub fn chr(&self) -> Vec<u8> {
+        (0x0000..0x2000).map(|addr| self.peek_ppu(addr)).collect()
+    }
+
+    /// Decode `count` instructions from CPU memory starting at `addr`.
+    /// PPU, APU and I/O registers read as $FF.
+    pub fn disassemble(&self, addr: u16, count: usize) -> Vec<Decoded> {
+        let mut instructions = Vec::with_capacity(count);
+        let mut addr = addr;
+        for _ in 0..count {
+            let decoded = disasm::decode(|addr| self.cpu.peek(&self.rom, addr), addr);
+            addr = decoded.next_addr();
+            instructions.push(decoded);
+        }
+        instructions
+    }
+
+    pub fn cpu_registers(&self) -> CpuRegisters {
+        self.cpu.registers()
+    }
+
+    /// Stop before the instruction at `addr` runs. Breakpoints and watchpoints are only checked by the
+    /// `step_*` functions; `clock` and `clock_frame` run past them.
+    pub fn add_breakpoint(&mut self, addr: u16) -> BreakpointId {
+        self.debugger.add(Some(addr), None)
+    }
+
+    /// Stop before an instruction at `addr` (or any address if None) runs with `condition` met
+    pub fn add_conditional_breakpoint(&mut self, addr: Option<u16>, condition: Condition) -> BreakpointId {
+        self.debugger.add(addr, Some(condition))
+    }
+
+    /// Stop when an address in `addrs` is accessed
+    pub fn add_watchpoint(&mut self, space: MemorySpace, addrs: RangeInclusive<u16>, access: Access) -> BreakpointId {
+        let id = self.debugger.new_id();
+        match space {
+            MemorySpace::Cpu => self.cpu.watches_mut().add(id, addrs, access),
+            MemorySpace::Ppu => self.ppu.watches_mut().add(id, addrs, access),
+        }
+        id
+    }
+
+    /// Remove a breakpoint or watchpoint. Returns false if there was no such id.
+    pub fn remove_breakpoint(&mut self, id: BreakpointId) -> bool {
+        self.debugger.remove(id) || self.cpu.watches_mut().remove(id) || self.ppu.watches_mut().remove(id)
+    }
+
+    /// Remove all breakpoints and watchpoints
+    pub fn clear_breakpoints(&mut self) {
+        self.debugger.clear();
+        self.cpu.watches_mut().clear();
+        self.ppu.watches_mut().clear();
+    }
+
+    /// Run until the next instruction boundary. Audio produced by the `step_*` functions is discarded.
+    pub fn step_instruction(&mut self, pad: &PadInputs) -> StopReason {
+        self.debug_run(pad, |_, _, boundary| boundary)
+    }
+
+    /// Like `step_instruction`, but a JSR runs until the subroutine returns
+    pub fn step_over(&mut self, pad: &PadInputs) -> StopReason {
+        if !self.cpu.at_instruction_boundary() || !self.cpu.at_subroutine_call(&self.rom) {
+            return self.step_instruction(pad);
+        }
+        let registers = self.cpu.registers();
+        let return_addr = registers.pc.wrapping_add(3);
+        self.debug_run(pad, |nes, _, boundary| {
+            //再帰呼び出しの途中で同じアドレスに戻ってきた場合はスタックで区別する
+            boundary && nes.cpu.registers().pc == return_addr && nes.cpu.registers().sp >= registers.sp
+        })
+    }
+
+    /// Run until the current subroutine (or interrupt handler) returns with RTS or RTI
+    pub fn step_out(&mut self, pad: &PadInputs) -> StopReason {
+        let sp = self.cpu.registers().sp;
+        self.debug_run(pad, |nes, _, boundary| {
+            boundary && nes.cpu.is_returning() && nes.cpu.registers().sp > sp
+        })
+    }
+
+    /// Run until the PPU moves to another scanline
+    pub fn step_scanline(&mut self, pad: &PadInputs) -> StopReason {
+        let (scanline, _) = self.ppu.position();
+        self.debug_run(pad, |nes, _, _| nes.ppu.position().0 != scanline)
+    }
+
+    /// Run until the end of the frame (or the next breakpoint)
+    pub fn step_frame(&mut self, pad: &PadInputs) -> StopReason {
+        self.debug_run(pad, |_, end_frame, _| end_frame)
+    }
+
+    /// Clock until `done(nes, end_frame, instruction_boundary)` returns true or a breakpoint is hit
+    fn debug_run(&mut self, pad: &PadInputs, mut done: impl FnMut(&Nes, bool, bool) -> bool) -> StopReason {
+        //通常実行中に記録されたヒットは捨てる
+        self.cpu.watches_mut().take_hit();
+        self.ppu.watches_mut().take_hit();
+        let mut frames = 0;
+        loop {
+            let cycles = self.cpu.cycles();
+            let (end_frame, _) = self.clock(pad);
+            if let Some(hit) = self.cpu.watches_mut().take_hit() {
+                return hit.stop_reason(MemorySpace::Cpu);
+            }
+            if let Some(hit) = self.ppu.watches_mut().take_hit() {
+                return hit.stop_reason(MemorySpace::Ppu);
+            }
+            let boundary = self.cpu.cycles() != cycles && self.cpu.at_instruction_boundary();
+            if done(self, end_frame, boundary) {
+                return StopReason::Done;
+            }
+            if boundary {
+                let registers = self.cpu.registers();
+                if let Some(id) = self.debugger.check(&registers) {
+                    return StopReason::Breakpoint { id, pc: registers.pc };
+                }
+            }
+            if end_frame {
+                frames += 1;
+                if frames >= MAX_RUN_FRAMES {
+                    return StopReason::Timeout;
+                }
+            }
+        }
+    }
+
+    fn end_frame(&mut self) {
+        if self.rewind.as_mut().is_some_and(|rewind| rewind.end_frame()) {
+            let state = self.save_state();
+            self.rewind.as_mut().unwrap().push(state);
+        }
+    }
+
+    /// Start recording rewind snapshots: up to `capacity` snapshots, one every `interval` frames.
+    /// Restarts recording if rewind is already enabled.
+    pub fn enable_rewind(&mut self, capacity: usize, interval: u32) {
+        self.rewind = Some(Rewind::new(capacity, interval));
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    /// Number of frames `rewind` can currently go back
+    pub fn rewind_available_frames(&self) -> u32 {
+        self.rewind.as_ref().map_or(0, |rewind| rewind.available_frames())
+    }
+
+    /// Bytes currently held by the rewind buffer
+    pub fn rewind_memory_usage(&self) -> usize {
+        self.rewind.as_ref().map_or(0, |rewind| rewind.memory_usage())
+    }
+
+    /// Roll back at least `frames` frames (rounded up to the snapshot interval), or as far as the
+    /// buffer goes. Returns how many frames were actually rewound.
+    pub fn rewind(&mut self, frames: u32) -> u32 {
+        let Some(mut rewind) = self.rewind.take() else {
+            return 0;
+        };
+        let rewound = match rewind.rewind(frames) {
+            Some((state, rewound)) => {
+                self.load_state(state).unwrap();
+                rewound
+            }
+            None => 0,
+        };
+        self.rewind = Some(rewind);
+        rewound
+    }
+
+    /// 9-bit pixels (palette color and emphasis bits), see `util::NES_PALETTE_512`
+    pub fn get_screen(&self) -> &[u16; 256 * 240] {
         self.ppu.get_screen()
     }
 }
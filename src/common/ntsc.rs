@@ -0,0 +1,119 @@
+//! NTSC composite video filter, in the style of blargg's nes_ntsc
+//!
+//! Each pixel of `Nes::get_screen` is turned back into the composite signal the PPU outputs
+//! (8 samples per pixel, 12 per color subcarrier cycle) and decoded the way a TV does. Luma and
+//! chroma share the signal, so edges pick up artifact colors, and the pattern crawls from frame
+//! to frame as the subcarrier phase moves.
+
+use std::f64::consts::PI;
+
+/// 256 pixels become 7 output pixels for every 3 input pixels
+pub const OUTPUT_WIDTH: usize = 602;
+pub const OUTPUT_HEIGHT: usize = 240;
+
+const SAMPLES_PER_PIXEL: usize = 8;
+const LINE_SAMPLES: usize = 256 * SAMPLES_PER_PIXEL;
+/// Chroma is averaged over 2 subcarrier cycles
+const CHROMA_WINDOW: usize = 24;
+
+/// Subcarrier phase (in 1/12 cycles) at which the decoder's I axis lines up with the colors
+pub(crate) const DECODER_HUE: f64 = 4.0;
+/// Gamma correction from the TV's 2.2 to the 1.8 the palettes are tuned for
+pub(crate) const DECODER_GAMMA: f64 = 2.2 / 1.8;
+
+/// Adjustments on top of the default decoding. All range from -1.0 to 1.0; 0.0 is the default.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FilterSettings {
+    /// -1.0 and 1.0 rotate colors by 180°
+    pub hue: f64,
+    /// -1.0 is greyscale, 1.0 doubles the saturation
+    pub saturation: f64,
+    /// Negative values blur; positive values sharpen, leaving more dot crawl in the luma
+    pub sharpness: f64,
+    pub brightness: f64,
+    pub contrast: f64,
+}
+
+/// Filter a frame into `OUTPUT_WIDTH` x `OUTPUT_HEIGHT` RGB pixels. `burst_phase` (0-2) is the
+/// subcarrier phase the frame starts on; advance it every frame for dot crawl, or keep it
+/// constant for a still image.
+pub fn filter(screen: &[u16; 256 * 240], burst_phase: usize, settings: &FilterSettings) -> Vec<[u8; 3]> {
+    let luma_window = (12.0 - 6.0 * settings.sharpness.clamp(-1.0, 1.0)).round() as usize;
+    let hue = DECODER_HUE + settings.hue.clamp(-1.0, 1.0) * 6.0;
+    let saturation = 1.0 + settings.saturation.clamp(-1.0, 1.0);
+    let contrast = 1.0 + settings.contrast.clamp(-1.0, 1.0);
+    let brightness = settings.brightness.clamp(-1.0, 1.0);
+    let cos: [f64; 12] = std::array::from_fn(|phase| (PI * (phase as f64 + hue) / 6.0).cos());
+    let sin: [f64; 12] = std::array::from_fn(|phase| (PI * (phase as f64 + hue) / 6.0).sin());
+
+    let mut output = vec![[0; 3]; OUTPUT_WIDTH * OUTPUT_HEIGHT];
+    //累積和で窓内の合計を求める
+    let mut sum_y = vec![0.0; LINE_SAMPLES + 1];
+    let mut sum_i = vec![0.0; LINE_SAMPLES + 1];
+    let mut sum_q = vec![0.0; LINE_SAMPLES + 1];
+    for (y, line) in screen.chunks_exact(256).enumerate() {
+        //341ドットのラインごとに位相が4サンプルずれる
+        let line_phase = (burst_phase + y) * 4 % 12;
+        for n in 0..LINE_SAMPLES {
+            let phase = (n + line_phase) % 12;
+            let value = signal(line[n / SAMPLES_PER_PIXEL], phase);
+            sum_y[n + 1] = sum_y[n] + value;
+            sum_i[n + 1] = sum_i[n] + value * cos[phase];
+            sum_q[n + 1] = sum_q[n] + value * sin[phase];
+        }
+        let row = &mut output[y * OUTPUT_WIDTH..][..OUTPUT_WIDTH];
+        for (x, rgb) in row.iter_mut().enumerate() {
+            let center = (2 * x + 1) * LINE_SAMPLES / (2 * OUTPUT_WIDTH);
+            //画面外は黒 (0) として平均する
+            let average = |sums: &[f64], len: usize| {
+                let start = center.saturating_sub(len / 2);
+                let end = (center + len - len / 2).min(LINE_SAMPLES);
+                (sums[end] - sums[start]) / len as f64
+            };
+            let luma = average(&sum_y, luma_window) * contrast + brightness;
+            let i = average(&sum_i, CHROMA_WINDOW) * saturation;
+            let q = average(&sum_q, CHROMA_WINDOW) * saturation;
+            *rgb = yiq_to_rgb(luma, i, q, DECODER_GAMMA);
+        }
+    }
+    output
+}
+
+/// Composite signal of a 9-bit pixel at a subcarrier phase (0-11), from the voltages measured
+/// on a 2C02. 0.0 is black and 1.0 white.
+pub(crate) fn signal(pixel: u16, phase: usize) -> f64 {
+    //輝度ごとの低/高電圧 (V)
+    const LOW: [f64; 4] = [0.350, 0.518, 0.962, 1.550];
+    const HIGH: [f64; 4] = [1.094, 1.506, 1.962, 1.962];
+    const BLACK: f64 = 0.518;
+    const WHITE: f64 = 1.962;
+    //強調ビットは補色の位相で信号を弱める
+    const EMPHASIS_ATTENUATION: f64 = 0.746;
+    let in_color_phase = |color: usize| (color + phase) % 12 < 6;
+
+    let pixel = pixel as usize;
+    let color = pixel & 0x0F;
+    let emphasis = (pixel >> 6) & 0b111;
+    //$xE/$xF は黒
+    let level = if color > 13 { 1 } else { (pixel >> 4) & 3 };
+    let low = if color == 0 { HIGH[level] } else { LOW[level] };
+    let high = if color > 12 { low } else { HIGH[level] };
+
+    let mut voltage = if in_color_phase(color) { high } else { low };
+    if (emphasis & 0b001 != 0 && in_color_phase(0xC))
+        || (emphasis & 0b010 != 0 && in_color_phase(0x4))
+        || (emphasis & 0b100 != 0 && in_color_phase(0x8))
+    {
+        voltage *= EMPHASIS_ATTENUATION;
+    }
+    (voltage - BLACK) / (WHITE - BLACK)
+}
+
+pub(crate) fn yiq_to_rgb(y: f64, i: f64, q: f64, gamma: f64) -> [u8; 3] {
+    let channels = [
+        y + 0.946882 * i + 0.623557 * q,
+        y - 0.274788 * i - 0.635691 * q,
+        y - 1.108545 * i + 1.709007 * q,
+    ];
+    channels.map(|value| (value.max(0.0).powf(gamma) * 255.0).round().clamp(0.0, 255.0) as u8)
+}
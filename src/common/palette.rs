@@ -0,0 +1,142 @@
+//! Palettes turning the 9-bit pixels of `Nes::get_screen` into RGB
+
+use super::ntsc::{signal, yiq_to_rgb, DECODER_GAMMA, DECODER_HUE};
+use super::util::{expand_emphasis, NES_PALETTE};
+
+/// Reason a .pal file could not be loaded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaletteError {
+    /// .pal files hold 64 colors (192 bytes) or 512 colors with emphasis (1536 bytes)
+    InvalidSize(usize),
+}
+
+impl std::fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PaletteError::InvalidSize(size) => {
+                write!(f, "palette file must be 192 or 1536 bytes, got {} bytes", size)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PaletteError {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PalettePreset {
+    /// The palette yNES has always used (`util::NES_PALETTE`)
+    Classic,
+    /// Decoded from the composite signal levels measured on a 2C02
+    Ntsc2C02,
+    /// The 2C02 decode with less saturation and no gamma correction, for softer colors
+    Smooth,
+    /// Saturated, high contrast colors in the style of a Sony PVM monitor
+    Pvm,
+}
+
+impl PalettePreset {
+    pub const ALL: [PalettePreset; 4] = [
+        PalettePreset::Classic,
+        PalettePreset::Ntsc2C02,
+        PalettePreset::Smooth,
+        PalettePreset::Pvm,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PalettePreset::Classic => "Classic",
+            PalettePreset::Ntsc2C02 => "2C02 (measured)",
+            PalettePreset::Smooth => "Smooth",
+            PalettePreset::Pvm => "PVM-style",
+        }
+    }
+}
+
+/// RGB colors for all 512 combinations of palette color and emphasis bits
+#[derive(Clone)]
+pub struct Palette {
+    colors: Box<[[u8; 3]; 512]>,
+}
+
+impl Palette {
+    pub fn preset(preset: PalettePreset) -> Self {
+        let colors = match preset {
+            PalettePreset::Classic => Box::new(expand_emphasis(&NES_PALETTE)),
+            PalettePreset::Ntsc2C02 => {
+                decode_ntsc(&NtscSettings { hue: 0.0, saturation: 1.0, contrast: 1.0, gamma: DECODER_GAMMA })
+            }
+            PalettePreset::Smooth => {
+                decode_ntsc(&NtscSettings { hue: 0.0, saturation: 0.8, contrast: 1.0, gamma: 1.0 })
+            }
+            PalettePreset::Pvm => {
+                decode_ntsc(&NtscSettings { hue: 0.3, saturation: 1.25, contrast: 1.05, gamma: 1.35 })
+            }
+        };
+        Palette { colors }
+    }
+
+    /// Load a .pal file. A 64 color file gets the emphasis colors computed the same way as
+    /// `util::NES_PALETTE_512`; a 512 color file is used as is.
+    pub fn from_pal(data: &[u8]) -> Result<Self, PaletteError> {
+        let mut colors = Box::new([[0; 3]; 512]);
+        match data.len() {
+            192 => {
+                let mut base = [[0; 3]; 64];
+                for (color, rgb) in base.iter_mut().zip(data.chunks_exact(3)) {
+                    color.copy_from_slice(rgb);
+                }
+                *colors = expand_emphasis(&base);
+            }
+            1536 => {
+                for (color, rgb) in colors.iter_mut().zip(data.chunks_exact(3)) {
+                    color.copy_from_slice(rgb);
+                }
+            }
+            size => return Err(PaletteError::InvalidSize(size)),
+        }
+        Ok(Palette { colors })
+    }
+
+    #[inline(always)]
+    pub fn rgb(&self, pixel: u16) -> [u8; 3] {
+        self.colors[pixel as usize & 0x1FF]
+    }
+
+    pub fn colors(&self) -> &[[u8; 3]; 512] {
+        &self.colors
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::preset(PalettePreset::Classic)
+    }
+}
+
+struct NtscSettings {
+    /// Added to the decoder's subcarrier phase, in 1/12 cycles
+    hue: f64,
+    saturation: f64,
+    contrast: f64,
+    gamma: f64,
+}
+
+/// Generate a palette by decoding one subcarrier cycle of each color's composite signal
+fn decode_ntsc(settings: &NtscSettings) -> Box<[[u8; 3]; 512]> {
+    let mut colors = Box::new([[0; 3]; 512]);
+    for (pixel, rgb) in colors.iter_mut().enumerate() {
+        let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+        for phase in 0..12 {
+            let value = signal(pixel as u16, phase);
+            let angle = std::f64::consts::PI * (phase as f64 + DECODER_HUE + settings.hue) / 6.0;
+            y += value;
+            i += value * angle.cos();
+            q += value * angle.sin();
+        }
+        let y = y / 12.0 * settings.contrast;
+        let i = i / 12.0 * settings.saturation;
+        let q = q / 12.0 * settings.saturation;
+        *rgb = yiq_to_rgb(y, i, q, settings.gamma);
+    }
+    colors
+}
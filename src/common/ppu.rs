@@ -1,23 +1,25 @@
+use super::debugger::{Access, WatchList};
+use super::nes::Region;
 use super::rom::*;
-use super::util::*;
+use super::state::*;
 
 struct VRam {
     name_table: Box<[u8; 0x1000]>, // 4 nametables (used for 4-screen; 2KB mirrored otherwise)
     background_palette: Box<[u8; 0x10]>,
     sprite_palette: Box<[u8; 0x10]>,
     sprite_memory: Box<[u8; 0x100]>,
-    mirroring: MirroringMode,
-    chr_ram: Option<Box<[u8; 0x2000]>>,
+    /// Checked on accesses through PPUDATA only
+    watches: WatchList,
 }
 
 impl VRam {
     /// Resolve a nametable address ($2000-$2FFF) to an index into name_table[0x1000]
     #[inline(always)]
-    fn resolve_nametable_addr(&self, addr: u16) -> usize {
+    fn resolve_nametable_addr(mirroring: MirroringMode, addr: u16) -> usize {
         let offset = (addr - 0x2000) as usize;
         let table = offset / 0x400; // 0-3
         let within = offset % 0x400;
-        let physical_table = match self.mirroring {
+        let physical_table = match mirroring {
             MirroringMode::Horizontal => match table {
                 0 | 1 => 0,
                 2 | 3 => 1,
@@ -38,23 +40,9 @@ impl VRam {
     fn read(&self, rom: &Rom, addr: u16) -> u8 {
         let addr = addr & 0x3FFF; // Mirror above $3FFF
         match addr {
-            0x0000..=0x1FFF => {
-                if let Some(ref ram) = self.chr_ram {
-                    ram[addr as usize]
-                } else {
-                    let chr = rom.get_chr();
-                    if chr.is_empty() {
-                        return 0;
-                    }
-                    if chr.len() == 0x1000 {
-                        chr[(addr as usize) & 0x0FFF]
-                    } else {
-                        chr[addr as usize % chr.len()]
-                    }
-                }
-            }
+            0x0000..=0x1FFF => rom.mapper.ppu_read(addr),
             0x2000..=0x2FFF => {
-                let idx = self.resolve_nametable_addr(addr);
+                let idx = Self::resolve_nametable_addr(rom.mapper.mirroring(), addr);
                 self.name_table[idx]
             }
             0x3000..=0x3EFF => self.read(rom, addr - 0x1000),
@@ -71,19 +59,15 @@ impl VRam {
         }
     }
 
-    fn write(&mut self, addr: u16, value: u8) {
+    fn write(&mut self, rom: &mut Rom, addr: u16, value: u8) {
         let addr = addr & 0x3FFF;
         match addr {
-            0x0000..=0x1FFF => {
-                if let Some(ref mut ram) = self.chr_ram {
-                    ram[addr as usize] = value;
-                }
-            }
+            0x0000..=0x1FFF => rom.mapper.ppu_write(addr, value),
             0x2000..=0x2FFF => {
-                let idx = self.resolve_nametable_addr(addr);
+                let idx = Self::resolve_nametable_addr(rom.mapper.mirroring(), addr);
                 self.name_table[idx] = value;
             }
-            0x3000..=0x3EFF => self.write(addr - 0x1000, value),
+            0x3000..=0x3EFF => self.write(rom, addr - 0x1000, value),
             0x3F00..=0x3FFF => {
                 let paddr = ((addr - 0x3F00) % 0x20) as usize;
                 match paddr {
@@ -96,6 +80,19 @@ impl VRam {
             _ => {}
         }
     }
+
+    /// Read on behalf of the CPU (PPUDATA), reporting it to the watchpoints
+    fn cpu_read(&mut self, rom: &Rom, addr: u16) -> u8 {
+        let value = self.read(rom, addr);
+        self.watches.check(addr, value, Access::Read);
+        value
+    }
+
+    /// Write on behalf of the CPU (PPUDATA), reporting it to the watchpoints
+    fn cpu_write(&mut self, rom: &mut Rom, addr: u16, value: u8) {
+        self.watches.check(addr, value, Access::Write);
+        self.write(rom, addr, value);
+    }
 }
 
 struct ControlRegister {
@@ -108,7 +105,6 @@ struct ControlRegister {
     main_screen: u8,
 }
 impl ControlRegister {
-    #[allow(dead_code)]
     fn read(&self) -> u8 {
         let mut value: u8 = 0;
         if self.nmi_on_v_blank {
@@ -154,16 +150,15 @@ struct ControlRegister2 {
     monochrome: bool,
 }
 impl ControlRegister2 {
-    #[allow(dead_code)]
     fn read(&self) -> u8 {
         let mut value: u8 = 0;
-        if self.color_emphasis_red {
+        if self.color_emphasis_blue {
             value |= 0b1000_0000;
         }
         if self.color_emphasis_green {
             value |= 0b0100_0000;
         }
-        if self.color_emphasis_blue {
+        if self.color_emphasis_red {
             value |= 0b0010_0000;
         }
         if self.show_sprite {
@@ -184,15 +179,31 @@ impl ControlRegister2 {
         value
     }
     fn write(&mut self, value: u8) {
-        self.color_emphasis_red = (value & 0b1000_0000) == 0b1000_0000;
+        self.color_emphasis_blue = (value & 0b1000_0000) == 0b1000_0000;
         self.color_emphasis_green = (value & 0b0100_0000) == 0b0100_0000;
-        self.color_emphasis_blue = (value & 0b0010_0000) == 0b0010_0000;
+        self.color_emphasis_red = (value & 0b0010_0000) == 0b0010_0000;
         self.show_sprite = (value & 0b0001_0000) == 0b0001_0000;
         self.show_bg = (value & 0b0000_1000) == 0b0000_1000;
         self.show_left_column_sprite = (value & 0b0000_0100) == 0b0000_0100;
         self.show_left_column_bg = (value & 0b0000_0010) == 0b0000_0010;
         self.monochrome = (value & 0b0000_0001) == 0b0000_0001;
     }
+    /// Frame buffer pixel for a palette entry: greyscale applied, emphasis bits in bits 6-8
+    #[inline(always)]
+    fn pixel(&self, color: u8) -> u16 {
+        let color = if self.monochrome { color & 0x30 } else { color & 0x3F };
+        let mut emphasis = 0;
+        if self.color_emphasis_red {
+            emphasis |= 0b001;
+        }
+        if self.color_emphasis_green {
+            emphasis |= 0b010;
+        }
+        if self.color_emphasis_blue {
+            emphasis |= 0b100;
+        }
+        color as u16 | (emphasis << 6)
+    }
 }
 
 struct StatusRegister {
@@ -226,35 +237,106 @@ struct Bus {
     v_ram: VRam,
 }
 
-enum State {
-    Idle,
-    Writing,
-}
-
 pub struct Ppu {
     bus: Bus,
     registers: Registers,
     sprite_addr: u8,
-    scroll_horizontal: u8,
-    scroll_vertical: u8,
-    v_ram_addr_h: u8,
-    v_ram_addr_l: u8,
-    state: State,
+    /// Current VRAM address (v): fine Y, nametable, coarse Y and coarse X as `yyy NN YYYYY XXXXX`
+    v: u16,
+    /// Temporary VRAM address (t), copied into v at the start of each line and frame
+    t: u16,
+    /// Fine X scroll (x)
+    fine_x: u8,
+    /// First/second write toggle shared by $2005 and $2006 (w)
+    w: bool,
     current_x: u16,
     current_y: u16,
-    frame: Box<[u8; 256 * 240]>,
+    /// 9-bit pixels: palette color in bits 0-5 and red/green/blue emphasis in bits 6-8
+    frame: Box<[u16; 256 * 240]>,
     read_buffer: u8,
+    bg: BackgroundPipeline,
+    /// Sprites found by evaluation on the current line, copied from OAM
+    secondary_oam: [u8; 32],
+    /// OAM indices of the sprites found by evaluation (more than 8 only without the sprite limit)
+    line_sprites: [u8; 64],
+    line_sprite_count: u8,
+    /// Sprite evaluation progress: OAM entry (n), byte within the entry (m) and bytes left to copy
+    eval_n: u8,
+    eval_m: u8,
+    eval_copy: u8,
+    /// Sprite 0 was found by evaluation and is in the first slot
+    sprite_0_on_line: bool,
+    /// Draw every sprite on a line instead of the first 8 (removes flicker; not hardware behavior)
+    no_sprite_limit: bool,
+    /// Sprites being drawn on the current line
+    sprites: [SpriteSlot; 64],
+    sprite_count: u8,
+    /// Set by reset; $2000/$2001/$2005/$2006 writes are ignored until the pre-render line
+    reset_flag: bool,
+    region: Region,
+    /// Value left on the data bus between the CPU and the PPU registers (returned by open bus reads)
+    io_latch: u8,
+    /// Frames until each bit of io_latch decays to 0 unless refreshed
+    io_latch_decay: [u8; 8],
+    /// The pre-render line is one dot shorter on odd frames while rendering (NTSC only)
+    odd_frame: bool,
+    /// $2002 was read just before the vblank flag would be set, so it isn't set this frame
+    v_blank_suppressed: bool,
+    /// PPU clocks the NMI condition (vblank flag and NMI enable) has held
+    nmi_held_clocks: u8,
+}
+
+/// Roughly 600ms, about how long the I/O latch holds a value
+const IO_LATCH_DECAY_FRAMES: u8 = 36;
+
+/// PPU clocks the NMI condition must hold before the CPU sees it. A $2002 read in that window
+/// clears the vblank flag and suppresses the NMI.
+const NMI_DELAY: u8 = 3;
+
+/// Background tile fetch latches and the shift registers they are loaded into every 8 dots
+#[derive(Default)]
+struct BackgroundPipeline {
+    name: u8,
+    attribute: u8,
+    pattern_l: u8,
+    pattern_h: u8,
+    shift_pattern_l: u16,
+    shift_pattern_h: u16,
+    shift_attribute_l: u16,
+    shift_attribute_h: u16,
 }
 
-struct Sprite {
-    pattern: u8,
-    background: bool,
-    pallet: u8,
-    index: u8,
+impl BackgroundPipeline {
+    fn shift(&mut self) {
+        self.shift_pattern_l <<= 1;
+        self.shift_pattern_h <<= 1;
+        self.shift_attribute_l <<= 1;
+        self.shift_attribute_h <<= 1;
+    }
+
+    /// Load the latched tile into the low 8 bits of the shift registers
+    fn reload(&mut self) {
+        self.shift_pattern_l = (self.shift_pattern_l & 0xFF00) | self.pattern_l as u16;
+        self.shift_pattern_h = (self.shift_pattern_h & 0xFF00) | self.pattern_h as u16;
+        let expand = |bit: u8| if self.attribute & bit != 0 { 0xFF } else { 0x00 };
+        self.shift_attribute_l = (self.shift_attribute_l & 0xFF00) | expand(0b01);
+        self.shift_attribute_h = (self.shift_attribute_h & 0xFF00) | expand(0b10);
+    }
+}
+
+/// A sprite fetched during dots 257-320 for drawing on the next line.
+/// The pattern bytes are already flipped horizontally if needed.
+#[derive(Clone, Copy, Default)]
+struct SpriteSlot {
+    x: u8,
+    attribute: u8,
+    pattern_l: u8,
+    pattern_h: u8,
+    is_sprite_0: bool,
 }
 
 impl Ppu {
-    pub fn new(mirroring: MirroringMode, has_chr_ram: bool) -> Self {
+    pub fn new() -> Self {
         Ppu {
             bus: Bus {
                 v_ram: VRam {
@@ -262,8 +344,7 @@ impl Ppu {
                     background_palette: Box::new([0; 0x10]),
                     sprite_palette: Box::new([0; 0x10]),
                     sprite_memory: Box::new([0; 0x100]),
-                    mirroring,
-                    chr_ram: if has_chr_ram { Some(Box::new([0; 0x2000])) } else { None },
+                    watches: WatchList::default(),
                 },
             },
             registers: Registers {
@@ -289,290 +370,776 @@ impl Ppu {
                 status_register: StatusRegister { v_blank: false, sprite_0_hit: false, sprite_overflow: false },
             },
             sprite_addr: 0,
-            scroll_horizontal: 0,
-            scroll_vertical: 0,
-            v_ram_addr_h: 0,
-            v_ram_addr_l: 0,
-            state: State::Idle,
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            w: false,
             current_x: 0,
             current_y: 0,
             frame: Box::new([0; 256 * 240]),
             read_buffer: 0,
+            bg: Default::default(),
+            secondary_oam: [0xFF; 32],
+            line_sprites: [0; 64],
+            line_sprite_count: 0,
+            eval_n: 0,
+            eval_m: 0,
+            eval_copy: 0,
+            sprite_0_on_line: false,
+            no_sprite_limit: false,
+            sprites: [Default::default(); 64],
+            sprite_count: 0,
+            reset_flag: false,
+            region: Region::Ntsc,
+            io_latch: 0,
+            io_latch_decay: [0; 8],
+            odd_frame: false,
+            v_blank_suppressed: false,
+            nmi_held_clocks: 0,
         }
     }
 
-    #[inline(always)]
-    fn get_pattern(&self, rom: &Rom, pattern: u8, pixel_in_tile_x: u16, pixel_in_tile_y: u16, is_sprite: bool) -> u8 {
-        let pattern_addr_l = if if is_sprite {
-            self.registers.control_register.sprite_chr_table
-        } else {
-            self.registers.control_register.bg_pattern_table
-        } {
-            0x1000
-        } else {
-            0x0000
-        } + ((pattern as u16) << 4)
-            + pixel_in_tile_y;
-        let pattern_addr_h = pattern_addr_l + 0x08;
-        let pattern_l = self.bus.v_ram.read(rom, pattern_addr_l);
-        let pattern_h = self.bus.v_ram.read(rom, pattern_addr_h);
-        ((pattern_l >> (7 - pixel_in_tile_x)) & 0b01) | (((pattern_h >> (7 - pixel_in_tile_x)) << 1) & 0b10)
-    }
-
-    pub fn clock(&mut self, rom: &Rom) -> (bool, bool) {
-        let mut nmi = false;
+    pub fn clock(&mut self, rom: &mut Rom) -> (bool, bool) {
+        let mut nmi_condition = false;
+        let pre_render_line = self.pre_render_line();
+        let v_blank_line = self.region.vblank_line();
         match self.current_y {
             0..=239 => {
-                if self.current_x <= 255 {
-                    let mut scrolled_x = self.current_x + self.scroll_horizontal as u16;
-                    let screen_overwrap_x = if scrolled_x > 255 {
-                        scrolled_x -= 256;
-                        true
-                    } else {
-                        false
-                    };
-                    let mut scrolled_y = self.current_y + self.scroll_vertical as u16;
-                    let screen_overwrap_y = if scrolled_y > 239 {
-                        scrolled_y -= 240;
-                        true
-                    } else {
-                        false
-                    };
-                    let mut main_screen = self.registers.control_register.main_screen;
-                    if screen_overwrap_x {
-                        if main_screen % 2 == 0 {
-                            main_screen += 1;
-                        } else {
-                            main_screen -= 1;
-                        }
-                    }
-                    if screen_overwrap_y {
-                        if main_screen / 2 == 0 {
-                            main_screen += 2;
-                        } else {
-                            main_screen -= 2;
-                        }
-                    }
-
-                    let tile_x = scrolled_x / 8;
-                    let tile_y = scrolled_y / 8;
-                    let pixel_in_tile_x = scrolled_x % 8;
-                    let pixel_in_tile_y = scrolled_y % 8;
-
-                    //スプライト
-                    let mut sprite_index: u8 = 0;
-                    let sprite = if self.registers.control_register2.show_sprite
-                        && (self.current_x >= 8 || self.registers.control_register2.show_left_column_sprite)
-                    {
-                        loop {
-                            let addr = (sprite_index as usize) * 4;
-                            let sprite_y = self.bus.v_ram.sprite_memory[addr].saturating_add(1);
-                            if (sprite_y as u16 <= self.current_y) && (sprite_y as u16 + 7 >= self.current_y) {
-                                let sprite_x = self.bus.v_ram.sprite_memory[addr + 3];
-                                if (sprite_x as u16 <= self.current_x) && (sprite_x as u16 + 7 >= self.current_x) {
-                                    let sprite_tile = self.bus.v_ram.sprite_memory[addr + 1];
-                                    let sprite_attr = self.bus.v_ram.sprite_memory[addr + 2];
-                                    //垂直反転
-                                    let mut pixel_in_tile_y = (self.current_y - (sprite_y as u16)) % 8;
-                                    if (sprite_attr & 0b1000_0000) == 0b1000_0000 {
-                                        pixel_in_tile_y = 7 - pixel_in_tile_y;
-                                    }
-                                    //水平反転
-                                    let mut pixel_in_tile_x = (self.current_x - (sprite_x as u16)) % 8;
-                                    if (sprite_attr & 0b0100_0000) == 0b0100_0000 {
-                                        pixel_in_tile_x = 7 - pixel_in_tile_x;
-                                    }
-
-                                    let pattern =
-                                        self.get_pattern(rom, sprite_tile, pixel_in_tile_x, pixel_in_tile_y, true);
-                                    if pattern != 0 {
-                                        let background = (sprite_attr & 0b0010_0000) == 0b0010_0000;
-                                        let pallet = sprite_attr & 0b11;
-                                        break Some(Sprite { pattern, background, pallet, index: sprite_index });
-                                    }
-                                }
-                            }
-                            sprite_index += 1;
-                            if sprite_index == 64 {
-                                break None;
-                            }
-                        }
-                    } else {
-                        None
-                    };
-
-                    //BG
-                    let mut pattern = if self.registers.control_register2.show_bg
-                        && (self.current_x >= 8 || self.registers.control_register2.show_left_column_bg)
-                    {
-                        let name_base_addr = 0x2000 + (main_screen as u16) * 0x400;
-                        let tile_y_addr = tile_y * 0x20;
-                        let name_addr = name_base_addr + tile_y_addr + tile_x;
-                        let name = self.bus.v_ram.read(rom, name_addr);
-                        self.get_pattern(rom, name, pixel_in_tile_x, pixel_in_tile_y, false)
-                    } else {
-                        0
-                    };
-
-                    if (self.current_x != 255)
-                        && sprite.is_some()
-                        && (sprite.as_ref().unwrap().index == 0)
-                        && (pattern != 0)
-                        && (sprite.as_ref().unwrap().pattern != 0)
-                    {
-                        //sprite 0 hit
-                        self.registers.status_register.sprite_0_hit = true;
-                    }
-
-                    let pallet_addr = if sprite.is_none() || (pattern != 0 && sprite.as_ref().unwrap().background) {
-                        //BGを描画する
-                        let attribute_block_x = tile_x / 4;
-                        let attribute_block_y_addr = tile_y / 4 * 8;
-                        let attribute_base_addr = 0x23C0 + (main_screen as u16) * 0x400;
-                        let attribute_addr = attribute_base_addr + attribute_block_y_addr + attribute_block_x;
-                        let attribute = self.bus.v_ram.read(rom, attribute_addr);
-                        let block = ((tile_x / 2) % 2) + (((tile_y / 2) % 2) * 2);
-                        0x3F00 + ((((attribute >> (block * 2)) & 0b11) as u16) << 2)
-                    } else {
-                        //スプライトを描画する
-                        pattern = sprite.as_ref().unwrap().pattern;
-                        0x3F10 + ((sprite.as_ref().unwrap().pallet as u16) << 2)
-                    };
-
-                    let colors = [
-                        self.bus.v_ram.read(rom, 0x3F00), // Universal BG color (always $3F00 when pattern==0)
-                        self.bus.v_ram.read(rom, pallet_addr + 1),
-                        self.bus.v_ram.read(rom, pallet_addr + 2),
-                        self.bus.v_ram.read(rom, pallet_addr + 3),
-                    ];
-                    let pixel = colors[pattern as usize];
-
-                    self.frame[(self.current_y * 256 + self.current_x) as usize] = pixel;
+                if self.is_rendering() {
+                    self.fetch_background(rom);
+                    self.evaluate_sprites();
+                    self.fetch_sprites(rom);
+                    self.update_v_ram_addr();
                 }
-                // 256..=340 => {} //Hblank
-            }
-            240 => {} //post-render
-            241 => {
-                //Vblank
-                if self.current_x == 1 {
-                    self.registers.status_register.v_blank = true;
+                if (1..=256).contains(&self.current_x) {
+                    self.render_pixel(rom);
                 }
-                nmi = self.registers.control_register.nmi_on_v_blank && self.registers.status_register.v_blank;
-            }
-            242..=260 => {
-                //Vblank
-                nmi = self.registers.control_register.nmi_on_v_blank && self.registers.status_register.v_blank;
+                // 257..=340 => {} //Hblank
             }
-            261 => {
+            y if y == pre_render_line => {
                 //pre-render scanline
                 if self.current_x == 1 {
                     self.registers.status_register.sprite_0_hit = false;
+                    self.registers.status_register.sprite_overflow = false;
                     self.registers.status_register.v_blank = false;
+                    self.reset_flag = false;
+                }
+                if self.is_rendering() {
+                    self.fetch_background(rom);
+                    //スプライト評価は行わないので1ライン目にはスプライトが表示されない
+                    if self.current_x == 1 {
+                        self.line_sprite_count = 0;
+                        self.sprite_0_on_line = false;
+                    }
+                    self.fetch_sprites(rom);
+                    self.update_v_ram_addr();
+                    if (280..=304).contains(&self.current_x) {
+                        //垂直方向のスクロールをtから戻す
+                        self.v = (self.v & 0x041F) | (self.t & 0x7BE0);
+                    }
                 }
             }
-            _ => panic!(),
+            y if y >= v_blank_line => {
+                //Vblank
+                if y == v_blank_line && self.current_x == 1 {
+                    self.registers.status_register.v_blank = !self.v_blank_suppressed;
+                    self.v_blank_suppressed = false;
+                }
+                nmi_condition =
+                    self.registers.control_register.nmi_on_v_blank && self.registers.status_register.v_blank;
+            }
+            _ => {} //post-render
+        }
+        if self.is_rendering() && (257..=320).contains(&self.current_x) && self.is_render_line() {
+            self.sprite_addr = 0;
         }
+        self.nmi_held_clocks = if nmi_condition {
+            self.nmi_held_clocks.saturating_add(1)
+        } else {
+            0
+        };
+
         self.current_x += 1;
-        if self.current_x > 340 {
+        //奇数フレームはレンダリング中ならプリレンダーラインの最後のドットを飛ばす
+        let skip_dot = self.current_x == 340
+            && self.current_y == pre_render_line
+            && self.odd_frame
+            && self.is_rendering()
+            && self.region == Region::Ntsc;
+        if self.current_x > 340 || skip_dot {
             self.current_x = 0;
             self.current_y += 1;
-            self.current_y %= 262;
+            self.current_y %= self.region.scanlines();
+            if self.current_y == 0 {
+                self.end_frame();
+            }
+        }
+        (
+            self.current_x == 0 && self.current_y == 0,
+            self.nmi_held_clocks >= NMI_DELAY,
+        )
+    }
+
+    fn end_frame(&mut self) {
+        self.odd_frame = !self.odd_frame;
+        for bit in 0..8 {
+            if self.io_latch_decay[bit] > 0 {
+                self.io_latch_decay[bit] -= 1;
+                if self.io_latch_decay[bit] == 0 {
+                    self.io_latch &= !(1 << bit);
+                }
+            }
+        }
+    }
+
+    /// Drive the bits of `value` selected by `mask` onto the I/O latch
+    fn refresh_io_latch(&mut self, value: u8, mask: u8) {
+        self.io_latch = (self.io_latch & !mask) | (value & mask);
+        for bit in 0..8 {
+            if mask & (1 << bit) != 0 {
+                self.io_latch_decay[bit] = IO_LATCH_DECAY_FRAMES;
+            }
+        }
+    }
+
+    /// Read from the PPU bus as part of rendering, letting the mapper see the address
+    fn fetch(&self, rom: &mut Rom, addr: u16) -> u8 {
+        rom.mapper.notify_ppu_addr(addr);
+        self.bus.v_ram.read(rom, addr)
+    }
+
+    /// Nametable, attribute and pattern fetches for the next tile every 8 dots (dots 1-256 and the
+    /// two tiles of the next line in 321-336), plus shifting and reloading the shift registers.
+    fn fetch_background(&mut self, rom: &mut Rom) {
+        let dot = self.current_x;
+        if matches!(dot, 2..=257 | 322..=337) {
+            self.bg.shift();
+        }
+        if matches!(dot, 9..=257 | 329..=337) && dot % 8 == 1 {
+            self.bg.reload();
+        }
+        match dot {
+            1..=256 | 321..=336 => match (dot - 1) % 8 {
+                0 => self.bg.name = self.fetch(rom, 0x2000 | (self.v & 0x0FFF)),
+                2 => {
+                    let addr = 0x23C0 | (self.v & 0x0C00) | ((self.v >> 4) & 0x38) | ((self.v >> 2) & 0x07);
+                    //coarse Y, coarse X の bit1 で 2x2 タイルのブロックを選ぶ
+                    let shift = ((self.v >> 4) & 0b100) | (self.v & 0b10);
+                    self.bg.attribute = (self.fetch(rom, addr) >> shift) & 0b11;
+                }
+                4 => self.bg.pattern_l = self.fetch(rom, self.bg_pattern_addr()),
+                6 => self.bg.pattern_h = self.fetch(rom, self.bg_pattern_addr() + 8),
+                _ => {}
+            },
+            //使われない名前テーブルの読み込み
+            337 | 339 => {
+                self.fetch(rom, 0x2000 | (self.v & 0x0FFF));
+            }
+            _ => {}
+        }
+    }
+
+    fn bg_pattern_addr(&self) -> u16 {
+        let base = if self.registers.control_register.bg_pattern_table {
+            0x1000
+        } else {
+            0x0000
+        };
+        base + ((self.bg.name as u16) << 4) + (self.v >> 12)
+    }
+
+    /// Sprite evaluation for the next line: secondary OAM is cleared during dots 1-64, then OAM is
+    /// scanned from dot 65 taking 2 dots per entry, plus 6 more to copy an entry that is in range.
+    fn evaluate_sprites(&mut self) {
+        let dot = self.current_x;
+        match dot {
+            1..=64 if dot.is_multiple_of(2) => self.secondary_oam[(dot / 2 - 1) as usize] = 0xFF,
+            65 => {
+                self.line_sprite_count = 0;
+                self.eval_n = 0;
+                self.eval_m = 0;
+                self.eval_copy = 0;
+                self.sprite_0_on_line = false;
+            }
+            66..=256 if dot.is_multiple_of(2) => self.evaluation_step(),
+            _ => {}
+        }
+        if dot == 256 && self.no_sprite_limit && self.line_sprite_count == 8 {
+            //9個目以降をすべて集める
+            for index in self.line_sprites[7] + 1..64 {
+                if self.sprite_in_range(self.bus.v_ram.sprite_memory[index as usize * 4]) {
+                    self.line_sprites[self.line_sprite_count as usize] = index;
+                    self.line_sprite_count += 1;
+                }
+            }
+        }
+    }
+
+    fn evaluation_step(&mut self) {
+        if self.eval_n >= 64 {
+            return;
+        }
+        let n = self.eval_n as usize;
+        let count = self.line_sprite_count as usize;
+        if self.eval_copy > 0 {
+            //タイル番号, 属性, X座標をコピー
+            let offset = (4 - self.eval_copy) as usize;
+            self.secondary_oam[count * 4 + offset] = self.bus.v_ram.sprite_memory[n * 4 + offset];
+            self.eval_copy -= 1;
+            if self.eval_copy == 0 {
+                self.line_sprites[count] = n as u8;
+                self.line_sprite_count += 1;
+                self.eval_n += 1;
+            }
+        } else if count < 8 {
+            let y = self.bus.v_ram.sprite_memory[n * 4];
+            self.secondary_oam[count * 4] = y;
+            if self.sprite_in_range(y) {
+                self.eval_copy = 3;
+                if n == 0 {
+                    self.sprite_0_on_line = true;
+                }
+            } else {
+                self.eval_n += 1;
+            }
+        } else {
+            //オーバーフローの判定
+            //ハードウェアのバグでnと一緒にmもインクリメントされるため, Y座標以外のバイトを見てしまう
+            let y = self.bus.v_ram.sprite_memory[n * 4 + self.eval_m as usize];
+            if self.sprite_in_range(y) {
+                self.registers.status_register.sprite_overflow = true;
+                self.eval_n = 64;
+            } else {
+                self.eval_n += 1;
+                self.eval_m = (self.eval_m + 1) & 0b11;
+            }
         }
-        (self.current_x == 0 && self.current_y == 0, nmi)
     }
 
-    pub fn get_screen(&self) -> &[u8; 256 * 240] {
+    #[inline(always)]
+    fn sprite_in_range(&self, y: u8) -> bool {
+        self.current_y.wrapping_sub(y as u16) < self.sprite_height()
+    }
+
+    #[inline(always)]
+    fn sprite_height(&self) -> u16 {
+        if self.registers.control_register.sprite_size {
+            16
+        } else {
+            8
+        }
+    }
+
+    /// Turn the 8 sprites per line limit off (or back on). The overflow flag still behaves
+    /// like hardware.
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.no_sprite_limit = !enabled;
+    }
+    pub fn sprite_limit(&self) -> bool {
+        !self.no_sprite_limit
+    }
+
+    /// Sprite pattern fetches in dots 257-320, 8 dots per sprite. Unused slots fetch tile $FF.
+    fn fetch_sprites(&mut self, rom: &mut Rom) {
+        let dot = self.current_x;
+        if !(257..=320).contains(&dot) {
+            return;
+        }
+        if dot == 257 {
+            self.sprite_count = self.line_sprite_count;
+        }
+        let slot = ((dot - 257) / 8) as usize;
+        match (dot - 257) % 8 {
+            0 | 2 => {
+                self.fetch(rom, 0x2000 | (self.v & 0x0FFF));
+            }
+            4 => {
+                let addr = self.sprite_pattern_addr(slot);
+                let pattern_l = self.fetch(rom, addr);
+                if slot < self.sprite_count as usize {
+                    self.load_sprite_slot(slot, pattern_l);
+                }
+            }
+            6 => {
+                let addr = self.sprite_pattern_addr(slot) + 8;
+                let pattern_h = self.fetch(rom, addr);
+                if slot < self.sprite_count as usize {
+                    self.sprites[slot].pattern_h = self.flip_sprite_pattern(slot, pattern_h);
+                }
+            }
+            _ => {}
+        }
+        if dot == 320 {
+            //8個を超える分はまとめて読み込む
+            for slot in 8..self.sprite_count as usize {
+                let addr = self.sprite_pattern_addr(slot);
+                let pattern_l = self.fetch(rom, addr);
+                self.load_sprite_slot(slot, pattern_l);
+                let pattern_h = self.fetch(rom, addr + 8);
+                self.sprites[slot].pattern_h = self.flip_sprite_pattern(slot, pattern_h);
+            }
+        }
+    }
+
+    fn sprite_pattern_addr(&self, slot: usize) -> u16 {
+        //空きスロットはタイル$FFを読む
+        let (tile, mut row, flip) = if slot < self.sprite_count as usize {
+            let oam = self.sprite_entry(slot);
+            let row = self.current_y.wrapping_sub(oam[0] as u16) & (self.sprite_height() - 1);
+            (oam[1], row, oam[2] & 0b1000_0000 != 0)
+        } else {
+            (0xFF, 0, false)
+        };
+        //垂直反転 (8x16では2タイルにまたがって反転する)
+        if flip {
+            row = self.sprite_height() - 1 - row;
+        }
+        let (base, tile) = if self.registers.control_register.sprite_size {
+            //8x16: タイル番号のbit0でパターンテーブルを選び, 下半分は次のタイル
+            let base = if tile & 1 != 0 { 0x1000 } else { 0x0000 };
+            (base, (tile & 0xFE) + (row / 8) as u8)
+        } else {
+            let base = if self.registers.control_register.sprite_chr_table {
+                0x1000
+            } else {
+                0x0000
+            };
+            (base, tile)
+        };
+        base + ((tile as u16) << 4) + (row & 7)
+    }
+
+    /// Y, tile, attribute and X of a sprite found by evaluation. The first 8 come from
+    /// secondary OAM, the ones beyond the sprite limit straight from OAM.
+    fn sprite_entry(&self, slot: usize) -> [u8; 4] {
+        let entry = if slot < 8 {
+            &self.secondary_oam[slot * 4..][..4]
+        } else {
+            &self.bus.v_ram.sprite_memory[self.line_sprites[slot] as usize * 4..][..4]
+        };
+        entry.try_into().unwrap()
+    }
+
+    fn load_sprite_slot(&mut self, slot: usize, pattern_l: u8) {
+        let oam = self.sprite_entry(slot);
+        let is_sprite_0 = slot == 0 && self.sprite_0_on_line;
+        self.sprites[slot] = SpriteSlot { x: oam[3], attribute: oam[2], pattern_l: 0, pattern_h: 0, is_sprite_0 };
+        self.sprites[slot].pattern_l = self.flip_sprite_pattern(slot, pattern_l);
+    }
+
+    fn flip_sprite_pattern(&self, slot: usize, pattern: u8) -> u8 {
+        //水平反転
+        if self.sprites[slot].attribute & 0b0100_0000 != 0 {
+            pattern.reverse_bits()
+        } else {
+            pattern
+        }
+    }
+
+    fn render_pixel(&mut self, rom: &Rom) {
+        let x = self.current_x - 1;
+        let mask = &self.registers.control_register2;
+
+        //BG
+        let (bg_pattern, bg_palette) = if mask.show_bg && (x >= 8 || mask.show_left_column_bg) {
+            let bit = 15 - self.fine_x;
+            let pattern = ((self.bg.shift_pattern_l >> bit) & 1) | (((self.bg.shift_pattern_h >> bit) & 1) << 1);
+            let palette = ((self.bg.shift_attribute_l >> bit) & 1) | (((self.bg.shift_attribute_h >> bit) & 1) << 1);
+            (pattern as u8, palette as u8)
+        } else {
+            (0, 0)
+        };
+
+        //スプライト (OAMの順に優先)
+        let sprite = if mask.show_sprite && (x >= 8 || mask.show_left_column_sprite) {
+            self.sprites[..self.sprite_count as usize].iter().find_map(|sprite| {
+                let offset = x.wrapping_sub(sprite.x as u16);
+                if offset >= 8 {
+                    return None;
+                }
+                let bit = 7 - offset;
+                let pattern = ((sprite.pattern_l >> bit) & 1) | (((sprite.pattern_h >> bit) & 1) << 1);
+                (pattern != 0).then_some((pattern, sprite))
+            })
+        } else {
+            None
+        };
+
+        if let Some((_, sprite)) = sprite {
+            if sprite.is_sprite_0 && bg_pattern != 0 && x != 255 {
+                //sprite 0 hit
+                self.registers.status_register.sprite_0_hit = true;
+            }
+        }
+
+        let pallet_addr = match sprite {
+            Some((pattern, sprite)) if bg_pattern == 0 || sprite.attribute & 0b0010_0000 == 0 => {
+                //スプライトを描画する
+                0x3F10 + (((sprite.attribute & 0b11) as u16) << 2) + pattern as u16
+            }
+            //BGを描画する (pattern 0 は共通の背景色 $3F00)
+            _ if bg_pattern != 0 => 0x3F00 + ((bg_palette as u16) << 2) + bg_pattern as u16,
+            _ => 0x3F00,
+        };
+        let color = self.bus.v_ram.read(rom, pallet_addr);
+        self.frame[(self.current_y * 256 + x) as usize] = self.registers.control_register2.pixel(color);
+    }
+
+    /// Watchpoints on PPUDATA accesses
+    pub fn watches_mut(&mut self) -> &mut WatchList {
+        &mut self.bus.v_ram.watches
+    }
+
+    /// Current (scanline, dot)
+    pub fn position(&self) -> (u16, u16) {
+        (self.current_y, self.current_x)
+    }
+
+    pub fn get_screen(&self) -> &[u16; 256 * 240] {
         &self.frame
     }
 
-    pub fn read(&mut self, rom: &Rom, addr: u8) -> u8 {
+    /// Increment and copy timing of v shared by the visible and pre-render lines
+    fn update_v_ram_addr(&mut self) {
+        let dot = self.current_x;
+        if (dot != 0 && dot <= 256 || (328..=336).contains(&dot)) && dot.is_multiple_of(8) {
+            self.increment_coarse_x();
+        }
+        if dot == 256 {
+            self.increment_y();
+        } else if dot == 257 {
+            //水平方向のスクロールをtから戻す
+            self.v = (self.v & 0x7BE0) | (self.t & 0x041F);
+        }
+    }
+
+    fn increment_coarse_x(&mut self) {
+        if self.v & 0x1F == 31 {
+            self.v &= !0x1F;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let coarse_y = (self.v >> 5) & 0x1F;
+        let coarse_y = match coarse_y {
+            29 => {
+                self.v ^= 0x0800;
+                0
+            }
+            //属性テーブル上の行からは名前テーブルを切り替えずに折り返す
+            31 => 0,
+            _ => coarse_y + 1,
+        };
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    /// Advance v after a $2007 access. While rendering the PPU applies its own X and Y
+    /// increments instead of adding 1 or 32.
+    fn increment_v_ram_addr(&mut self) {
+        if self.is_rendering() && self.is_render_line() {
+            self.increment_coarse_x();
+            self.increment_y();
+        } else {
+            let step = if self.registers.control_register.v_ram_io_addressing {
+                32
+            } else {
+                1
+            };
+            self.v = self.v.wrapping_add(step) & 0x7FFF;
+        }
+    }
+
+    fn pre_render_line(&self) -> u16 {
+        self.region.scanlines() - 1
+    }
+
+    /// Visible lines and the pre-render line, where the PPU fetches while rendering is enabled
+    fn is_render_line(&self) -> bool {
+        self.current_y < 240 || self.current_y == self.pre_render_line()
+    }
+
+    /// Switch the frame layout (number of lines and vblank start) to that of `region`
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.current_y %= region.scanlines();
+    }
+
+    #[inline(always)]
+    fn is_rendering(&self) -> bool {
+        self.registers.control_register2.show_bg || self.registers.control_register2.show_sprite
+    }
+
+    /// What reading a register would return, without the side effects of the read
+    pub fn peek(&self, rom: &Rom, addr: u8) -> u8 {
+        match addr {
+            0x02 => self.registers.status_register.read() | (self.io_latch & 0x1F),
+            //レンダリング中はセカンダリOAMの初期化で$FFが読める
+            0x04 if self.is_rendering() && self.is_render_line() && (1..=64).contains(&self.current_x) => 0xFF,
+            0x04 => self.bus.v_ram.sprite_memory[self.sprite_addr as usize],
+            0x07 if self.v & 0x3FFF >= 0x3F00 => {
+                //パレットは6bitで、上位2bitはオープンバス
+                let color = self.peek_v_ram(rom, self.v);
+                let color = if self.registers.control_register2.monochrome {
+                    color & 0x30
+                } else {
+                    color
+                };
+                color | (self.io_latch & 0xC0)
+            }
+            0x07 => self.read_buffer,
+            _ => self.io_latch,
+        }
+    }
+
+    /// Read a register. Bits a register doesn't drive come from the I/O latch (open bus).
+    pub fn read(&mut self, rom: &mut Rom, addr: u8) -> u8 {
+        let result = self.peek(rom, addr);
         match addr {
             0x02 => {
-                self.state = State::Idle;
-                let result = self.registers.status_register.read();
+                self.w = false;
+                if self.current_y == self.region.vblank_line() && self.current_x == 1 {
+                    //フラグが立つ直前に読むとこのフレームはフラグもNMIも発生しない
+                    self.v_blank_suppressed = true;
+                }
                 self.registers.status_register.v_blank = false;
-                result
-            }
-            0x04 => {
-                let result = self.bus.v_ram.sprite_memory[self.sprite_addr as usize];
-                // self.sprite_addr = self.sprite_addr.wrapping_add(1); //TODO?
-                result
+                self.refresh_io_latch(result, 0xE0);
             }
+            0x04 => self.refresh_io_latch(result, 0xFF),
             0x07 => {
-                let mut result = self.read_buffer;
-                let mut addr = get_addr(self.v_ram_addr_h, self.v_ram_addr_l);
-                self.read_buffer = self.bus.v_ram.read(rom, addr);
-                if (0x3F00..=0x3FFF).contains(&addr) {
-                    result = self.read_buffer;
+                let addr = self.v & 0x3FFF;
+                rom.mapper.notify_ppu_addr(addr);
+                self.read_buffer = self.bus.v_ram.cpu_read(rom, addr);
+                if addr >= 0x3F00 {
+                    //パレットの裏にあるネームテーブルがバッファに入る
                     self.read_buffer = self.bus.v_ram.read(rom, addr - 0x1000);
-                }
-                addr = addr.wrapping_add(if self.registers.control_register.v_ram_io_addressing {
-                    32
+                    self.refresh_io_latch(result, 0x3F);
                 } else {
-                    1
-                });
-                self.v_ram_addr_h = ((addr & 0xFF00) >> 8) as u8;
-                self.v_ram_addr_l = addr as u8;
-                result
+                    self.refresh_io_latch(result, 0xFF);
+                }
+                self.increment_v_ram_addr();
             }
-            _ => 0,
+            _ => {}
+        }
+        result
+    }
+
+    /// Read the PPU address space ($0000-$3FFF, mirrored above) without side effects.
+    /// Palette entries are 6 bits.
+    pub fn peek_v_ram(&self, rom: &Rom, addr: u16) -> u8 {
+        let addr = addr & 0x3FFF;
+        let value = self.bus.v_ram.read(rom, addr);
+        if addr >= 0x3F00 {
+            value & 0x3F
+        } else {
+            value
         }
     }
 
-    pub fn write(&mut self, addr: u8, value: u8) {
+    pub fn oam(&self) -> &[u8] {
+        &self.bus.v_ram.sprite_memory[..]
+    }
+
+    pub fn write(&mut self, rom: &mut Rom, addr: u8, value: u8) {
+        self.refresh_io_latch(value, 0xFF);
+        if self.reset_flag && matches!(addr, 0x00 | 0x01 | 0x05 | 0x06) {
+            return;
+        }
         match addr {
-            0x00 => self.registers.control_register.write(value),
+            0x00 => {
+                self.registers.control_register.write(value);
+                self.t = (self.t & !0x0C00) | (((value & 0b11) as u16) << 10);
+            }
             0x01 => self.registers.control_register2.write(value),
             0x03 => self.sprite_addr = value,
             0x04 => {
-                self.bus.v_ram.sprite_memory[self.sprite_addr as usize] = value;
-                self.sprite_addr = self.sprite_addr.wrapping_add(1); //TODO?
-            }
-            0x05 => match self.state {
-                State::Idle => {
-                    self.scroll_horizontal = value;
-                    self.state = State::Writing;
-                }
-                State::Writing => {
-                    self.scroll_vertical = value;
-                    self.state = State::Idle;
+                if self.is_rendering() && self.is_render_line() {
+                    //レンダリング中は書き込まれず、アドレスの上位6bitだけが進む
+                    self.sprite_addr = self.sprite_addr.wrapping_add(4);
+                } else {
+                    self.write_oam(value);
                 }
-            },
-            0x06 => match self.state {
-                State::Idle => {
-                    self.v_ram_addr_h = value;
-                    self.scroll_vertical &= 0b0011_1011;
-                    self.scroll_vertical |= (value & 0b11) << 6;
-                    self.registers.control_register.main_screen = (value & 0b00001100) >> 2;
-                    self.state = State::Writing;
+            }
+            0x05 => {
+                if !self.w {
+                    //X: coarse X → t, 下位3bit → fine X
+                    self.t = (self.t & !0x001F) | (value >> 3) as u16;
+                    self.fine_x = value & 0b111;
+                } else {
+                    //Y: coarse Y と fine Y → t
+                    self.t = (self.t & !0x73E0) | (((value & 0b111) as u16) << 12) | (((value >> 3) as u16) << 5);
                 }
-                State::Writing => {
-                    self.v_ram_addr_l = value;
-                    self.scroll_horizontal &= 0b00000111;
-                    self.scroll_horizontal |= (value & 0b11111) << 3;
-                    self.state = State::Idle;
+                self.w = !self.w;
+            }
+            0x06 => {
+                if !self.w {
+                    //上位6bit (bit14はクリアされる)
+                    self.t = (self.t & 0x00FF) | (((value & 0x3F) as u16) << 8);
+                } else {
+                    self.t = (self.t & 0xFF00) | value as u16;
+                    self.v = self.t;
+                    rom.mapper.notify_ppu_addr(self.v & 0x3FFF);
                 }
-            },
+                self.w = !self.w;
+            }
             0x07 => {
                 //VRAM
-                let mut addr = get_addr(self.v_ram_addr_h, self.v_ram_addr_l);
-                self.bus.v_ram.write(addr, value);
-                addr = addr.wrapping_add(if self.registers.control_register.v_ram_io_addressing {
-                    32
-                } else {
-                    1
-                });
-                self.v_ram_addr_h = ((addr & 0xFF00) >> 8) as u8;
-                self.v_ram_addr_l = addr as u8;
+                let addr = self.v & 0x3FFF;
+                rom.mapper.notify_ppu_addr(addr);
+                self.bus.v_ram.cpu_write(rom, addr, value);
+                self.increment_v_ram_addr();
             }
             _ => {}
         }
     }
 
+    /// Reset button: PPUCTRL, PPUMASK, scroll (t and fine X), the write toggle and the read buffer
+    /// are cleared. v, VRAM, OAM and palettes keep their contents.
+    pub fn reset(&mut self) {
+        self.registers.control_register.write(0);
+        self.registers.control_register2.write(0);
+        self.t = 0;
+        self.fine_x = 0;
+        self.w = false;
+        self.read_buffer = 0;
+        self.reset_flag = true;
+    }
+
+    /// Copy a page to OAM like OAM DMA would, without the CPU
+    #[cfg(test)]
     pub fn dma_write(&mut self, data: &[u8; 0x100]) {
-        for byte in data {
-            self.bus.v_ram.sprite_memory[self.sprite_addr as usize] = *byte;
-            self.sprite_addr = self.sprite_addr.wrapping_add(1);
+        for &byte in data {
+            self.write_oam(byte);
+        }
+    }
+
+    fn write_oam(&mut self, value: u8) {
+        //属性のbit2-4は存在しないので0になる
+        let value = if self.sprite_addr & 3 == 2 { value & 0xE3 } else { value };
+        self.bus.v_ram.sprite_memory[self.sprite_addr as usize] = value;
+        self.sprite_addr = self.sprite_addr.wrapping_add(1);
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.bus.v_ram.name_table[..]);
+        w.write_bytes(&self.bus.v_ram.background_palette[..]);
+        w.write_bytes(&self.bus.v_ram.sprite_palette[..]);
+        w.write_bytes(&self.bus.v_ram.sprite_memory[..]);
+        w.write_u8(self.registers.control_register.read());
+        w.write_u8(self.registers.control_register2.read());
+        w.write_u8(self.registers.status_register.read());
+        w.write_u8(self.sprite_addr);
+        w.write_u16(self.v);
+        w.write_u16(self.t);
+        w.write_u8(self.fine_x);
+        w.write_bool(self.w);
+        w.write_u16(self.current_x);
+        w.write_u16(self.current_y);
+        let frame: Vec<u8> = self.frame.iter().flat_map(|pixel| pixel.to_le_bytes()).collect();
+        w.write_bytes(&frame);
+        w.write_u8(self.read_buffer);
+        w.write_bool(self.reset_flag);
+        w.write_u8(self.bg.name);
+        w.write_u8(self.bg.attribute);
+        w.write_u8(self.bg.pattern_l);
+        w.write_u8(self.bg.pattern_h);
+        w.write_u16(self.bg.shift_pattern_l);
+        w.write_u16(self.bg.shift_pattern_h);
+        w.write_u16(self.bg.shift_attribute_l);
+        w.write_u16(self.bg.shift_attribute_h);
+        w.write_bytes(&self.secondary_oam);
+        w.write_u8(self.line_sprite_count);
+        w.write_bytes(&self.line_sprites);
+        w.write_u8(self.eval_n);
+        w.write_u8(self.eval_m);
+        w.write_u8(self.eval_copy);
+        w.write_bool(self.sprite_0_on_line);
+        w.write_u8(self.sprite_count);
+        for sprite in &self.sprites {
+            w.write_u8(sprite.x);
+            w.write_u8(sprite.attribute);
+            w.write_u8(sprite.pattern_l);
+            w.write_u8(sprite.pattern_h);
+            w.write_bool(sprite.is_sprite_0);
+        }
+        w.write_u8(self.io_latch);
+        w.write_bytes(&self.io_latch_decay);
+        w.write_bool(self.odd_frame);
+        w.write_bool(self.v_blank_suppressed);
+        w.write_u8(self.nmi_held_clocks);
+    }
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes_into(&mut self.bus.v_ram.name_table[..])?;
+        r.read_bytes_into(&mut self.bus.v_ram.background_palette[..])?;
+        r.read_bytes_into(&mut self.bus.v_ram.sprite_palette[..])?;
+        r.read_bytes_into(&mut self.bus.v_ram.sprite_memory[..])?;
+        self.registers.control_register.write(r.read_u8()?);
+        self.registers.control_register2.write(r.read_u8()?);
+        let status = r.read_u8()?;
+        self.registers.status_register.v_blank = status & 0b1000_0000 != 0;
+        self.registers.status_register.sprite_0_hit = status & 0b0100_0000 != 0;
+        self.registers.status_register.sprite_overflow = status & 0b0010_0000 != 0;
+        self.sprite_addr = r.read_u8()?;
+        self.v = r.read_u16()?;
+        self.t = r.read_u16()?;
+        if self.v > 0x7FFF || self.t > 0x7FFF {
+            return Err(StateError::InvalidData);
+        }
+        self.fine_x = r.read_u8_max(7)?;
+        self.w = r.read_bool()?;
+        self.current_x = r.read_u16()?;
+        self.current_y = r.read_u16()?;
+        if self.current_x > 340 || self.current_y >= self.region.scanlines() {
+            return Err(StateError::InvalidData);
+        }
+        let mut frame = vec![0; self.frame.len() * 2];
+        r.read_bytes_into(&mut frame)?;
+        for (pixel, bytes) in self.frame.iter_mut().zip(frame.chunks_exact(2)) {
+            *pixel = u16::from_le_bytes([bytes[0], bytes[1]]);
+            if *pixel > 0x1FF {
+                return Err(StateError::InvalidData);
+            }
+        }
+        self.read_buffer = r.read_u8()?;
+        self.reset_flag = r.read_bool()?;
+        self.bg.name = r.read_u8()?;
+        self.bg.attribute = r.read_u8_max(3)?;
+        self.bg.pattern_l = r.read_u8()?;
+        self.bg.pattern_h = r.read_u8()?;
+        self.bg.shift_pattern_l = r.read_u16()?;
+        self.bg.shift_pattern_h = r.read_u16()?;
+        self.bg.shift_attribute_l = r.read_u16()?;
+        self.bg.shift_attribute_h = r.read_u16()?;
+        r.read_bytes_into(&mut self.secondary_oam)?;
+        self.line_sprite_count = r.read_u8_max(64)?;
+        r.read_bytes_into(&mut self.line_sprites)?;
+        if self.line_sprites.iter().any(|&index| index >= 64) {
+            return Err(StateError::InvalidData);
+        }
+        self.eval_n = r.read_u8_max(64)?;
+        self.eval_m = r.read_u8_max(3)?;
+        self.eval_copy = r.read_u8_max(3)?;
+        self.sprite_0_on_line = r.read_bool()?;
+        self.sprite_count = r.read_u8_max(64)?;
+        for sprite in self.sprites.iter_mut() {
+            sprite.x = r.read_u8()?;
+            sprite.attribute = r.read_u8()?;
+            sprite.pattern_l = r.read_u8()?;
+            sprite.pattern_h = r.read_u8()?;
+            sprite.is_sprite_0 = r.read_bool()?;
         }
+        self.io_latch = r.read_u8()?;
+        r.read_bytes_into(&mut self.io_latch_decay)?;
+        self.odd_frame = r.read_bool()?;
+        self.v_blank_suppressed = r.read_bool()?;
+        self.nmi_held_clocks = r.read_u8()?;
+        Ok(())
     }
 }
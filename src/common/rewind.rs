@@ -0,0 +1,147 @@
+use std::collections::VecDeque;
+
+/// Ring buffer of save states for rewinding.
+///
+/// Only the newest snapshot is kept in full. Older ones are stored as deltas
+/// (XOR against the next newer snapshot, then run-length encoded), which stay
+/// small because most of the machine state doesn't change between snapshots.
+pub struct Rewind {
+    capacity: usize,
+    interval: u32,
+    frames_since_snapshot: u32,
+    latest: Option<Vec<u8>>,
+    /// deltas[i] turns snapshot i + 1 into snapshot i (oldest first)
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    /// Keep up to `capacity` snapshots, one every `interval` frames
+    pub fn new(capacity: usize, interval: u32) -> Self {
+        Rewind {
+            capacity: capacity.max(1),
+            interval: interval.max(1),
+            frames_since_snapshot: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// Called at the end of every frame; returns true if a snapshot is due
+    pub fn end_frame(&mut self) -> bool {
+        self.frames_since_snapshot += 1;
+        self.latest.is_none() || self.frames_since_snapshot >= self.interval
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        self.frames_since_snapshot = 0;
+        if let Some(latest) = self.latest.take() {
+            if latest.len() == state.len() {
+                self.deltas.push_back(encode_delta(&state, &latest));
+                if self.deltas.len() >= self.capacity {
+                    self.deltas.pop_front();
+                }
+            } else {
+                self.deltas.clear();
+            }
+        }
+        self.latest = Some(state);
+    }
+
+    /// Number of frames that can currently be rewound
+    pub fn available_frames(&self) -> u32 {
+        match self.latest {
+            Some(_) => self.frames_since_snapshot + self.deltas.len() as u32 * self.interval,
+            None => 0,
+        }
+    }
+
+    /// Drop snapshots until one at least `frames` frames old is the newest and return it
+    /// along with how many frames back it is. Returns the oldest snapshot if there isn't one old enough.
+    pub fn rewind(&mut self, frames: u32) -> Option<(&[u8], u32)> {
+        let latest = self.latest.as_mut()?;
+        let mut rewound = self.frames_since_snapshot;
+        while rewound < frames {
+            match self.deltas.pop_back() {
+                Some(delta) => {
+                    apply_delta(latest, &delta);
+                    rewound += self.interval;
+                }
+                None => break,
+            }
+        }
+        self.frames_since_snapshot = 0;
+        Some((latest, rewound))
+    }
+
+    /// Total bytes held by the buffer
+    pub fn memory_usage(&self) -> usize {
+        self.latest.as_ref().map_or(0, |latest| latest.len())
+            + self.deltas.iter().map(|delta| delta.len()).sum::<usize>()
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+/// Encode `from XOR to` as a list of (unchanged run length, changed run length, changed bytes)
+pub fn encode_delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < from.len() {
+        let skip_start = pos;
+        while pos < from.len() && from[pos] == to[pos] {
+            pos += 1;
+        }
+        if pos == from.len() {
+            break;
+        }
+        let literal_start = pos;
+        // A literal run ends at the first unchanged stretch long enough to be worth a new record
+        while pos < from.len() && from[pos..].iter().zip(&to[pos..]).take(4).any(|(a, b)| a != b) {
+            pos += 1;
+        }
+        write_varint(&mut out, literal_start - skip_start);
+        write_varint(&mut out, pos - literal_start);
+        out.extend(
+            from[literal_start..pos]
+                .iter()
+                .zip(&to[literal_start..pos])
+                .map(|(a, b)| a ^ b),
+        );
+    }
+    out
+}
+
+/// Apply a delta produced by `encode_delta(from, to)` to `from`, turning it into `to`
+pub fn apply_delta(data: &mut [u8], delta: &[u8]) {
+    let mut pos = 0;
+    let mut offset = 0;
+    while pos < delta.len() {
+        offset += read_varint(delta, &mut pos);
+        let len = read_varint(delta, &mut pos);
+        for (byte, diff) in data[offset..offset + len].iter_mut().zip(&delta[pos..pos + len]) {
+            *byte ^= diff;
+        }
+        pos += len;
+        offset += len;
+    }
+}
@@ -1,44 +1,79 @@
-struct SliceInfo {
-    start: usize,
-    end: usize,
-}
+use super::mapper::*;
+use super::nes::RamPowerOnState;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum MirroringMode {
     Horizontal,
     Vertical,
-    #[allow(dead_code)]
     SingleScreenLower,
-    #[allow(dead_code)]
     SingleScreenUpper,
     FourScreen,
 }
 
-pub struct Rom {
-    memory: Vec<u8>,
-    prog: SliceInfo,
-    chr: SliceInfo,
+/// Header format detected from byte 7
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeaderFormat {
+    /// iNES with garbage in bytes 12-15 (e.g. "DiskDude!"); the upper mapper nibble is ignored
+    ArchaicINes,
+    INes,
+    Nes20,
+}
+
+/// CPU/PPU timing (NES 2.0 byte 12)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy,
+}
+
+/// Console type (byte 7 bits 0-1, NES 2.0 byte 13)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem { ppu_type: u8, hardware_type: u8 },
+    Playchoice10,
+    Extended(u8),
+}
+
+/// Parsed iNES / NES 2.0 header. Sizes are in bytes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RomHeader {
+    pub format: HeaderFormat,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
     pub mirroring: MirroringMode,
-    #[allow(dead_code)]
-    pub has_battery_ram: bool,
-    #[allow(dead_code)]
-    pub mapper: u8,
-    has_chr_ram: bool,
+    pub has_battery: bool,
+    pub has_trainer: bool,
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+    pub misc_roms: u8,
+    pub default_expansion_device: u8,
 }
 
-impl Rom {
-    pub fn load(rom: &[u8]) -> Result<Self, String> {
-        if rom.len() < 16 || rom[0..=3] != [0x4E, 0x45, 0x53, 0x1A] {
-            return Err(String::from("invalid file format"));
-        }
+impl RomHeader {
+    pub const SIZE: usize = 16;
+    pub const TRAINER_SIZE: usize = 512;
 
-        let prog_start = 0x0010_usize;
-        let prog_end = prog_start + (rom[4] as usize) * 0x4000;
-        let chr_start = prog_end;
-        let chr_end = chr_start + (rom[5] as usize) * 0x2000;
+    /// Parse the 16 byte header. The magic number must already be checked.
+    pub fn parse(header: &[u8]) -> Self {
+        let flags6 = header[6];
+        let flags7 = header[7];
 
-        let flags6 = rom[6];
-        let flags7 = rom[7];
+        let format = if flags7 & 0x0C == 0x08 {
+            HeaderFormat::Nes20
+        } else if header[12..16].iter().all(|&b| b == 0) {
+            HeaderFormat::INes
+        } else {
+            HeaderFormat::ArchaicINes
+        };
 
         let mirroring = if flags6 & 0b1000 != 0 {
             MirroringMode::FourScreen
@@ -47,33 +82,250 @@ impl Rom {
         } else {
             MirroringMode::Horizontal
         };
+        let has_battery = flags6 & 0b10 != 0;
+        let has_trainer = flags6 & 0b100 != 0;
+
+        let console_type = |extra: u8| match flags7 & 0b11 {
+            0 => ConsoleType::Nes,
+            1 => ConsoleType::VsSystem { ppu_type: extra & 0x0F, hardware_type: extra >> 4 },
+            2 => ConsoleType::Playchoice10,
+            _ => ConsoleType::Extended(extra & 0x0F),
+        };
+
+        match format {
+            HeaderFormat::Nes20 => {
+                let mapper = (flags6 >> 4) as u16 | (flags7 & 0xF0) as u16 | ((header[8] & 0x0F) as u16) << 8;
+                let timing = match header[12] & 0b11 {
+                    0 => Timing::Ntsc,
+                    1 => Timing::Pal,
+                    2 => Timing::MultiRegion,
+                    _ => Timing::Dendy,
+                };
+                RomHeader {
+                    format,
+                    mapper,
+                    submapper: header[8] >> 4,
+                    prg_rom_size: Self::rom_size(header[4], header[9] & 0x0F, 0x4000),
+                    chr_rom_size: Self::rom_size(header[5], header[9] >> 4, 0x2000),
+                    prg_ram_size: Self::ram_size(header[10] & 0x0F),
+                    prg_nvram_size: Self::ram_size(header[10] >> 4),
+                    chr_ram_size: Self::ram_size(header[11] & 0x0F),
+                    chr_nvram_size: Self::ram_size(header[11] >> 4),
+                    mirroring,
+                    has_battery,
+                    has_trainer,
+                    timing,
+                    console_type: console_type(header[13]),
+                    misc_roms: header[14] & 0b11,
+                    default_expansion_device: header[15] & 0x3F,
+                }
+            }
+            _ => {
+                let mapper = if format == HeaderFormat::INes {
+                    (flags6 >> 4) | (flags7 & 0xF0)
+                } else {
+                    flags6 >> 4
+                };
+                let chr_rom_size = header[5] as usize * 0x2000;
+                // iNES can't express RAM sizes; assume the common 8K PRG-RAM and 8K CHR-RAM.
+                // Byte 8 (PRG-RAM in 8K units) is only trusted up to MMC5's 64K: most dumps leave it 0 or
+                // full of junk, and in archaic headers it's part of a signature like "DiskDude!".
+                let prg_ram_units = match header[8] {
+                    units @ 1..=8 if format == HeaderFormat::INes => units,
+                    _ => 1,
+                };
+                let prg_ram_size = prg_ram_units as usize * 0x2000;
+                let chr_ram_size = if chr_rom_size == 0 { 0x2000 } else { 0 };
+                let (prg_ram_size, prg_nvram_size) = if has_battery {
+                    (0, prg_ram_size)
+                } else {
+                    (prg_ram_size, 0)
+                };
+                let timing = if format == HeaderFormat::INes && header[9] & 1 != 0 {
+                    Timing::Pal
+                } else {
+                    Timing::Ntsc
+                };
+                RomHeader {
+                    format,
+                    mapper: mapper as u16,
+                    submapper: 0,
+                    prg_rom_size: header[4] as usize * 0x4000,
+                    chr_rom_size,
+                    prg_ram_size,
+                    prg_nvram_size,
+                    chr_ram_size,
+                    chr_nvram_size: 0,
+                    mirroring,
+                    has_battery,
+                    has_trainer,
+                    timing,
+                    console_type: if format == HeaderFormat::INes {
+                        console_type(0)
+                    } else {
+                        ConsoleType::Nes
+                    },
+                    misc_roms: 0,
+                    default_expansion_device: 0,
+                }
+            }
+        }
+    }
+
+    /// NES 2.0 ROM size: 12-bit bank count, or exponent-multiplier notation when the MSB nibble is $F
+    fn rom_size(lsb: u8, msb: u8, unit: usize) -> usize {
+        if msb == 0x0F {
+            let exponent = (lsb >> 2) as u32;
+            let multiplier = (lsb & 0b11) as usize * 2 + 1;
+            1_usize.checked_shl(exponent).unwrap_or(0).saturating_mul(multiplier)
+        } else {
+            (((msb as usize) << 8) | lsb as usize) * unit
+        }
+    }
+
+    /// NES 2.0 RAM size: 64 << shift, 0 means none
+    fn ram_size(shift: u8) -> usize {
+        if shift == 0 {
+            0
+        } else {
+            64 << shift
+        }
+    }
+}
+
+/// Reason a ROM image could not be loaded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RomError {
+    /// File is shorter than the 16 byte header
+    TruncatedHeader,
+    /// File doesn't start with "NES\x1A"
+    InvalidMagic,
+    /// Header has a trainer but the file ends inside it
+    TruncatedTrainer,
+    /// Header declares no PRG-ROM
+    EmptyPrgRom,
+    /// File ends before the declared PRG-ROM size (sizes in bytes)
+    TruncatedPrgRom {
+        expected: usize,
+        actual: usize,
+    },
+    /// File ends before the declared CHR-ROM size (sizes in bytes)
+    TruncatedChrRom {
+        expected: usize,
+        actual: usize,
+    },
+    UnsupportedMapper(u16),
+}
+
+impl std::fmt::Display for RomError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RomError::TruncatedHeader => write!(f, "file is too short for an iNES header"),
+            RomError::InvalidMagic => write!(f, "invalid file format"),
+            RomError::TruncatedTrainer => write!(f, "unexpected end of file in trainer"),
+            RomError::EmptyPrgRom => write!(f, "no PRG-ROM"),
+            RomError::TruncatedPrgRom { expected, actual } => {
+                write!(f, "PRG-ROM is truncated ({} of {} bytes)", actual, expected)
+            }
+            RomError::TruncatedChrRom { expected, actual } => {
+                write!(f, "CHR-ROM is truncated ({} of {} bytes)", actual, expected)
+            }
+            RomError::UnsupportedMapper(mapper) => write!(f, "unsupported mapper: {}", mapper),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+pub struct Rom {
+    pub header: RomHeader,
+    pub mapper: Box<dyn Mapper>,
+    /// 512 byte trainer, mapped at $7000
+    pub trainer: Option<Vec<u8>>,
+}
 
-        let has_battery_ram = flags6 & 0b10 != 0;
-        let mapper = (flags6 >> 4) | (flags7 & 0xF0);
+impl Rom {
+    pub fn load(rom: &[u8]) -> Result<Self, RomError> {
+        if rom.len() < RomHeader::SIZE {
+            return Err(RomError::TruncatedHeader);
+        }
+        if rom[0..=3] != [0x4E, 0x45, 0x53, 0x1A] {
+            return Err(RomError::InvalidMagic);
+        }
 
-        let has_chr_ram = rom[5] == 0;
+        let header = RomHeader::parse(&rom[..RomHeader::SIZE]);
+        if header.prg_rom_size == 0 {
+            return Err(RomError::EmptyPrgRom);
+        }
 
-        let mut memory = vec![];
-        memory.extend_from_slice(rom);
+        let trainer_start = RomHeader::SIZE;
+        let prog_start = trainer_start + if header.has_trainer { RomHeader::TRAINER_SIZE } else { 0 };
+        if rom.len() < prog_start {
+            return Err(RomError::TruncatedTrainer);
+        }
+        let prog_end = prog_start.saturating_add(header.prg_rom_size);
+        if rom.len() < prog_end {
+            return Err(RomError::TruncatedPrgRom { expected: header.prg_rom_size, actual: rom.len() - prog_start });
+        }
+        let chr_start = prog_end;
+        let chr_end = chr_start.saturating_add(header.chr_rom_size);
+        if rom.len() < chr_end {
+            return Err(RomError::TruncatedChrRom { expected: header.chr_rom_size, actual: rom.len() - chr_start });
+        }
 
-        let rom = Rom {
-            memory,
-            prog: SliceInfo { start: prog_start, end: prog_end },
-            chr: SliceInfo { start: chr_start, end: chr_end },
-            mirroring,
-            has_battery_ram,
-            mapper,
-            has_chr_ram,
+        let mut prg_ram_size = header.prg_ram_size + header.prg_nvram_size;
+        if header.has_trainer {
+            //トレーナーは$7000-$71FFに配置されるのでPRG-RAMが必要
+            prg_ram_size = prg_ram_size.max(0x2000);
+        }
+        let mut memory = CartridgeMemory::new(
+            &rom[prog_start..prog_end],
+            &rom[chr_start..chr_end],
+            prg_ram_size,
+            header.chr_ram_size + header.chr_nvram_size,
+        );
+        let trainer = if header.has_trainer {
+            Some(rom[trainer_start..prog_start].to_vec())
+        } else {
+            None
         };
+        if let Some(trainer) = &trainer {
+            for (i, &value) in trainer.iter().enumerate() {
+                memory.write_prg_ram(0x1000 + i, value);
+            }
+            memory.clear_prg_ram_dirty();
+        }
+        let mapper = create_mapper(header.mapper, memory, header.mirroring)?;
+
+        let rom = Rom { header, mapper, trainer };
         Ok(rom)
     }
+
+    /// Put the board back into its power-on state. Battery-backed PRG-RAM keeps its contents,
+    /// other PRG-RAM is filled with `pattern` (and the trainer reloaded).
+    pub fn power_cycle(&mut self, pattern: RamPowerOnState) {
+        let mut memory = self.mapper.memory().clone();
+        if !self.header.has_battery {
+            let mut ram = vec![0; memory.prg_ram().len()];
+            pattern.fill(&mut ram);
+            if let Some(trainer) = &self.trainer {
+                ram[0x1000..0x1000 + trainer.len()].copy_from_slice(trainer);
+            }
+            memory.load_prg_ram(&ram);
+        }
+        self.mapper = create_mapper(self.header.mapper, memory, self.header.mirroring)
+            .expect("mapper was supported when the ROM was loaded");
+    }
+    #[allow(dead_code)]
     pub fn get_prog(&self) -> &[u8] {
-        &self.memory[self.prog.start..self.prog.end]
+        self.mapper.memory().prg_rom()
     }
+    #[allow(dead_code)]
     pub fn get_chr(&self) -> &[u8] {
-        &self.memory[self.chr.start..self.chr.end]
+        self.mapper.memory().chr_rom()
     }
+    #[allow(dead_code)]
     pub fn has_chr_ram(&self) -> bool {
-        self.has_chr_ram
+        self.mapper.memory().has_chr_ram()
     }
 }
@@ -0,0 +1,346 @@
+//! Pixel art upscalers for RGB frames (such as a `Palette` applied to `Nes::get_screen`)
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scaler {
+    /// Scale2x (EPX)
+    Scale2x,
+    Scale3x,
+    /// Scale2x applied twice
+    Scale4x,
+    /// Not real HQ2x: corners are blended based on the YUV threshold comparisons hqx uses, but with a
+    /// compact rule set. hqx's 256 pattern table isn't implemented.
+    HqStyle2x,
+    HqStyle3x,
+    /// xBR (Hyllian), level 2 edge detection
+    Xbr2x,
+    Xbr3x,
+    /// xBR 2x applied twice
+    Xbr4x,
+}
+
+impl Scaler {
+    pub const ALL: [Scaler; 8] = [
+        Scaler::Scale2x,
+        Scaler::Scale3x,
+        Scaler::Scale4x,
+        Scaler::HqStyle2x,
+        Scaler::HqStyle3x,
+        Scaler::Xbr2x,
+        Scaler::Xbr3x,
+        Scaler::Xbr4x,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scaler::Scale2x => "Scale2x",
+            Scaler::Scale3x => "Scale3x",
+            Scaler::Scale4x => "Scale4x",
+            Scaler::HqStyle2x => "HQ-style 2x",
+            Scaler::HqStyle3x => "HQ-style 3x",
+            Scaler::Xbr2x => "xBR 2x",
+            Scaler::Xbr3x => "xBR 3x",
+            Scaler::Xbr4x => "xBR 4x",
+        }
+    }
+
+    pub fn factor(&self) -> usize {
+        match self {
+            Scaler::Scale2x | Scaler::HqStyle2x | Scaler::Xbr2x => 2,
+            Scaler::Scale3x | Scaler::HqStyle3x | Scaler::Xbr3x => 3,
+            Scaler::Scale4x | Scaler::Xbr4x => 4,
+        }
+    }
+}
+
+type Rgb = [u8; 3];
+
+/// Scale a `width` x `height` image by `scaler.factor()` in both directions
+pub fn scale(input: &[Rgb], width: usize, height: usize, scaler: Scaler) -> Vec<Rgb> {
+    assert_eq!(input.len(), width * height);
+    let image = Image::new(input, width, height);
+    match scaler {
+        Scaler::Scale2x => scale2x(&image),
+        Scaler::Scale3x => scale3x(&image),
+        Scaler::Scale4x => scale2x(&Image::new(&scale2x(&image), width * 2, height * 2)),
+        Scaler::HqStyle2x => hq_style(&image, 2),
+        Scaler::HqStyle3x => hq_style(&image, 3),
+        Scaler::Xbr2x => xbr(&image, 2),
+        Scaler::Xbr3x => xbr(&image, 3),
+        Scaler::Xbr4x => xbr(&Image::new(&xbr(&image, 2), width * 2, height * 2), 2),
+    }
+}
+
+struct Image<'a> {
+    pixels: &'a [Rgb],
+    yuv: Vec<[i32; 3]>,
+    width: usize,
+    height: usize,
+}
+
+impl<'a> Image<'a> {
+    fn new(pixels: &'a [Rgb], width: usize, height: usize) -> Self {
+        let yuv = pixels
+            .iter()
+            .map(|&[r, g, b]| {
+                let (r, g, b) = (r as i32, g as i32, b as i32);
+                [
+                    (299 * r + 587 * g + 114 * b) / 1000,
+                    (-169 * r - 331 * g + 500 * b) / 1000 + 128,
+                    (500 * r - 419 * g - 81 * b) / 1000 + 128,
+                ]
+            })
+            .collect();
+        Image { pixels, yuv, width, height }
+    }
+
+    /// Index of the pixel at an offset from (x, y); the edges are repeated outside the image
+    #[inline(always)]
+    fn index(&self, x: usize, y: usize, dx: isize, dy: isize) -> usize {
+        let x = (x as isize + dx).clamp(0, self.width as isize - 1) as usize;
+        let y = (y as isize + dy).clamp(0, self.height as isize - 1) as usize;
+        y * self.width + x
+    }
+
+    /// Run `f` for every pixel and write the `factor` x `factor` block it returns (row-major,
+    /// any entries past `factor * factor` are ignored)
+    fn map_blocks<const N: usize>(&self, factor: usize, f: impl Fn(usize, usize) -> [Rgb; N]) -> Vec<Rgb> {
+        let out_width = self.width * factor;
+        let mut output = vec![[0; 3]; out_width * self.height * factor];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let block = f(x, y);
+                for (i, &pixel) in block.iter().take(factor * factor).enumerate() {
+                    output[(y * factor + i / factor) * out_width + x * factor + i % factor] = pixel;
+                }
+            }
+        }
+        output
+    }
+}
+
+fn scale2x(image: &Image) -> Vec<Rgb> {
+    image.map_blocks(2, |x, y| {
+        let p = |dx, dy| image.pixels[image.index(x, y, dx, dy)];
+        let (a, b, c, d, e) = (p(0, -1), p(1, 0), p(-1, 0), p(0, 1), p(0, 0));
+        if c != b && a != d {
+            [
+                if c == a { a } else { e },
+                if a == b { b } else { e },
+                if d == c { c } else { e },
+                if b == d { d } else { e },
+            ]
+        } else {
+            [e; 4]
+        }
+    })
+}
+
+fn scale3x(image: &Image) -> Vec<Rgb> {
+    image.map_blocks(3, |x, y| {
+        let p = |dx, dy| image.pixels[image.index(x, y, dx, dy)];
+        let (a, b, c) = (p(-1, -1), p(0, -1), p(1, -1));
+        let (d, e, f) = (p(-1, 0), p(0, 0), p(1, 0));
+        let (g, h, i) = (p(-1, 1), p(0, 1), p(1, 1));
+        if b == h || d == f {
+            return [e; 9];
+        }
+        [
+            if d == b { d } else { e },
+            if (d == b && e != c) || (b == f && e != a) { b } else { e },
+            if b == f { f } else { e },
+            if (d == b && e != g) || (d == h && e != a) { d } else { e },
+            e,
+            if (b == f && e != i) || (h == f && e != c) { f } else { e },
+            if d == h { d } else { e },
+            if (d == h && e != i) || (h == f && e != g) { h } else { e },
+            if h == f { f } else { e },
+        ]
+    })
+}
+
+/// Weighted average of colors
+fn mix(colors: &[(Rgb, u32)]) -> Rgb {
+    let total: u32 = colors.iter().map(|&(_, weight)| weight).sum();
+    std::array::from_fn(|channel| {
+        let sum: u32 = colors
+            .iter()
+            .map(|&(color, weight)| color[channel] as u32 * weight)
+            .sum();
+        ((sum + total / 2) / total) as u8
+    })
+}
+
+/// The 4 diagonal directions, in the order of the corners of an output block
+const CORNERS: [(isize, isize); 4] = [(-1, -1), (1, -1), (-1, 1), (1, 1)];
+
+fn hq_style(image: &Image, factor: usize) -> Vec<Rgb> {
+    //hqxと同じしきい値 (Y, U, V)
+    let differ = |a: usize, b: usize| {
+        let (a, b) = (image.yuv[a], image.yuv[b]);
+        (a[0] - b[0]).abs() > 48 || (a[1] - b[1]).abs() > 7 || (a[2] - b[2]).abs() > 6
+    };
+    let corner = |x: usize, y: usize, (dx, dy): (isize, isize)| {
+        let e = image.index(x, y, 0, 0);
+        let horizontal = image.index(x, y, dx, 0);
+        let vertical = image.index(x, y, 0, dy);
+        let diagonal = image.index(x, y, dx, dy);
+        let pixel = |i: usize| image.pixels[i];
+        if differ(e, horizontal) && differ(e, vertical) && !differ(horizontal, vertical) {
+            //角が辺をまたいでいる
+            if differ(e, diagonal) {
+                mix(&[(pixel(e), 2), (pixel(horizontal), 1), (pixel(vertical), 1)])
+            } else {
+                mix(&[(pixel(e), 6), (pixel(horizontal), 1), (pixel(vertical), 1)])
+            }
+        } else {
+            pixel(e)
+        }
+    };
+    match factor {
+        2 => image.map_blocks(2, |x, y| CORNERS.map(|direction| corner(x, y, direction))),
+        _ => image.map_blocks(3, |x, y| {
+            let [top_left, top_right, bottom_left, bottom_right] = CORNERS.map(|direction| corner(x, y, direction));
+            let e = image.index(x, y, 0, 0);
+            //辺の中央は両側の角が同じ側に寄っている時だけ混ぜる
+            let edge = |dx: isize, dy: isize, a: Rgb, b: Rgb| {
+                let side = image.index(x, y, dx, dy);
+                let center = image.pixels[e];
+                if differ(e, side) && a != center && b != center {
+                    mix(&[(center, 7), (image.pixels[side], 1)])
+                } else {
+                    center
+                }
+            };
+            [
+                top_left,
+                edge(0, -1, top_left, top_right),
+                top_right,
+                edge(-1, 0, top_left, bottom_left),
+                image.pixels[e],
+                edge(1, 0, top_right, bottom_right),
+                bottom_left,
+                edge(0, 1, bottom_left, bottom_right),
+                bottom_right,
+            ]
+        }),
+    }
+}
+
+/// Blend `weight`/256 of `color` into `dest`
+fn blend(dest: &mut Rgb, color: Rgb, weight: u32) {
+    for (d, c) in dest.iter_mut().zip(color) {
+        *d = ((*d as u32 * (256 - weight) + c as u32 * weight) / 256) as u8;
+    }
+}
+
+fn xbr(image: &Image, factor: usize) -> Vec<Rgb> {
+    let df = |a: usize, b: usize| {
+        let (a, b) = (image.yuv[a], image.yuv[b]);
+        48 * (a[0] - b[0]).abs() + 7 * (a[1] - b[1]).abs() + 6 * (a[2] - b[2]).abs()
+    };
+    let eq = |a: usize, b: usize| df(a, b) < 155;
+    image.map_blocks(factor, |x, y| {
+        let mut out = [image.pixels[image.index(x, y, 0, 0)]; 9];
+        //右下の角の処理を90°ずつ回転させて4つの角に適用する
+        for rotation in 0..4 {
+            let rotate = |dx: isize, dy: isize| match rotation {
+                0 => (dx, dy),
+                1 => (dy, -dx),
+                2 => (-dx, -dy),
+                _ => (-dy, dx),
+            };
+            let n = |dx: isize, dy: isize| {
+                let (dx, dy) = rotate(dx, dy);
+                image.index(x, y, dx, dy)
+            };
+            //出力ブロック内の位置 (右下の角を基準にした (列, 行))
+            let sub = |column: usize, row: usize| {
+                let (dx, dy) = rotate(
+                    2 * column as isize - (factor as isize - 1),
+                    2 * row as isize - (factor as isize - 1),
+                );
+                let column = ((dx + factor as isize - 1) / 2) as usize;
+                let row = ((dy + factor as isize - 1) / 2) as usize;
+                row * factor + column
+            };
+            let (pe, pi, ph, pf) = (n(0, 0), n(1, 1), n(0, 1), n(1, 0));
+            let (pg, pc, pd, pb) = (n(-1, 1), n(1, -1), n(-1, 0), n(0, -1));
+            let (h5, f4, i4, i5) = (n(0, 2), n(2, 0), n(2, 1), n(1, 2));
+            let color = |i: usize| image.pixels[i];
+            if color(pe) == color(ph) || color(pe) == color(pf) {
+                continue;
+            }
+            let e = df(pe, pc) + df(pe, pg) + df(pi, h5) + df(pi, f4) + 4 * df(ph, pf);
+            let i = df(ph, pd) + df(ph, i5) + df(pf, i4) + df(pf, pb) + 4 * df(pe, pi);
+            if e > i {
+                continue;
+            }
+            let px = if df(pe, pf) <= df(pe, ph) { color(pf) } else { color(ph) };
+            let last = factor - 1;
+            let corner = sub(last, last);
+            if e < i
+                && ((!eq(pf, pb) && !eq(ph, pd))
+                    || (eq(pe, pi) && !eq(pf, i4) && !eq(ph, i5))
+                    || eq(pe, pg)
+                    || eq(pe, pc))
+            {
+                let ke = df(pf, pg);
+                let ki = df(ph, pc);
+                //浅い辺 (左に伸びる) と急な辺 (上に伸びる)
+                let left = 2 * ke <= ki && color(pe) != color(pg) && color(pd) != color(pg);
+                let up = ke >= 2 * ki && color(pe) != color(pc) && color(pb) != color(pc);
+                if factor == 2 {
+                    let (right, bottom) = (sub(1, 0), sub(0, 1));
+                    match (left, up) {
+                        (true, true) => {
+                            blend(&mut out[corner], px, 224);
+                            blend(&mut out[bottom], px, 64);
+                            out[right] = out[bottom];
+                        }
+                        (true, false) => {
+                            blend(&mut out[corner], px, 192);
+                            blend(&mut out[bottom], px, 64);
+                        }
+                        (false, true) => {
+                            blend(&mut out[corner], px, 192);
+                            blend(&mut out[right], px, 64);
+                        }
+                        (false, false) => blend(&mut out[corner], px, 128),
+                    }
+                } else {
+                    let (right_top, right_middle) = (sub(2, 0), sub(2, 1));
+                    let (bottom_left, bottom_middle) = (sub(0, 2), sub(1, 2));
+                    match (left, up) {
+                        (true, true) => {
+                            blend(&mut out[bottom_middle], px, 192);
+                            blend(&mut out[bottom_left], px, 64);
+                            out[right_middle] = out[bottom_middle];
+                            out[right_top] = out[bottom_left];
+                            out[corner] = px;
+                        }
+                        (true, false) => {
+                            blend(&mut out[bottom_middle], px, 192);
+                            blend(&mut out[right_middle], px, 64);
+                            blend(&mut out[bottom_left], px, 64);
+                            out[corner] = px;
+                        }
+                        (false, true) => {
+                            blend(&mut out[right_middle], px, 192);
+                            blend(&mut out[bottom_middle], px, 64);
+                            blend(&mut out[right_top], px, 64);
+                            out[corner] = px;
+                        }
+                        (false, false) => {
+                            blend(&mut out[corner], px, 224);
+                            blend(&mut out[right_middle], px, 32);
+                            blend(&mut out[bottom_middle], px, 32);
+                        }
+                    }
+                }
+            } else {
+                blend(&mut out[corner], px, 128);
+            }
+        }
+        out
+    })
+}
@@ -0,0 +1,170 @@
+//! Save state binary format
+//!
+//! All integers are little-endian, `bool` is one byte (0 or 1) and byte arrays are
+//! a `u32` length followed by the bytes.
+//!
+//! | Offset | Size | Contents                                          |
+//! |--------|------|---------------------------------------------------|
+//! | 0      | 4    | Magic `"YNST"`                                    |
+//! | 4      | 4    | Format version (`STATE_VERSION`)                  |
+//! | 8      | 4    | ROM fingerprint (FNV-1a of PRG-ROM and CHR-ROM)   |
+//! | 12     | ...  | Nes, CPU, PPU, APU and mapper sections, in order  |
+//!
+//! The section layouts are defined by the `save_state` methods of each component and
+//! change together with `STATE_VERSION`.
+
+/// Bumped whenever any section layout changes
+pub const STATE_VERSION: u32 = 11;
+
+const MAGIC: [u8; 4] = *b"YNST";
+
+/// Reason a save state could not be loaded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StateError {
+    InvalidMagic,
+    UnsupportedVersion(u32),
+    /// The state was saved with a different ROM
+    RomMismatch,
+    UnexpectedEof,
+    /// A field holds a value the emulator can't be in
+    InvalidData,
+}
+
+impl std::fmt::Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StateError::InvalidMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => write!(f, "unsupported save state version: {}", version),
+            StateError::RomMismatch => write!(f, "save state belongs to a different ROM"),
+            StateError::UnexpectedEof => write!(f, "unexpected end of save state"),
+            StateError::InvalidData => write!(f, "invalid save state data"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// FNV-1a hash identifying the cartridge a state belongs to
+pub fn rom_fingerprint(prg_rom: &[u8], chr_rom: &[u8]) -> u32 {
+    prg_rom
+        .iter()
+        .chain(chr_rom.iter())
+        .fold(0x811C_9DC5_u32, |hash, &byte| {
+            (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+        })
+}
+
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(fingerprint: u32) -> Self {
+        let mut writer = StateWriter { buf: Vec::new() };
+        writer.buf.extend_from_slice(&MAGIC);
+        writer.write_u32(STATE_VERSION);
+        writer.write_u32(fingerprint);
+        writer
+    }
+    pub fn write_u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+    pub fn write_bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+    pub fn write_u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_f64(&mut self, value: f64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_bytes(&mut self, value: &[u8]) {
+        self.write_u32(value.len() as u32);
+        self.buf.extend_from_slice(value);
+    }
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    /// Check the header and position the reader at the first section
+    pub fn new(data: &'a [u8], fingerprint: u32) -> Result<Self, StateError> {
+        let mut reader = StateReader { data, pos: 0 };
+        if reader.take(4)? != MAGIC {
+            return Err(StateError::InvalidMagic);
+        }
+        let version = reader.read_u32()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        if reader.read_u32()? != fingerprint {
+            return Err(StateError::RomMismatch);
+        }
+        Ok(reader)
+    }
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        let end = self.pos.checked_add(len).ok_or(StateError::UnexpectedEof)?;
+        let slice = self.data.get(self.pos..end).ok_or(StateError::UnexpectedEof)?;
+        self.pos = end;
+        Ok(slice)
+    }
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+    /// Read a u8 that must not exceed `max`
+    pub fn read_u8_max(&mut self, max: u8) -> Result<u8, StateError> {
+        let value = self.read_u8()?;
+        if value > max {
+            return Err(StateError::InvalidData);
+        }
+        Ok(value)
+    }
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::InvalidData),
+        }
+    }
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    pub fn read_f64(&mut self) -> Result<f64, StateError> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    /// Read a byte array into `dest`; the stored length must match exactly
+    pub fn read_bytes_into(&mut self, dest: &mut [u8]) -> Result<(), StateError> {
+        let len = self.read_u32()? as usize;
+        if len != dest.len() {
+            return Err(StateError::InvalidData);
+        }
+        dest.copy_from_slice(self.take(len)?);
+        Ok(())
+    }
+    /// Fails if there is trailing data
+    pub fn finish(&self) -> Result<(), StateError> {
+        if self.pos == self.data.len() {
+            Ok(())
+        } else {
+            Err(StateError::InvalidData)
+        }
+    }
+}
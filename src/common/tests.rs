@@ -1,22 +1,28 @@
 use super::apu::*;
+use super::disasm::*;
 use super::nes::*;
+use super::ntsc;
+use super::palette::*;
 use super::ppu::*;
 use super::rom::*;
+use super::scale::*;
 use super::util::*;
 
 /// Creates a minimal valid iNES ROM for testing
 fn make_test_rom(prg: &[u8], chr: &[u8], vertical_mirroring: bool) -> Vec<u8> {
+    make_test_rom_with_mapper(prg, chr, vertical_mirroring, 0)
+}
+
+/// Creates a minimal valid iNES ROM using the given mapper number
+fn make_test_rom_with_mapper(prg: &[u8], chr: &[u8], vertical_mirroring: bool, mapper: u8) -> Vec<u8> {
     let prg_banks = if prg.len() <= 0x4000 {
         1
     } else {
-        (prg.len() + 0x3FFF) / 0x4000
-    };
-    let chr_banks = if chr.is_empty() {
-        0
-    } else {
-        (chr.len() + 0x1FFF) / 0x2000
+        prg.len().div_ceil(0x4000)
     };
-    let flags6 = if vertical_mirroring { 1u8 } else { 0u8 };
+    let chr_banks = if chr.is_empty() { 0 } else { chr.len().div_ceil(0x2000) };
+    let flags6 = if vertical_mirroring { 1u8 } else { 0u8 } | (mapper << 4);
+    let flags7 = mapper & 0xF0;
     let mut rom_data = vec![
         0x4E,
         0x45,
@@ -25,7 +31,7 @@ fn make_test_rom(prg: &[u8], chr: &[u8], vertical_mirroring: bool) -> Vec<u8> {
         prg_banks as u8,
         chr_banks as u8,
         flags6,
-        0,
+        flags7,
         0,
         0,
         0,
@@ -54,7 +60,7 @@ fn test_rom_parse_valid() {
     let rom = Rom::load(&rom_data);
     assert!(rom.is_ok());
     let rom = rom.unwrap();
-    assert_eq!(rom.mirroring, MirroringMode::Vertical);
+    assert_eq!(rom.header.mirroring, MirroringMode::Vertical);
     assert_eq!(rom.get_prog().len(), 0x4000);
     assert_eq!(rom.get_chr().len(), 0x2000);
 }
@@ -63,7 +69,7 @@ fn test_rom_parse_valid() {
 fn test_rom_parse_horizontal_mirroring() {
     let rom_data = make_test_rom(&[0u8; 0x4000], &[0u8; 0x2000], false);
     let rom = Rom::load(&rom_data).unwrap();
-    assert_eq!(rom.mirroring, MirroringMode::Horizontal);
+    assert_eq!(rom.header.mirroring, MirroringMode::Horizontal);
 }
 
 #[test]
@@ -76,10 +82,422 @@ fn test_rom_chr_ram_flag() {
     assert_eq!(rom.get_chr().len(), 0);
 }
 
+#[test]
+fn test_rom_header_ines() {
+    let rom = Rom::load(&make_test_rom_with_mapper(&[0u8; 0x8000], &[], true, 0x42)).unwrap();
+    let header = rom.header;
+    assert_eq!(header.format, HeaderFormat::INes);
+    assert_eq!(header.mapper, 0x42);
+    assert_eq!(header.prg_rom_size, 0x8000);
+    assert_eq!(header.chr_rom_size, 0);
+    assert_eq!(header.prg_ram_size, 0x2000);
+    assert_eq!(header.chr_ram_size, 0x2000);
+    assert_eq!(header.timing, Timing::Ntsc);
+    assert_eq!(header.console_type, ConsoleType::Nes);
+}
+
+#[test]
+fn test_rom_header_archaic_ines_ignores_upper_mapper_nibble() {
+    let mut rom_data = make_test_rom_with_mapper(&[0u8; 0x4000], &[0u8; 0x2000], false, 0x42);
+    rom_data[7..16].copy_from_slice(b"DiskDude!");
+    let rom = Rom::load(&rom_data).unwrap();
+    assert_eq!(rom.header.format, HeaderFormat::ArchaicINes);
+    assert_eq!(rom.header.mapper, 2);
+}
+
+#[test]
+fn test_rom_header_ignores_junk_ram_size() {
+    // Battery-backed archaic dump with a signature over bytes 7-15: byte 8 is 'i'
+    let mut header = [0u8; 16];
+    header[..4].copy_from_slice(b"NES\x1A");
+    header[4] = 2;
+    header[6] = 0b10;
+    header[7..16].copy_from_slice(b"DiskDude!");
+    let parsed = RomHeader::parse(&header);
+    assert_eq!(parsed.format, HeaderFormat::ArchaicINes);
+    assert_eq!(parsed.prg_ram_size, 0);
+    assert_eq!(parsed.prg_nvram_size, 0x2000);
+
+    // iNES: byte 8 is used for plausible sizes only
+    header[7..16].fill(0);
+    header[8] = 4;
+    assert_eq!(RomHeader::parse(&header).prg_nvram_size, 0x8000);
+    header[8] = 0xFF;
+    assert_eq!(RomHeader::parse(&header).prg_nvram_size, 0x2000);
+}
+
+#[test]
+fn test_rom_header_nes20() {
+    let mut rom_data = make_test_rom_with_mapper(&[0u8; 0x8000], &[], false, 2);
+    rom_data[7] |= 0x08;
+    rom_data[8] = 0x10; // submapper 1
+    rom_data[10] = 0x70; // 8K PRG-NVRAM
+    rom_data[11] = 0x08; // 16K CHR-RAM
+    rom_data[12] = 0x03; // Dendy
+    rom_data[15] = 0x01; // standard controllers
+    let rom = Rom::load(&rom_data).unwrap();
+    let header = rom.header;
+    assert_eq!(header.format, HeaderFormat::Nes20);
+    assert_eq!(header.mapper, 2);
+    assert_eq!(header.submapper, 1);
+    assert_eq!(header.prg_rom_size, 0x8000);
+    assert_eq!(header.prg_ram_size, 0);
+    assert_eq!(header.prg_nvram_size, 0x2000);
+    assert_eq!(header.chr_ram_size, 0x4000);
+    assert_eq!(header.timing, Timing::Dendy);
+    assert_eq!(header.default_expansion_device, 1);
+
+    // CHR-RAM is sized from the header
+    let mut rom = rom;
+    rom.mapper.ppu_write(0x1FFF, 0x77);
+    assert_eq!(rom.mapper.ppu_read(0x1FFF), 0x77);
+}
+
+#[test]
+fn test_rom_header_nes20_exponent_multiplier_size() {
+    let mut rom_data = make_test_rom(&[0u8; 0x4000], &[], false);
+    rom_data[7] |= 0x08;
+    rom_data[9] = 0x0F;
+    rom_data[4] = (13 << 2) | 0b01; // 2^13 * 3 = 24K
+    rom_data.resize(16 + 0x6000, 0);
+    let rom = Rom::load(&rom_data).unwrap();
+    assert_eq!(rom.header.prg_rom_size, 0x6000);
+    assert_eq!(rom.get_prog().len(), 0x6000);
+}
+
+#[test]
+fn test_rom_header_nes20_without_prg_ram() {
+    let mut rom_data = make_test_rom(&[0u8; 0x4000], &[0u8; 0x2000], false);
+    rom_data[7] |= 0x08;
+    let mut rom = Rom::load(&rom_data).unwrap();
+    rom.mapper.cpu_write(0x6000, 0x12);
+    assert_eq!(rom.mapper.cpu_read(0x6000), 0);
+}
+
+#[test]
+fn test_rom_trainer_loaded_at_7000() {
+    let mut prg = vec![0u8; 0x4000];
+    prg[0] = 0xA5;
+    let mut rom_data = make_test_rom(&prg, &[0u8; 0x2000], false);
+    rom_data[6] |= 0b100;
+    let mut trainer = [0u8; 512];
+    trainer[0] = 0x11;
+    trainer[511] = 0x22;
+    rom_data.splice(16..16, trainer.iter().cloned());
+    let rom = Rom::load(&rom_data).unwrap();
+    assert!(rom.header.has_trainer);
+    assert_eq!(rom.mapper.cpu_read(0x8000), 0xA5);
+    assert_eq!(rom.mapper.cpu_read(0x7000), 0x11);
+    assert_eq!(rom.mapper.cpu_read(0x71FF), 0x22);
+}
+
 #[test]
 fn test_rom_invalid() {
     let result = Rom::load(&[0, 1, 2, 3]);
     assert!(result.is_err());
+    assert_eq!(Rom::load(&[0u8; 16]).err(), Some(RomError::InvalidMagic));
+}
+
+#[test]
+fn test_rom_unsupported_mapper() {
+    let rom_data = make_test_rom_with_mapper(&[0u8; 0x4000], &[0u8; 0x2000], false, 0xFF);
+    assert!(Rom::load(&rom_data).is_err());
+    assert_eq!(Rom::load(&rom_data).err(), Some(RomError::UnsupportedMapper(0xFF)));
+}
+
+#[test]
+fn test_rom_truncated_errors() {
+    let rom_data = make_test_rom(&[0u8; 0x4000], &[0u8; 0x2000], false);
+    assert_eq!(Rom::load(&rom_data[..15]).err(), Some(RomError::TruncatedHeader));
+    assert_eq!(
+        Rom::load(&rom_data[..16 + 0x1000]).err(),
+        Some(RomError::TruncatedPrgRom { expected: 0x4000, actual: 0x1000 })
+    );
+    assert_eq!(
+        Rom::load(&rom_data[..16 + 0x4000 + 0x100]).err(),
+        Some(RomError::TruncatedChrRom { expected: 0x2000, actual: 0x100 })
+    );
+
+    let mut with_trainer = rom_data[..16].to_vec();
+    with_trainer[6] |= 0b100;
+    with_trainer.extend_from_slice(&[0u8; 100]);
+    assert_eq!(Rom::load(&with_trainer).err(), Some(RomError::TruncatedTrainer));
+
+    let mut no_prg = rom_data.clone();
+    no_prg[4] = 0;
+    assert_eq!(Rom::load(&no_prg).err(), Some(RomError::EmptyPrgRom));
+}
+
+#[test]
+fn test_nrom_prg_mirroring() {
+    // 16K PRG-ROM is mirrored into $C000-$FFFF
+    let mut prg = vec![0u8; 0x4000];
+    prg[0x0123] = 0x5A;
+    let rom = Rom::load(&make_test_rom(&prg, &[0u8; 0x2000], false)).unwrap();
+    assert_eq!(rom.mapper.cpu_read(0x8123), 0x5A);
+    assert_eq!(rom.mapper.cpu_read(0xC123), 0x5A);
+}
+
+#[test]
+fn test_nrom_chr_ram_write() {
+    let mut rom = Rom::load(&make_test_rom(&[0u8; 0x4000], &[], false)).unwrap();
+    rom.mapper.ppu_write(0x1234, 0xA5);
+    assert_eq!(rom.mapper.ppu_read(0x1234), 0xA5);
+
+    // CHR-ROM is read-only
+    let mut rom = Rom::load(&make_test_rom(&[0u8; 0x4000], &[0u8; 0x2000], false)).unwrap();
+    rom.mapper.ppu_write(0x1234, 0xA5);
+    assert_eq!(rom.mapper.ppu_read(0x1234), 0x00);
+}
+
+/// Writes a 5-bit value to an MMC1 register through the serial port
+fn mmc1_write(rom: &mut Rom, addr: u16, value: u8) {
+    for i in 0..5 {
+        rom.mapper.cpu_write(addr, (value >> i) & 1);
+        rom.mapper.clock_cpu();
+        rom.mapper.clock_cpu();
+    }
+}
+
+/// PRG-ROM where the first byte of every 16K bank holds its bank number
+fn make_banked_prg(banks: usize) -> Vec<u8> {
+    let mut prg = vec![0u8; banks * 0x4000];
+    for bank in 0..banks {
+        prg[bank * 0x4000] = bank as u8;
+    }
+    prg
+}
+
+#[test]
+fn test_mmc1_prg_banking() {
+    let rom_data = make_test_rom_with_mapper(&make_banked_prg(8), &[0u8; 0x2000], false, 1);
+    let mut rom = Rom::load(&rom_data).unwrap();
+    // Power-on: last bank fixed at $C000
+    assert_eq!(rom.mapper.cpu_read(0xC000), 7);
+    mmc1_write(&mut rom, 0xE000, 3);
+    assert_eq!(rom.mapper.cpu_read(0x8000), 3);
+    assert_eq!(rom.mapper.cpu_read(0xC000), 7);
+    // Fix first bank at $8000, switch $C000
+    mmc1_write(&mut rom, 0x8000, 0b01000);
+    assert_eq!(rom.mapper.cpu_read(0x8000), 0);
+    assert_eq!(rom.mapper.cpu_read(0xC000), 3);
+    // 32K mode ignores the low bank bit
+    mmc1_write(&mut rom, 0x8000, 0b00000);
+    assert_eq!(rom.mapper.cpu_read(0x8000), 2);
+    assert_eq!(rom.mapper.cpu_read(0xC000), 3);
+}
+
+#[test]
+fn test_mmc1_chr_banking_and_mirroring() {
+    let mut chr = vec![0u8; 0x8000];
+    for bank in 0..8 {
+        chr[bank * 0x1000] = bank as u8;
+    }
+    let rom_data = make_test_rom_with_mapper(&make_banked_prg(2), &chr, false, 1);
+    let mut rom = Rom::load(&rom_data).unwrap();
+    // 4K CHR mode, vertical mirroring
+    mmc1_write(&mut rom, 0x8000, 0b11110);
+    assert_eq!(rom.mapper.mirroring(), MirroringMode::Vertical);
+    mmc1_write(&mut rom, 0xA000, 5);
+    mmc1_write(&mut rom, 0xC000, 2);
+    assert_eq!(rom.mapper.ppu_read(0x0000), 5);
+    assert_eq!(rom.mapper.ppu_read(0x1000), 2);
+    // Single-screen mirroring, 8K CHR mode
+    mmc1_write(&mut rom, 0x8000, 0b01101);
+    assert_eq!(rom.mapper.mirroring(), MirroringMode::SingleScreenUpper);
+    assert_eq!(rom.mapper.ppu_read(0x0000), 4);
+    assert_eq!(rom.mapper.ppu_read(0x1000), 5);
+}
+
+#[test]
+fn test_mmc1_ignores_consecutive_writes() {
+    let rom_data = make_test_rom_with_mapper(&make_banked_prg(8), &[0u8; 0x2000], false, 1);
+    let mut rom = Rom::load(&rom_data).unwrap();
+    // The second write of each pair lands on the next cycle and is ignored
+    for i in 0..5 {
+        rom.mapper.cpu_write(0xE000, (5 >> i) & 1);
+        rom.mapper.clock_cpu();
+        rom.mapper.cpu_write(0xE000, 1);
+        rom.mapper.clock_cpu();
+        rom.mapper.clock_cpu();
+    }
+    assert_eq!(rom.mapper.cpu_read(0x8000), 5);
+}
+
+#[test]
+fn test_mmc1_prg_ram_enable() {
+    let rom_data = make_test_rom_with_mapper(&make_banked_prg(2), &[0u8; 0x2000], false, 1);
+    let mut rom = Rom::load(&rom_data).unwrap();
+    rom.mapper.cpu_write(0x6000, 0x42);
+    assert_eq!(rom.mapper.cpu_read(0x6000), 0x42);
+    mmc1_write(&mut rom, 0xE000, 0x10);
+    rom.mapper.cpu_write(0x6000, 0x24);
+    assert_eq!(rom.mapper.cpu_read(0x6000), 0);
+    mmc1_write(&mut rom, 0xE000, 0x00);
+    assert_eq!(rom.mapper.cpu_read(0x6000), 0x42);
+}
+
+#[test]
+fn test_mmc3_banking() {
+    // 8K PRG banks / 1K CHR banks tagged with their bank number
+    let mut prg = vec![0u8; 0x10000];
+    for bank in 0..8 {
+        prg[bank * 0x2000] = bank as u8;
+    }
+    let mut chr = vec![0u8; 0x8000];
+    for bank in 0..32 {
+        chr[bank * 0x400] = bank as u8;
+    }
+    let mut rom = Rom::load(&make_test_rom_with_mapper(&prg, &chr, false, 4)).unwrap();
+    rom.mapper.cpu_write(0x8000, 6);
+    rom.mapper.cpu_write(0x8001, 2);
+    rom.mapper.cpu_write(0x8000, 7);
+    rom.mapper.cpu_write(0x8001, 3);
+    assert_eq!(rom.mapper.cpu_read(0x8000), 2);
+    assert_eq!(rom.mapper.cpu_read(0xA000), 3);
+    assert_eq!(rom.mapper.cpu_read(0xC000), 6);
+    assert_eq!(rom.mapper.cpu_read(0xE000), 7);
+    // PRG mode 1 swaps $8000 and $C000
+    rom.mapper.cpu_write(0x8000, 0x40);
+    assert_eq!(rom.mapper.cpu_read(0x8000), 6);
+    assert_eq!(rom.mapper.cpu_read(0xC000), 2);
+
+    rom.mapper.cpu_write(0x8000, 0);
+    rom.mapper.cpu_write(0x8001, 9); // 2K bank ignores bit 0
+    rom.mapper.cpu_write(0x8000, 2);
+    rom.mapper.cpu_write(0x8001, 20);
+    assert_eq!(rom.mapper.ppu_read(0x0000), 8);
+    assert_eq!(rom.mapper.ppu_read(0x0400), 9);
+    assert_eq!(rom.mapper.ppu_read(0x1000), 20);
+    // CHR A12 inversion
+    rom.mapper.cpu_write(0x8000, 0x80);
+    assert_eq!(rom.mapper.ppu_read(0x0000), 20);
+    assert_eq!(rom.mapper.ppu_read(0x1000), 8);
+
+    rom.mapper.cpu_write(0xA000, 1);
+    assert_eq!(rom.mapper.mirroring(), MirroringMode::Horizontal);
+    rom.mapper.cpu_write(0xA000, 0);
+    assert_eq!(rom.mapper.mirroring(), MirroringMode::Vertical);
+}
+
+#[test]
+fn test_mmc3_a12_filter() {
+    let mut rom = Rom::load(&make_test_rom_with_mapper(&[0u8; 0x8000], &[0u8; 0x2000], false, 4)).unwrap();
+    rom.mapper.cpu_write(0xC000, 1);
+    rom.mapper.cpu_write(0xC001, 0);
+    rom.mapper.cpu_write(0xE001, 0);
+    // First rising edge reloads the counter
+    for _ in 0..4 {
+        rom.mapper.clock_cpu();
+    }
+    rom.mapper.notify_ppu_addr(0x1000);
+    assert!(!rom.mapper.irq());
+    // A short low pulse is filtered out
+    rom.mapper.notify_ppu_addr(0x0000);
+    rom.mapper.clock_cpu();
+    rom.mapper.notify_ppu_addr(0x1000);
+    assert!(!rom.mapper.irq());
+    // A long low period lets the next edge decrement the counter to zero
+    rom.mapper.notify_ppu_addr(0x0000);
+    for _ in 0..4 {
+        rom.mapper.clock_cpu();
+    }
+    rom.mapper.notify_ppu_addr(0x1000);
+    assert!(rom.mapper.irq());
+    rom.mapper.cpu_write(0xE000, 0);
+    assert!(!rom.mapper.irq());
+}
+
+#[test]
+fn test_mmc3_scanline_irq_from_ppu() {
+    let mut rom = Rom::load(&make_test_rom_with_mapper(&[0u8; 0x8000], &[0u8; 0x2000], false, 4)).unwrap();
+    let mut ppu = Ppu::new();
+    // BG patterns at $0000, sprite patterns at $1000, rendering on
+    ppu.write(&mut rom, 0x00, 0x08);
+    ppu.write(&mut rom, 0x01, 0x18);
+    rom.mapper.cpu_write(0xC000, 5);
+    rom.mapper.cpu_write(0xC001, 0);
+    rom.mapper.cpu_write(0xE001, 0);
+
+    let mut dots = 0;
+    while !rom.mapper.irq() {
+        ppu.clock(&mut rom);
+        dots += 1;
+        if dots % 3 == 0 {
+            rom.mapper.clock_cpu();
+        }
+        assert!(dots < 341 * 262, "IRQ never fired");
+    }
+    // Line 0 reloads the counter, line 5 brings it to zero at the first sprite fetch
+    assert_eq!(dots / 341, 5);
+    assert!((257..=264).contains(&(dots % 341)), "IRQ fired at dot {}", dots % 341);
+}
+
+#[test]
+fn test_uxrom_banking_with_bus_conflict() {
+    let mut prg = make_banked_prg(8);
+    // Bank select table in the fixed bank
+    prg[0x1FF00] = 0xFF;
+    prg[0x1FF01] = 0x01;
+    let mut rom = Rom::load(&make_test_rom_with_mapper(&prg, &[], false, 2)).unwrap();
+    assert_eq!(rom.mapper.cpu_read(0xC000), 7);
+    rom.mapper.cpu_write(0xFF00, 3);
+    assert_eq!(rom.mapper.cpu_read(0x8000), 3);
+    assert_eq!(rom.mapper.cpu_read(0xC000), 7);
+    // Written value is ANDed with the ROM byte at the target address
+    rom.mapper.cpu_write(0xFF01, 3);
+    assert_eq!(rom.mapper.cpu_read(0x8000), 1);
+}
+
+#[test]
+fn test_cnrom_chr_banking() {
+    let mut chr = vec![0u8; 0x8000];
+    for bank in 0..4 {
+        chr[bank * 0x2000 + 0x10] = bank as u8;
+    }
+    let mut prg = vec![0u8; 0x8000];
+    prg[0x7000] = 0xFF;
+    let mut rom = Rom::load(&make_test_rom_with_mapper(&prg, &chr, true, 3)).unwrap();
+    rom.mapper.cpu_write(0xF000, 2);
+    assert_eq!(rom.mapper.ppu_read(0x0010), 2);
+    assert_eq!(rom.mapper.mirroring(), MirroringMode::Vertical);
+}
+
+#[test]
+fn test_axrom_single_screen() {
+    let mut rom = Rom::load(&make_test_rom_with_mapper(&make_banked_prg(8), &[], false, 7)).unwrap();
+    assert_eq!(rom.mapper.mirroring(), MirroringMode::SingleScreenLower);
+    rom.mapper.cpu_write(0x8000, 0x12);
+    assert_eq!(rom.mapper.cpu_read(0x8000), 4);
+    assert_eq!(rom.mapper.cpu_read(0xC000), 5);
+    assert_eq!(rom.mapper.mirroring(), MirroringMode::SingleScreenUpper);
+
+    // All four nametables show the same 1K page
+    let mut ppu = Ppu::new();
+    ppu.write(&mut rom, 0x06, 0x20);
+    ppu.write(&mut rom, 0x06, 0x00);
+    ppu.write(&mut rom, 0x07, 0x77);
+    ppu.write(&mut rom, 0x06, 0x2C);
+    ppu.write(&mut rom, 0x06, 0x00);
+    ppu.read(&mut rom, 0x07);
+    assert_eq!(ppu.read(&mut rom, 0x07), 0x77);
+}
+
+#[test]
+fn test_gxrom_banking() {
+    let mut prg = vec![0u8; 0x20000];
+    for bank in 0..4 {
+        prg[bank * 0x8000] = bank as u8;
+        prg[bank * 0x8000 + 0x7FFF] = 0xFF;
+    }
+    let mut chr = vec![0u8; 0x8000];
+    for bank in 0..4 {
+        chr[bank * 0x2000] = bank as u8;
+    }
+    let mut rom = Rom::load(&make_test_rom_with_mapper(&prg, &chr, false, 66)).unwrap();
+    rom.mapper.cpu_write(0xFFFF, 0x21);
+    assert_eq!(rom.mapper.cpu_read(0x8000), 2);
+    assert_eq!(rom.mapper.ppu_read(0x0000), 1);
 }
 
 #[test]
@@ -94,6 +512,174 @@ fn test_nes_palette_first_color() {
     assert_eq!(NES_PALETTE[0], [84, 84, 84]);
 }
 
+#[test]
+fn test_nes_palette_512() {
+    assert_eq!(NES_PALETTE_512[..64], NES_PALETTE[..]);
+    // Red emphasis keeps red and darkens green and blue
+    let [r, g, b] = NES_PALETTE[0x20];
+    assert_eq!(
+        NES_PALETTE_512[0x40 | 0x20],
+        [r, (g as u16 * 3 / 4) as u8, (b as u16 * 3 / 4) as u8]
+    );
+    // All three darken everything
+    assert!(NES_PALETTE_512[0x1C0 | 0x20]
+        .iter()
+        .zip(&NES_PALETTE[0x20])
+        .all(|(a, b)| a < b));
+}
+
+#[test]
+fn test_palette_from_pal() {
+    assert_eq!(Palette::from_pal(&[0; 100]).err(), Some(PaletteError::InvalidSize(100)));
+
+    let mut data = vec![0u8; 192];
+    data[0x30 * 3..][..3].copy_from_slice(&[200, 100, 40]);
+    let palette = Palette::from_pal(&data).unwrap();
+    assert_eq!(palette.rgb(0x30), [200, 100, 40]);
+    // Green emphasis darkens red and blue
+    assert_eq!(palette.rgb(0x80 | 0x30), [150, 100, 30]);
+
+    let data: Vec<u8> = (0..1536).map(|i| i as u8).collect();
+    let palette = Palette::from_pal(&data).unwrap();
+    assert_eq!(palette.rgb(0x1FF), [0xFD, 0xFE, 0xFF]);
+}
+
+#[test]
+fn test_palette_presets() {
+    assert_eq!(Palette::default().colors(), &NES_PALETTE_512);
+    for preset in PalettePreset::ALL {
+        let palette = Palette::preset(preset);
+        assert_eq!(palette.rgb(0x0D), [0, 0, 0], "{}", preset.name());
+        assert!(palette.rgb(0x30).iter().all(|&c| c > 230), "{}", preset.name());
+        // $16 is red, $1A green and $12 blue
+        let [r, g, b] = palette.rgb(0x16);
+        assert!(r > g && r > b, "{}", preset.name());
+        let [r, g, b] = palette.rgb(0x1A);
+        assert!(g > r && g > b, "{}", preset.name());
+        let [r, g, b] = palette.rgb(0x12);
+        assert!(b > r && b > g, "{}", preset.name());
+    }
+    assert_eq!(Palette::preset(PalettePreset::Ntsc2C02).rgb(0x16), [131, 46, 36]);
+}
+
+#[test]
+fn test_ntsc_filter_flat_color() {
+    let screen = Box::new([0x16u16; 256 * 240]);
+    let output = ntsc::filter(&screen, 0, &Default::default());
+    assert_eq!(output.len(), ntsc::OUTPUT_WIDTH * ntsc::OUTPUT_HEIGHT);
+    // Away from the edges a flat area decodes to the same color as the measured palette
+    let expected = Palette::preset(PalettePreset::Ntsc2C02).rgb(0x16);
+    let pixel = output[100 * ntsc::OUTPUT_WIDTH + 300];
+    assert!(
+        pixel.iter().zip(&expected).all(|(&a, &b)| a.abs_diff(b) <= 1),
+        "{:?}",
+        pixel
+    );
+
+    let settings = ntsc::FilterSettings { saturation: -1.0, ..Default::default() };
+    let [r, g, b] = ntsc::filter(&screen, 0, &settings)[100 * ntsc::OUTPUT_WIDTH + 300];
+    assert!(r == g && g == b);
+}
+
+#[test]
+fn test_ntsc_filter_artifacts() {
+    // Single pixel black and white stripes produce artifact colors that move with the burst phase
+    let mut screen = Box::new([0x0Fu16; 256 * 240]);
+    for (i, pixel) in screen.iter_mut().enumerate() {
+        if i % 2 == 0 {
+            *pixel = 0x30;
+        }
+    }
+    let settings = Default::default();
+    let frame0 = ntsc::filter(&screen, 0, &settings);
+    let frame1 = ntsc::filter(&screen, 1, &settings);
+    assert!(frame0.iter().any(|[r, g, b]| r != g || g != b));
+    assert_ne!(frame0, frame1);
+}
+
+#[test]
+fn test_scale_flat_image() {
+    let input = vec![[10, 20, 30]; 5 * 4];
+    for scaler in Scaler::ALL {
+        let factor = scaler.factor();
+        let output = scale(&input, 5, 4, scaler);
+        assert_eq!(output.len(), 5 * 4 * factor * factor, "{}", scaler.name());
+        assert!(output.iter().all(|&pixel| pixel == [10, 20, 30]), "{}", scaler.name());
+    }
+}
+
+#[test]
+fn test_scale_diagonal_edge() {
+    // White below the diagonal, black above it
+    const SIZE: usize = 8;
+    let input: Vec<[u8; 3]> = (0..SIZE * SIZE)
+        .map(|i| if i % SIZE <= i / SIZE { [255; 3] } else { [0; 3] })
+        .collect();
+    let transpose = |image: &[[u8; 3]], size: usize| -> Vec<[u8; 3]> {
+        (0..size * size).map(|i| image[(i % size) * size + i / size]).collect()
+    };
+    for scaler in Scaler::ALL {
+        let factor = scaler.factor();
+        let output = scale(&input, SIZE, SIZE, scaler);
+        let nearest: Vec<[u8; 3]> = (0..output.len())
+            .map(|i| input[(i / (SIZE * factor)) / factor * SIZE + (i % (SIZE * factor)) / factor])
+            .collect();
+        // The staircase gets smoothed
+        assert_ne!(output, nearest, "{}", scaler.name());
+        // Every direction is treated the same
+        assert_eq!(
+            scale(&transpose(&input, SIZE), SIZE, SIZE, scaler),
+            transpose(&output, SIZE * factor),
+            "{}",
+            scaler.name()
+        );
+    }
+    // Scale2x only moves existing colors around, the others blend
+    let output = scale(&input, SIZE, SIZE, Scaler::Scale2x);
+    assert!(output.iter().all(|&pixel| pixel == [0; 3] || pixel == [255; 3]));
+    let output = scale(&input, SIZE, SIZE, Scaler::Xbr2x);
+    assert!(output.iter().any(|&pixel| pixel != [0; 3] && pixel != [255; 3]));
+}
+
+#[test]
+fn test_scale_xbr_edges() {
+    // '#' is white, '.' is black; returns the gray levels of the output block at (x, y)
+    let block = |rows: &[&str], scaler: Scaler, x: usize, y: usize| -> Vec<Vec<u8>> {
+        let (width, height) = (rows[0].len(), rows.len());
+        let input: Vec<[u8; 3]> = rows
+            .iter()
+            .flat_map(|row| row.chars().map(|c| if c == '#' { [255; 3] } else { [0; 3] }))
+            .collect();
+        let factor = scaler.factor();
+        let output = scale(&input, width, height, scaler);
+        (0..factor)
+            .map(|row| {
+                let start = (y * factor + row) * width * factor + x * factor;
+                output[start..start + factor].iter().map(|pixel| pixel[0]).collect()
+            })
+            .collect()
+    };
+    // A shallow staircase blends into the pixel to the left of the corner, a steep one upwards
+    let shallow = ["........", "......##", "....####", "..######", "########", "########"];
+    let steep = [
+        "....##", "....##", "...###", "...###", "..####", "..####", ".#####", ".#####",
+    ];
+    assert_eq!(block(&shallow, Scaler::Xbr2x, 3, 2), [[0, 0], [63, 191]]);
+    assert_eq!(block(&steep, Scaler::Xbr2x, 2, 3), [[0, 63], [0, 191]]);
+    assert_eq!(
+        block(&shallow, Scaler::Xbr3x, 3, 2),
+        [[0, 0, 0], [0, 0, 63], [63, 191, 255]]
+    );
+    assert_eq!(
+        block(&steep, Scaler::Xbr3x, 2, 3),
+        [[0, 0, 63], [0, 0, 191], [0, 63, 255]]
+    );
+    // A one pixel wide diagonal line: its neighbours share a color, so neither direction applies
+    let line = ["#####", "###.#", "##.##", "#####", "#####"];
+    assert_eq!(block(&line, Scaler::Xbr2x, 2, 2), [[127, 0], [127, 127]]);
+    assert_eq!(block(&line, Scaler::Xbr2x, 3, 1), [[127, 127], [0, 127]]);
+}
+
 #[test]
 fn test_get_addr() {
     assert_eq!(get_addr(0x12, 0x34), 0x1234);
@@ -103,7 +689,9 @@ fn test_get_addr() {
 
 #[test]
 fn test_ppu_new_horizontal() {
-    let ppu = Ppu::new(MirroringMode::Horizontal, false);
+    let rom = Rom::load(&make_test_rom(&[0u8; 0x4000], &[0u8; 0x2000], false)).unwrap();
+    assert_eq!(rom.mapper.mirroring(), MirroringMode::Horizontal);
+    let ppu = Ppu::new();
     let screen = ppu.get_screen();
     // Initially all pixels should be 0
     assert!(screen.iter().all(|&p| p == 0));
@@ -111,11 +699,226 @@ fn test_ppu_new_horizontal() {
 
 #[test]
 fn test_ppu_new_vertical() {
-    let ppu = Ppu::new(MirroringMode::Vertical, false);
+    let rom = Rom::load(&make_test_rom(&[0u8; 0x4000], &[0u8; 0x2000], true)).unwrap();
+    assert_eq!(rom.mapper.mirroring(), MirroringMode::Vertical);
+    let ppu = Ppu::new();
     let screen = ppu.get_screen();
     assert!(screen.iter().all(|&p| p == 0));
 }
 
+#[test]
+fn test_ppu_mid_frame_scroll_split() {
+    // Tile 1 is solid color 1, tile 0 is empty
+    let mut chr = vec![0u8; 0x2000];
+    chr[0x10..0x18].fill(0xFF);
+    let mut rom = Rom::load(&make_test_rom(&[0u8; 0x4000], &chr, false)).unwrap();
+    let mut ppu = Ppu::new();
+    let clock = |ppu: &mut Ppu, rom: &mut Rom, dots: usize| {
+        for _ in 0..dots {
+            ppu.clock(rom);
+        }
+    };
+    // Fill the bottom nametable ($2800) with tile 1
+    ppu.write(&mut rom, 0x06, 0x28);
+    ppu.write(&mut rom, 0x06, 0x00);
+    for _ in 0..0x3C0 {
+        ppu.write(&mut rom, 0x07, 0x01);
+    }
+    ppu.write(&mut rom, 0x06, 0x3F);
+    ppu.write(&mut rom, 0x06, 0x00);
+    ppu.write(&mut rom, 0x07, 0x0F);
+    ppu.write(&mut rom, 0x07, 0x30);
+    ppu.write(&mut rom, 0x00, 0x00);
+    ppu.write(&mut rom, 0x05, 0x00);
+    ppu.write(&mut rom, 0x05, 0x00);
+    ppu.write(&mut rom, 0x01, 0x0A);
+    clock(&mut ppu, &mut rom, 262 * 341);
+
+    // Point v at the bottom nametable during hblank of line 100
+    clock(&mut ppu, &mut rom, 100 * 341 + 300);
+    ppu.write(&mut rom, 0x06, 0x28);
+    ppu.write(&mut rom, 0x06, 0x00);
+    clock(&mut ppu, &mut rom, 262 * 341 - (100 * 341 + 300));
+
+    let screen = ppu.get_screen();
+    assert!(screen[..101 * 256].iter().all(|&p| p == 0x0F));
+    assert!(screen[101 * 256..].iter().all(|&p| p == 0x30));
+}
+
+#[test]
+fn test_ppu_sprite_rendering() {
+    let mut chr = vec![0u8; 0x2000];
+    chr[0x10..0x18].fill(0xFF);
+    let mut rom = Rom::load(&make_test_rom(&[0u8; 0x4000], &chr, false)).unwrap();
+    let mut ppu = Ppu::new();
+    // Background of solid tile 1 everywhere
+    ppu.write(&mut rom, 0x06, 0x20);
+    ppu.write(&mut rom, 0x06, 0x00);
+    for _ in 0..0x3C0 {
+        ppu.write(&mut rom, 0x07, 0x01);
+    }
+    ppu.write(&mut rom, 0x06, 0x3F);
+    ppu.write(&mut rom, 0x06, 0x01);
+    ppu.write(&mut rom, 0x07, 0x30);
+    ppu.write(&mut rom, 0x06, 0x3F);
+    ppu.write(&mut rom, 0x06, 0x11);
+    ppu.write(&mut rom, 0x07, 0x16);
+    // Sprite 0 at (100, 50) in front of the background; the rest are hidden below the screen
+    let mut oam = [0xFFu8; 0x100];
+    oam[..4].copy_from_slice(&[49, 0x01, 0x00, 100]);
+    ppu.dma_write(&oam);
+    ppu.write(&mut rom, 0x00, 0x00);
+    ppu.write(&mut rom, 0x05, 0x00);
+    ppu.write(&mut rom, 0x05, 0x00);
+    ppu.write(&mut rom, 0x01, 0x1E);
+    for _ in 0..262 * 341 {
+        ppu.clock(&mut rom);
+    }
+    for _ in 0..200 * 341 {
+        ppu.clock(&mut rom);
+    }
+    assert_eq!(ppu.read(&mut rom, 0x02) & 0b0100_0000, 0b0100_0000);
+    for _ in 0..62 * 341 {
+        ppu.clock(&mut rom);
+    }
+
+    let screen = ppu.get_screen();
+    for y in 0..240 {
+        for x in 0..256 {
+            let expected = if (50..58).contains(&y) && (100..108).contains(&x) {
+                0x16
+            } else {
+                0x30
+            };
+            assert_eq!(screen[y * 256 + x], expected, "({}, {})", x, y);
+        }
+    }
+}
+
+/// PPU with a transparent background, solid sprite tile 1 drawn in color $16 and the given OAM
+fn make_sprite_test_ppu(oam: &[u8; 0x100]) -> (Ppu, Rom) {
+    let mut chr = vec![0u8; 0x2000];
+    chr[0x10..0x18].fill(0xFF);
+    let mut rom = Rom::load(&make_test_rom(&[0u8; 0x4000], &chr, false)).unwrap();
+    let mut ppu = Ppu::new();
+    ppu.write(&mut rom, 0x06, 0x3F);
+    ppu.write(&mut rom, 0x06, 0x11);
+    ppu.write(&mut rom, 0x07, 0x16);
+    ppu.dma_write(oam);
+    ppu.write(&mut rom, 0x00, 0x00);
+    ppu.write(&mut rom, 0x05, 0x00);
+    ppu.write(&mut rom, 0x05, 0x00);
+    ppu.write(&mut rom, 0x01, 0x1E);
+    (ppu, rom)
+}
+
+/// Run one frame and return the sprite overflow flag as seen in the middle of it
+fn run_sprite_test_frame(ppu: &mut Ppu, rom: &mut Rom) -> bool {
+    for _ in 0..200 * 341 {
+        ppu.clock(rom);
+    }
+    let overflow = ppu.read(rom, 0x02) & 0b0010_0000 != 0;
+    for _ in 0..62 * 341 {
+        ppu.clock(rom);
+    }
+    overflow
+}
+
+#[test]
+fn test_ppu_sprite_limit_and_overflow() {
+    // 9 sprites side by side on lines 50-57
+    let mut oam = [0xFFu8; 0x100];
+    for i in 0..9 {
+        oam[i * 4..][..4].copy_from_slice(&[49, 0x01, 0x00, i as u8 * 10]);
+    }
+    let (mut ppu, mut rom) = make_sprite_test_ppu(&oam);
+    run_sprite_test_frame(&mut ppu, &mut rom);
+    assert!(run_sprite_test_frame(&mut ppu, &mut rom));
+    let line = &ppu.get_screen()[50 * 256..51 * 256];
+    assert!((0..8).all(|i| line[i * 10] == 0x16));
+    assert_eq!(line[80], 0x00);
+
+    // Without the limit the 9th sprite shows up and the flag is unaffected
+    ppu.set_sprite_limit(false);
+    assert!(run_sprite_test_frame(&mut ppu, &mut rom));
+    assert_eq!(ppu.get_screen()[50 * 256 + 80], 0x16);
+}
+
+#[test]
+fn test_ppu_sprite_overflow_bug() {
+    // 8 sprites on lines 50-57 and none after them, so the flag should stay clear...
+    let mut oam = [0xFFu8; 0x100];
+    for i in 0..8 {
+        oam[i * 4..][..4].copy_from_slice(&[49, 0x01, 0x00, i as u8 * 10]);
+    }
+    let (mut ppu, mut rom) = make_sprite_test_ppu(&oam);
+    run_sprite_test_frame(&mut ppu, &mut rom);
+    assert!(!run_sprite_test_frame(&mut ppu, &mut rom));
+
+    // ...but after sprite 8 the overflow check reads sprite 9's tile number as its Y
+    oam[9 * 4 + 1] = 49;
+    ppu.dma_write(&oam);
+    assert!(run_sprite_test_frame(&mut ppu, &mut rom));
+}
+
+#[test]
+fn test_ppu_8x16_sprites() {
+    // Tiles 2 and 3 of the $1000 table: top half color 1, bottom half color 2
+    let mut chr = vec![0u8; 0x2000];
+    chr[0x1020..0x1028].fill(0xFF);
+    chr[0x1038..0x1040].fill(0xFF);
+    let mut rom = Rom::load(&make_test_rom(&[0u8; 0x4000], &chr, false)).unwrap();
+    let mut ppu = Ppu::new();
+    ppu.write(&mut rom, 0x06, 0x3F);
+    ppu.write(&mut rom, 0x06, 0x11);
+    ppu.write(&mut rom, 0x07, 0x16);
+    ppu.write(&mut rom, 0x07, 0x27);
+    // Tile $03 selects the $1000 table even though PPUCTRL points sprites at $0000.
+    // The second sprite is flipped vertically.
+    let mut oam = [0xFFu8; 0x100];
+    oam[..8].copy_from_slice(&[49, 0x03, 0x00, 16, 49, 0x03, 0x80, 32]);
+    ppu.dma_write(&oam);
+    ppu.write(&mut rom, 0x00, 0x20);
+    ppu.write(&mut rom, 0x05, 0x00);
+    ppu.write(&mut rom, 0x05, 0x00);
+    ppu.write(&mut rom, 0x01, 0x1E);
+    for _ in 0..2 * 262 * 341 {
+        ppu.clock(&mut rom);
+    }
+
+    let screen = ppu.get_screen();
+    for y in 48..68 {
+        let (top, flipped) = match y {
+            50..=57 => (0x16, 0x27),
+            58..=65 => (0x27, 0x16),
+            _ => (0x00, 0x00),
+        };
+        assert_eq!(screen[y * 256 + 16], top, "line {}", y);
+        assert_eq!(screen[y * 256 + 32], flipped, "line {}", y);
+    }
+}
+
+#[test]
+fn test_ppu_emphasis_and_greyscale() {
+    let mut rom = Rom::load(&make_test_rom(&[0u8; 0x4000], &[0u8; 0x2000], false)).unwrap();
+    let mut ppu = Ppu::new();
+    ppu.write(&mut rom, 0x06, 0x3F);
+    ppu.write(&mut rom, 0x06, 0x00);
+    ppu.write(&mut rom, 0x07, 0x16);
+    // Rendering off: the backdrop color fills the screen
+    ppu.write(&mut rom, 0x01, 0b1010_0000);
+    for _ in 0..262 * 341 {
+        ppu.clock(&mut rom);
+    }
+    assert!(ppu.get_screen().iter().all(|&p| p == 0x16 | 0b101 << 6));
+
+    ppu.write(&mut rom, 0x01, 0b0100_0001);
+    for _ in 0..262 * 341 {
+        ppu.clock(&mut rom);
+    }
+    assert!(ppu.get_screen().iter().all(|&p| p == 0x10 | 0b010 << 6));
+}
+
 #[test]
 fn test_apu_new() {
     let apu = Apu::new();
@@ -124,11 +927,22 @@ fn test_apu_new() {
     let _ = apu; // Just check it constructs without panic
 }
 
+#[test]
+fn test_apu_region_switch_retunes_noise() {
+    let mut apu = Apu::new();
+    apu.set_region(Region::Ntsc);
+    apu.write(0x0E, 0x05);
+    assert_eq!(apu.noise_period(), 96);
+    apu.set_region(Region::Pal);
+    assert_eq!(apu.noise_period(), 88);
+    apu.set_region(Region::Dendy);
+    assert_eq!(apu.noise_period(), 96);
+}
+
 #[test]
 fn test_apu_clock_produces_output() {
     let mut apu = Apu::new();
-    let prg = vec![0u8; 0x4000];
-    let output = apu.clock(&prg);
+    let output = apu.clock();
     // Output should be a finite f32
     assert!(output.is_finite());
 }
@@ -173,6 +987,747 @@ fn test_nes_clock_frame_samples_are_finite() {
     }
 }
 
+#[test]
+fn test_nes_save_ram() {
+    let mut prg = vec![0u8; 0x4000];
+    // LDA #$42 / STA $6001 / JMP $8005
+    prg[..8].copy_from_slice(&[0xA9, 0x42, 0x8D, 0x01, 0x60, 0x4C, 0x05, 0x80]);
+    prg[0x3FFC] = 0x00;
+    prg[0x3FFD] = 0x80;
+    let mut rom_data = make_test_rom(&prg, &[0u8; 0x2000], false);
+
+    // No battery → no save RAM
+    let nes = Nes::new(&rom_data).unwrap();
+    assert!(nes.save_ram().is_none());
+
+    rom_data[6] |= 0b10;
+    let mut nes = Nes::new(&rom_data).unwrap();
+    assert_eq!(nes.save_ram().unwrap().len(), 0x2000);
+    nes.load_save_ram(&[0x11, 0x22, 0x33]);
+    assert!(!nes.is_save_ram_dirty());
+    assert_eq!(&nes.save_ram().unwrap()[..3], &[0x11, 0x22, 0x33]);
+
+    let pad = PadInputs { pad1: Default::default(), pad2: Default::default() };
+    nes.clock_frame(&pad);
+    assert!(nes.is_save_ram_dirty());
+    assert_eq!(&nes.save_ram().unwrap()[..3], &[0x11, 0x42, 0x33]);
+    nes.clear_save_ram_dirty();
+    nes.clock_frame(&pad);
+    // Writing the same value again doesn't mark the RAM dirty
+    assert!(!nes.is_save_ram_dirty());
+}
+
+#[test]
+fn test_nes_reset_and_power_cycle() {
+    let mut prg = vec![0u8; 0x4000];
+    #[rustfmt::skip]
+    let code = [
+        0xEE, 0x00, 0x60,             // INC $6000
+        0xA5, 0x00, 0x8D, 0x01, 0x60, // LDA $00 / STA $6001
+        0x4C, 0x08, 0x80,             // loop: JMP loop
+    ];
+    prg[..code.len()].copy_from_slice(&code);
+    prg[0x3FFC] = 0x00;
+    prg[0x3FFD] = 0x80;
+    let mut rom_data = make_test_rom(&prg, &[0u8; 0x2000], false);
+    rom_data[6] |= 0b10;
+    let pad = PadInputs { pad1: Default::default(), pad2: Default::default() };
+    let mut nes = Nes::new(&rom_data).unwrap();
+    nes.clock_frame(&pad);
+    assert_eq!(&nes.save_ram().unwrap()[..2], &[0x01, 0x00]);
+
+    // Reset re-runs the reset vector without touching memory
+    nes.reset();
+    nes.clock_frame(&pad);
+    assert_eq!(&nes.save_ram().unwrap()[..2], &[0x02, 0x00]);
+
+    // Power cycling refills internal RAM but keeps battery-backed RAM
+    nes.power_cycle(RamPowerOnState::Ones);
+    nes.clock_frame(&pad);
+    assert_eq!(&nes.save_ram().unwrap()[..2], &[0x03, 0xFF]);
+}
+
+#[test]
+fn test_nes_region() {
+    let pad = PadInputs { pad1: Default::default(), pad2: Default::default() };
+    let count_cpu_cycles = |nes: &mut Nes, frames: usize| {
+        let mut cycles = 0;
+        let mut ppu_clocks = 0;
+        for _ in 0..frames {
+            loop {
+                let (end_frame, sample) = nes.clock(&pad);
+                ppu_clocks += 1;
+                cycles += sample.is_some() as usize;
+                if end_frame {
+                    break;
+                }
+            }
+        }
+        (cycles, ppu_clocks)
+    };
+
+    let mut prg = vec![0u8; 0x4000];
+    prg[..3].copy_from_slice(&[0x4C, 0x00, 0x80]); // loop: JMP loop
+    prg[0x3FFC] = 0x00;
+    prg[0x3FFD] = 0x80;
+    let mut rom_data = make_test_rom(&prg, &[0u8; 0x2000], false);
+    assert_eq!(Nes::new(&rom_data).unwrap().region(), Region::Ntsc);
+    rom_data[7] |= 0x08;
+    rom_data[12] = 0x01; // PAL
+    let mut nes = Nes::new(&rom_data).unwrap();
+    assert_eq!(nes.region(), Region::Pal);
+    // 312 lines and 3.2 PPU clocks per CPU clock
+    assert_eq!(
+        count_cpu_cycles(&mut nes, 10),
+        (341 * 312 * 10 * 5 / 16, 341 * 312 * 10)
+    );
+    assert!((nes.region().frame_rate() - 50.007).abs() < 0.001);
+
+    // The region is part of save states
+    let state = nes.save_state();
+    nes.set_region(Region::Ntsc);
+    nes.power_cycle(RamPowerOnState::Zero);
+    assert_eq!(count_cpu_cycles(&mut nes, 3), (341 * 262, 341 * 262 * 3));
+    nes.load_state(&state).unwrap();
+    assert_eq!(nes.region(), Region::Pal);
+
+    nes.set_region(Region::Dendy);
+    nes.power_cycle(RamPowerOnState::Zero);
+    assert_eq!(count_cpu_cycles(&mut nes, 1), (341 * 312 / 3, 341 * 312));
+}
+
+#[test]
+fn test_ppu_region_vblank() {
+    let rom_data = make_test_rom(&[0u8; 0x4000], &[0u8; 0x2000], false);
+    for (region, v_blank_line) in [(Region::Ntsc, 241), (Region::Pal, 241), (Region::Dendy, 291)] {
+        let mut rom = Rom::load(&rom_data).unwrap();
+        let mut ppu = Ppu::new();
+        ppu.set_region(region);
+        let mut v_blank_lines = vec![];
+        for line in 0..region.scanlines() {
+            for _ in 0..341 {
+                ppu.clock(&mut rom);
+            }
+            if ppu.read(&mut rom, 0x02) & 0x80 != 0 {
+                v_blank_lines.push(line);
+            }
+        }
+        // Reading $2002 clears the flag, so it's seen once on the line it gets set
+        assert_eq!(v_blank_lines, [v_blank_line], "{}", region.name());
+    }
+}
+
+#[test]
+fn test_ppu_open_bus() {
+    let mut rom = Rom::load(&make_test_rom(&[0u8; 0x4000], &[0u8; 0x2000], false)).unwrap();
+    let mut ppu = Ppu::new();
+    // Write-only registers read back the last value written to any register
+    ppu.write(&mut rom, 0x02, 0xA5);
+    assert_eq!(ppu.read(&mut rom, 0x00), 0xA5);
+    assert_eq!(ppu.read(&mut rom, 0x05), 0xA5);
+    // $2002 drives only its top 3 bits
+    assert_eq!(ppu.read(&mut rom, 0x02), 0x05);
+    assert_eq!(ppu.read(&mut rom, 0x00), 0x05);
+
+    // Palette reads have the top 2 bits from the latch
+    ppu.write(&mut rom, 0x06, 0x3F);
+    ppu.write(&mut rom, 0x06, 0x00);
+    ppu.write(&mut rom, 0x07, 0x2C);
+    ppu.write(&mut rom, 0x06, 0x3F);
+    ppu.write(&mut rom, 0x06, 0x00);
+    ppu.write(&mut rom, 0x02, 0xFF);
+    assert_eq!(ppu.read(&mut rom, 0x07), 0xEC);
+
+    // The latch decays after about 600ms without being refreshed
+    let clock_frames = |ppu: &mut Ppu, rom: &mut Rom, frames: usize| {
+        for _ in 0..frames * 262 * 341 {
+            ppu.clock(rom);
+        }
+    };
+    ppu.write(&mut rom, 0x02, 0xFF);
+    clock_frames(&mut ppu, &mut rom, 35);
+    assert_eq!(ppu.read(&mut rom, 0x00), 0xFF);
+    clock_frames(&mut ppu, &mut rom, 1);
+    assert_eq!(ppu.read(&mut rom, 0x00), 0x00);
+
+    // OAM reads don't increment the address and the unused attribute bits read as 0
+    ppu.write(&mut rom, 0x03, 0x02);
+    ppu.write(&mut rom, 0x04, 0xFF);
+    ppu.write(&mut rom, 0x03, 0x02);
+    assert_eq!(ppu.read(&mut rom, 0x04), 0xE3);
+    assert_eq!(ppu.read(&mut rom, 0x04), 0xE3);
+}
+
+#[test]
+fn test_ppu_v_blank_race() {
+    // (dots into the vblank line when $2002 is read, flag value read, NMI happened)
+    let cases = [
+        (0, false, true),
+        (1, false, false),
+        (2, true, false),
+        (3, true, false),
+        (4, true, true),
+    ];
+    for (dot, expected_flag, expected_nmi) in cases {
+        let mut rom = Rom::load(&make_test_rom(&[0u8; 0x4000], &[0u8; 0x2000], false)).unwrap();
+        let mut ppu = Ppu::new();
+        ppu.write(&mut rom, 0x00, 0x80);
+        let mut nmi = false;
+        for _ in 0..241 * 341 + dot {
+            nmi |= ppu.clock(&mut rom).1;
+        }
+        let flag = ppu.read(&mut rom, 0x02) & 0x80 != 0;
+        for _ in 0..341 {
+            nmi |= ppu.clock(&mut rom).1;
+        }
+        assert_eq!((flag, nmi), (expected_flag, expected_nmi), "dot {}", dot);
+        // Reading just before the flag is set keeps it from being set at all, later reads clear it
+        let flag_later = ppu.read(&mut rom, 0x02) & 0x80 != 0;
+        assert_eq!(flag_later, dot == 0, "dot {}", dot);
+    }
+}
+
+#[test]
+fn test_ppu_odd_frame_skip() {
+    let rom_data = make_test_rom(&[0u8; 0x4000], &[0u8; 0x2000], false);
+    for (region, rendering, expected) in [
+        (Region::Ntsc, false, 262 * 341 * 2),
+        (Region::Ntsc, true, 262 * 341 * 2 - 1),
+        (Region::Pal, true, 312 * 341 * 2),
+    ] {
+        let mut rom = Rom::load(&rom_data).unwrap();
+        let mut ppu = Ppu::new();
+        ppu.set_region(region);
+        ppu.write(&mut rom, 0x01, if rendering { 0x08 } else { 0x00 });
+        let mut clocks = 0;
+        let mut frames = 0;
+        while frames < 2 {
+            clocks += 1;
+            frames += ppu.clock(&mut rom).0 as usize;
+        }
+        assert_eq!(clocks, expected, "{} rendering: {}", region.name(), rendering);
+    }
+}
+
+#[test]
+fn test_nes_trace() {
+    let mut prg = vec![0u8; 0x4000];
+    #[rustfmt::skip]
+    let code = [
+        0xA9, 0x40,       // LDA #$40
+        0x8D, 0x00, 0x02, // STA $0200
+        0xA2, 0x01,       // LDX #$01
+        0xBD, 0xFF, 0x01, // LDA $01FF,X
+        0xA7, 0x10,       // LAX $10 (unofficial)
+        0x4C, 0x0C, 0x80, // loop: JMP loop
+    ];
+    prg[..code.len()].copy_from_slice(&code);
+    prg[0x3FFC] = 0x00;
+    prg[0x3FFD] = 0x80;
+    let pad = PadInputs { pad1: Default::default(), pad2: Default::default() };
+    let mut nes = Nes::new(&make_test_rom(&prg, &[0u8; 0x2000], false)).unwrap();
+    let lines = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+    let sink = lines.clone();
+    nes.set_trace_callback(Some(Box::new(move |line| sink.borrow_mut().push(line.to_string()))));
+    while lines.borrow().len() < 7 {
+        nes.clock(&pad);
+    }
+    #[rustfmt::skip]
+    let expected = [
+        "8000  A9 40     LDA #$40                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7",
+        "8002  8D 00 02  STA $0200 = 00                  A:40 X:00 Y:00 P:24 SP:FD PPU:  0, 27 CYC:9",
+        "8005  A2 01     LDX #$01                        A:40 X:00 Y:00 P:24 SP:FD PPU:  0, 39 CYC:13",
+        "8007  BD FF 01  LDA $01FF,X @ 0200 = 40         A:40 X:01 Y:00 P:24 SP:FD PPU:  0, 45 CYC:15",
+        "800A  A7 10    *LAX $10 = 00                    A:40 X:01 Y:00 P:24 SP:FD PPU:  0, 60 CYC:20",
+        "800C  4C 0C 80  JMP $800C                       A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 69 CYC:23",
+        "800C  4C 0C 80  JMP $800C                       A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 78 CYC:26",
+    ];
+    assert_eq!(*lines.borrow(), expected);
+
+    nes.set_trace_callback(None);
+    nes.clock_frame(&pad);
+    assert_eq!(lines.borrow().len(), 7);
+}
+
+#[test]
+fn test_cpu_bus_timing() {
+    let mut prg = vec![0u8; 0x4000];
+    #[rustfmt::skip]
+    let code = [
+        0xA9, 0x02,       // LDA #$02
+        0x8D, 0x14, 0x40, // STA $4014
+        0x8D, 0x14, 0x40, // STA $4014
+        0x24, 0x00,       // BIT $00
+        0x8D, 0x14, 0x40, // STA $4014
+        0xA2, 0x02,       // LDX #$02
+        0xBD, 0xFF, 0x01, // LDA $01FF,X (dummy read of $0101)
+        0xEE, 0x00, 0x03, // INC $0300
+        0xA9, 0x10,       // LDA #$10
+        0x8D, 0x15, 0x40, // STA $4015 (DMC fetches $C000)
+        0x4C, 0x1A, 0x80, // loop: JMP loop
+    ];
+    prg[..code.len()].copy_from_slice(&code);
+    prg[0x3FFC] = 0x00;
+    prg[0x3FFD] = 0x80;
+    let pad = PadInputs { pad1: Default::default(), pad2: Default::default() };
+    let mut nes = Nes::new(&make_test_rom(&prg, &[0u8; 0x2000], false)).unwrap();
+    for i in 0..0x100 {
+        nes.poke_cpu(0x0200 + i, i as u8);
+    }
+    nes.poke_cpu(0x0300, 0x05);
+    let cycles = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+    let sink = cycles.clone();
+    nes.set_trace_callback(Some(Box::new(move |line: &str| {
+        let (_, cycle) = line.split_once("CYC:").unwrap();
+        sink.borrow_mut().push(cycle.parse::<u64>().unwrap());
+    })));
+    nes.add_watchpoint(MemorySpace::Cpu, 0x0101..=0x0101, Access::Read);
+    nes.add_watchpoint(MemorySpace::Cpu, 0x0300..=0x0300, Access::Write);
+    nes.add_watchpoint(MemorySpace::Cpu, 0xC000..=0xC000, Access::Read);
+
+    //ダミーリード、RMWの二重書き込み、DMCのDMAはすべてバス上に現れる
+    let mut hits = Vec::new();
+    while let StopReason::Watchpoint { addr, value, access, .. } = nes.step_frame(&pad) {
+        hits.push((addr, value, access));
+    }
+    assert_eq!(
+        hits,
+        [
+            (0x0101, 0x00, Access::Read),
+            (0x0300, 0x05, Access::Write),
+            (0x0300, 0x06, Access::Write),
+            (0xC000, 0xA9, Access::Read),
+        ]
+    );
+
+    //OAM DMAは513サイクル、奇数サイクルで始まると514サイクル
+    let cycles = cycles.borrow();
+    let lengths: Vec<u64> = cycles.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    assert!(matches!(lengths[1], 517 | 518));
+    assert_eq!(lengths[2..7], [4 + 514, 3, 4 + 513, 2, 5]);
+    //DMCのフェッチはCPUを3か4サイクル止める
+    assert_eq!(lengths[7..9], [6, 2]);
+    assert!(matches!(lengths[9], 7 | 8));
+    assert_eq!(lengths[10], 3);
+
+    let expected: Vec<u8> = (0..=255u8).map(|i| if i % 4 == 2 { i & 0xE3 } else { i }).collect();
+    assert_eq!(nes.oam(), &expected[..]);
+}
+
+#[test]
+fn test_dmc_dma_disabled_mid_fetch() {
+    let pad = PadInputs { pad1: Default::default(), pad2: Default::default() };
+    //DMAの要求からgetサイクルまでの間に$4015で止める
+    for delay in 160..=164u8 {
+        for nops in 0..5 {
+            let mut prg = vec![0u8; 0x4000];
+            #[rustfmt::skip]
+            let mut code = vec![
+                0xA9, 0x0F,       // LDA #$0F
+                0x8D, 0x10, 0x40, // STA $4010
+                0xA9, 0x00,       // LDA #$00
+                0x8D, 0x12, 0x40, // STA $4012
+                0xA9, 0x01,       // LDA #$01
+                0x8D, 0x13, 0x40, // STA $4013
+                0xA9, 0x10,       // LDA #$10
+                0x8D, 0x15, 0x40, // STA $4015
+                0xA2, delay,      // LDX #delay
+                0xCA,             // loop: DEX
+                0xD0, 0xFD,       // BNE loop
+            ];
+            code.extend(std::iter::repeat_n(0xEA, nops)); // NOP
+            let end = 0x8000 + code.len() as u16 + 5;
+            #[rustfmt::skip]
+            code.extend([
+                0x8E, 0x15, 0x40,                  // STX $4015
+                0x4C, end as u8, (end >> 8) as u8, // end: JMP end
+            ]);
+            prg[..code.len()].copy_from_slice(&code);
+            prg[0x3FFC] = 0x00;
+            prg[0x3FFD] = 0x80;
+            let mut nes = Nes::new(&make_test_rom(&prg, &[0u8; 0x2000], false)).unwrap();
+            for _ in 0..3 {
+                nes.clock_frame(&pad);
+            }
+            assert_eq!(nes.peek_cpu(0x4015) & 0x10, 0, "delay {} nops {}", delay, nops);
+        }
+    }
+}
+
+#[test]
+fn test_nes_reset_during_dmc_dma() {
+    let mut prg = vec![0u8; 0x4000];
+    #[rustfmt::skip]
+    let code = [
+        0xA9, 0x4F,       // LDA #$4F (loop, fastest rate)
+        0x8D, 0x10, 0x40, // STA $4010
+        0xA9, 0xFF,       // LDA #$FF
+        0x8D, 0x13, 0x40, // STA $4013
+        0xA9, 0x10,       // LDA #$10
+        0x8D, 0x15, 0x40, // STA $4015
+        0x4C, 0x0F, 0x80, // loop: JMP loop
+    ];
+    prg[..code.len()].copy_from_slice(&code);
+    prg[0x3FFC] = 0x00;
+    prg[0x3FFD] = 0x80;
+    let pad = PadInputs { pad1: Default::default(), pad2: Default::default() };
+    let mut nes = Nes::new(&make_test_rom(&prg, &[0u8; 0x2000], false)).unwrap();
+    nes.clock_frame(&pad);
+    let state = nes.save_state();
+
+    //DMCのDMAが取得を待っている間にリセットしても止まらない
+    for cpu_cycles in 100..140 {
+        nes.load_state(&state).unwrap();
+        for _ in 0..cpu_cycles * 3 {
+            nes.clock(&pad);
+        }
+        nes.reset();
+        for _ in 0..30 {
+            nes.clock(&pad);
+        }
+    }
+    nes.clock_frame(&pad);
+    assert_eq!(nes.peek_cpu(0x4015) & 0x10, 0x10);
+}
+
+#[test]
+fn test_debugger() {
+    let mut prg = vec![0u8; 0x4000];
+    #[rustfmt::skip]
+    let code = [
+        0xA2, 0x00,       // $8000: LDX #$00
+        0x20, 0x10, 0x80, // $8002: loop: JSR sub
+        0xE8,             // $8005: INX
+        0x8E, 0x00, 0x03, // $8006: STX $0300
+        0x4C, 0x02, 0x80, // $8009: JMP loop
+    ];
+    #[rustfmt::skip]
+    let sub = [
+        0xA9, 0x20,       // $8010: sub: LDA #$20
+        0x8D, 0x06, 0x20, // $8012: STA $2006
+        0xA9, 0x00,       // $8015: LDA #$00
+        0x8D, 0x06, 0x20, // $8017: STA $2006
+        0x8D, 0x07, 0x20, // $801A: STA $2007
+        0x60,             // $801D: RTS
+    ];
+    prg[..code.len()].copy_from_slice(&code);
+    prg[0x10..0x10 + sub.len()].copy_from_slice(&sub);
+    prg[0x3FFC] = 0x00;
+    prg[0x3FFD] = 0x80;
+    let pad = PadInputs { pad1: Default::default(), pad2: Default::default() };
+    let mut nes = Nes::new(&make_test_rom(&prg, &[0u8; 0x2000], false)).unwrap();
+
+    let loop_break = nes.add_breakpoint(0x8002);
+    assert_eq!(
+        nes.step_frame(&pad),
+        StopReason::Breakpoint { id: loop_break, pc: 0x8002 }
+    );
+    assert_eq!(nes.step_instruction(&pad), StopReason::Done);
+    assert_eq!((nes.cpu_registers().pc, nes.cpu_registers().sp), (0x8010, 0xFB));
+    assert_eq!(nes.step_out(&pad), StopReason::Done);
+    assert_eq!((nes.cpu_registers().pc, nes.cpu_registers().sp), (0x8005, 0xFD));
+
+    let watch = nes.add_watchpoint(MemorySpace::Cpu, 0x0300..=0x0300, Access::Write);
+    assert_eq!(
+        nes.step_frame(&pad),
+        StopReason::Watchpoint { id: watch, space: MemorySpace::Cpu, addr: 0x0300, value: 1, access: Access::Write }
+    );
+    assert!(nes.remove_breakpoint(watch));
+    assert!(!nes.remove_breakpoint(watch));
+    assert_eq!(
+        nes.step_frame(&pad),
+        StopReason::Breakpoint { id: loop_break, pc: 0x8002 }
+    );
+    assert_eq!(nes.step_over(&pad), StopReason::Done);
+    assert_eq!((nes.cpu_registers().pc, nes.cpu_registers().sp), (0x8005, 0xFD));
+
+    assert!(nes.remove_breakpoint(loop_break));
+    let ppu_watch = nes.add_watchpoint(MemorySpace::Ppu, 0x2000..=0x23FF, Access::ReadWrite);
+    assert_eq!(
+        nes.step_frame(&pad),
+        StopReason::Watchpoint {
+            id: ppu_watch,
+            space: MemorySpace::Ppu,
+            addr: 0x2000,
+            value: 0,
+            access: Access::Write
+        }
+    );
+
+    nes.clear_breakpoints();
+    let condition = Condition { register: Register::X, comparison: Comparison::Equal, value: 5 };
+    let conditional = nes.add_conditional_breakpoint(Some(0x8006), condition);
+    assert_eq!(
+        nes.step_frame(&pad),
+        StopReason::Breakpoint { id: conditional, pc: 0x8006 }
+    );
+    assert_eq!(nes.cpu_registers().x, 5);
+
+    nes.clear_breakpoints();
+    assert_eq!(nes.step_scanline(&pad), StopReason::Done);
+    assert_eq!(nes.step_frame(&pad), StopReason::Done);
+}
+
+#[test]
+fn test_nes_peek_poke() {
+    let mut prg = vec![0u8; 0x4000];
+    #[rustfmt::skip]
+    let code = [
+        0xA9, 0x21, 0x8D, 0x06, 0x20, // LDA #$21 / STA $2006
+        0xA9, 0x00, 0x8D, 0x06, 0x20, // LDA #$00 / STA $2006
+        0xA9, 0x55, 0x8D, 0x07, 0x20, // LDA #$55 / STA $2007 ($2100)
+        0xA9, 0x3F, 0x8D, 0x06, 0x20, // LDA #$3F / STA $2006
+        0xA9, 0x00, 0x8D, 0x06, 0x20, // LDA #$00 / STA $2006
+        0xA9, 0x2A, 0x8D, 0x07, 0x20, // LDA #$2A / STA $2007 ($3F00)
+        0x4C, 0x1E, 0x80,             // loop: JMP loop
+    ];
+    prg[..code.len()].copy_from_slice(&code);
+    prg[0x3FFC] = 0x00;
+    prg[0x3FFD] = 0x80;
+    let mut chr = [0u8; 0x2000];
+    chr[0x1005] = 0x77;
+    let pad = PadInputs { pad1: Default::default(), pad2: Default::default() };
+    let mut nes = Nes::new(&make_test_rom(&prg, &chr, false)).unwrap();
+    nes.clock_frame(&pad);
+
+    assert!(nes.poke_cpu(0x0810, 0x42));
+    assert_eq!(nes.wram()[0x10], 0x42);
+    assert_eq!(nes.peek_cpu(0x0010), 0x42);
+    assert!(!nes.poke_cpu(0x8000, 0x00));
+    assert_eq!(nes.peek_cpu(0x8000), 0xA9);
+
+    assert_eq!(nes.peek_ppu(0x2100), 0x55);
+    let nametables = nes.nametables();
+    assert_eq!(
+        (nametables[0x100], nametables[0x500], nametables[0x900]),
+        (0x55, 0x55, 0x00)
+    );
+    let palette = nes.palette_ram();
+    assert_eq!((palette[0x00], palette[0x10]), (0x2A, 0x2A));
+    assert_eq!(nes.chr()[0x1005], 0x77);
+    assert_eq!(nes.oam().len(), 0x100);
+
+    //PPUDATA は v = $3F01 を指している。覗いてもアドレスは進まない
+    assert_eq!(nes.peek_cpu(0x2007), nes.peek_cpu(0x2007));
+    assert_eq!(nes.peek_cpu(0x2007) & 0x3F, nes.palette_ram()[0x01]);
+    //vblank フラグも覗くだけではクリアされない
+    while nes.peek_cpu(0x2002) & 0x80 == 0 {
+        nes.clock(&pad);
+    }
+    assert_eq!(nes.peek_cpu(0x2002) & 0x80, 0x80);
+}
+
+#[test]
+fn test_disassemble() {
+    #[rustfmt::skip]
+    let code = [
+        0xA9, 0x40,       // $C000: LDA #$40
+        0x9D, 0x10, 0x00, // $C002: STA $0010,X
+        0xD0, 0xF9,       // $C005: BNE $C000
+        0xB1, 0x20,       // $C007: LDA ($20),Y
+        0xE7, 0x30,       // $C009: ISB $30 (unofficial)
+        0x1A,             // $C00B: NOP (unofficial)
+        0x20, 0x00, 0xD0, // $C00C: JSR $D000
+        0x6C, 0xFE, 0xFF, // $C00F: JMP ($FFFE)
+        0x02,             // $C012: undefined
+        0x4C, 0x07,       // $C013: JMP cut off by the end
+    ];
+    let instructions = disassemble(&code, 0xC000);
+    let text: Vec<String> = instructions.iter().map(|decoded| decoded.to_string()).collect();
+    assert_eq!(
+        text,
+        [
+            "LDA #$40",
+            "STA $0010,X",
+            "BNE $C000",
+            "LDA ($20),Y",
+            "ISB $30",
+            "NOP",
+            "JSR $D000",
+            "JMP ($FFFE)",
+            "???",
+            "???",
+            "???"
+        ]
+    );
+    let sizes: Vec<u16> = instructions.iter().map(|decoded| decoded.size()).collect();
+    assert_eq!(sizes, [2, 3, 2, 2, 2, 1, 3, 3, 1, 1, 1]);
+    assert_eq!(instructions[2].instruction, Instruction::BNE);
+    assert_eq!(instructions[2].mode, AddressingMode::Relative);
+    assert_eq!(instructions[2].branch_target(), Some(0xC000));
+    assert_eq!(instructions[6].branch_target(), Some(0xD000));
+    assert_eq!(instructions[7].branch_target(), None);
+    let unofficial: Vec<bool> = instructions.iter().map(|decoded| decoded.unofficial).collect();
+    assert_eq!(
+        unofficial,
+        [false, false, false, false, true, true, false, false, true, true, true]
+    );
+    assert_eq!(instructions[1].bytes(), [0x9D, 0x10, 0x00]);
+
+    let source = [
+        ".setcpu \"6502X\"",
+        ".org $C000",
+        "LC000:  lda     #$40",
+        "        sta     a:$0010,x",
+        "        bne     LC000",
+        "        lda     ($20),y",
+        "        isc     $30",
+        "        .byte   $1A",
+        "        jsr     $D000",
+        "        jmp     ($FFFE)",
+        "        .byte   $02",
+        "        .byte   $4C",
+        "        .byte   $07",
+    ];
+    assert_eq!(to_ca65(&instructions), source.join("\n") + "\n");
+}
+
+#[test]
+fn test_ram_power_on_state() {
+    let mut ram = [0x55u8; 16];
+    RamPowerOnState::Alternating.fill(&mut ram);
+    assert_eq!(ram[..8], [0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF]);
+    RamPowerOnState::Zero.fill(&mut ram);
+    assert!(ram.iter().all(|&byte| byte == 0));
+    let mut other = [0u8; 16];
+    RamPowerOnState::Random(1234).fill(&mut ram);
+    RamPowerOnState::Random(1234).fill(&mut other);
+    assert_eq!(ram, other);
+    assert!(ram.iter().any(|&byte| byte != ram[0]));
+}
+
+/// Program that keeps the PPU rendering and the pulse channel changing pitch
+fn make_busy_rom() -> Vec<u8> {
+    let mut prg = vec![0u8; 0x4000];
+    #[rustfmt::skip]
+    let code = [
+        0xA9, 0x1E, 0x8D, 0x01, 0x20, // LDA #$1E / STA $2001
+        0xA9, 0x0F, 0x8D, 0x15, 0x40, // LDA #$0F / STA $4015
+        0xA9, 0xBF, 0x8D, 0x00, 0x40, // LDA #$BF / STA $4000
+        0xE6, 0x00,                   // loop: INC $00
+        0xA5, 0x00, 0x8D, 0x02, 0x40, // LDA $00 / STA $4002
+        0xA9, 0x01, 0x8D, 0x03, 0x40, // LDA #$01 / STA $4003
+        0x4C, 0x0F, 0x80,             // JMP loop
+    ];
+    prg[..code.len()].copy_from_slice(&code);
+    prg[0x3FFC] = 0x00;
+    prg[0x3FFD] = 0x80;
+    let mut chr = vec![0u8; 0x2000];
+    for (i, byte) in chr.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    make_test_rom(&prg, &chr, false)
+}
+
+#[test]
+fn test_nes_save_state_round_trip() {
+    let pad = PadInputs { pad1: Default::default(), pad2: Default::default() };
+    let mut nes = Nes::new(&make_busy_rom()).unwrap();
+    for _ in 0..3 {
+        nes.clock_frame(&pad);
+    }
+    // Stop mid-frame so the CPU and PPU are somewhere in the middle of their work
+    for _ in 0..12345 {
+        nes.clock(&pad);
+    }
+    let state = nes.save_state();
+
+    let mut expected_audio = vec![];
+    for _ in 0..2 {
+        expected_audio.extend_from_slice(nes.clock_frame(&pad));
+    }
+    let expected_screen = nes.get_screen().to_vec();
+    let expected_state = nes.save_state();
+
+    let mut other = Nes::new(&make_busy_rom()).unwrap();
+    other.load_state(&state).unwrap();
+    let mut audio = vec![];
+    for _ in 0..2 {
+        audio.extend_from_slice(other.clock_frame(&pad));
+    }
+    assert_eq!(audio, expected_audio);
+    assert_eq!(other.get_screen().to_vec(), expected_screen);
+    assert_eq!(other.save_state(), expected_state);
+}
+
+#[test]
+fn test_nes_load_state_errors() {
+    let pad = PadInputs { pad1: Default::default(), pad2: Default::default() };
+    let mut nes = Nes::new(&make_busy_rom()).unwrap();
+    nes.clock_frame(&pad);
+    let state = nes.save_state();
+
+    assert_eq!(nes.load_state(b"YN").err(), Some(StateError::UnexpectedEof));
+    assert_eq!(nes.load_state(&[0u8; 16]).err(), Some(StateError::InvalidMagic));
+    let mut future = state.clone();
+    future[4..8].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+    assert_eq!(
+        nes.load_state(&future).err(),
+        Some(StateError::UnsupportedVersion(STATE_VERSION + 1))
+    );
+    assert_eq!(
+        nes.load_state(&state[..state.len() - 1]).err(),
+        Some(StateError::UnexpectedEof)
+    );
+    let mut trailing = state.clone();
+    trailing.push(0);
+    assert_eq!(nes.load_state(&trailing).err(), Some(StateError::InvalidData));
+
+    let mut other_prg = vec![0u8; 0x4000];
+    other_prg[0] = 0xEA;
+    let other = Nes::new(&make_test_rom(&other_prg, &[0u8; 0x2000], false)).unwrap();
+    assert_eq!(nes.load_state(&other.save_state()).err(), Some(StateError::RomMismatch));
+
+    // Failed loads leave the machine untouched
+    assert_eq!(nes.save_state(), state);
+}
+
+#[test]
+fn test_rewind_delta_round_trip() {
+    use super::rewind::*;
+    let from: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+    let mut to = from.clone();
+    to[0] ^= 1;
+    to[500..520].fill(0xAA);
+    to[999] = 0;
+    let delta = encode_delta(&from, &to);
+    assert!(delta.len() < 40);
+    let mut data = from.clone();
+    apply_delta(&mut data, &delta);
+    assert_eq!(data, to);
+    assert!(encode_delta(&from, &from).is_empty());
+}
+
+#[test]
+fn test_nes_rewind() {
+    let pad = PadInputs { pad1: Default::default(), pad2: Default::default() };
+    let mut nes = Nes::new(&make_busy_rom()).unwrap();
+    assert_eq!(nes.rewind(10), 0);
+
+    nes.enable_rewind(8, 2);
+    let mut states = vec![];
+    for _ in 0..20 {
+        nes.clock_frame(&pad);
+        states.push(nes.save_state());
+    }
+    // Snapshots at frames 1, 3, ..., 19; the oldest ones fell out of the buffer
+    assert_eq!(nes.rewind_available_frames(), 15);
+    assert!(nes.rewind_memory_usage() < states[0].len() * 2);
+
+    // 20 frames were run; going back 3 lands on the snapshot after frame 17
+    assert_eq!(nes.rewind(3), 3);
+    assert_eq!(nes.save_state(), states[16]);
+
+    // Running forward again keeps recording
+    nes.clock_frame(&pad);
+    assert_eq!(nes.save_state(), states[17]);
+
+    // Asking for more than is buffered stops at the oldest snapshot
+    assert_eq!(nes.rewind(1000), 13);
+    assert_eq!(nes.save_state(), states[4]);
+    assert_eq!(nes.rewind_available_frames(), 0);
+}
+
 #[test]
 fn test_struct_sizes() {
     use super::cpu::*;
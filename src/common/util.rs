@@ -70,3 +70,30 @@ pub const NES_PALETTE: [[u8; 3]; 64] = [
     [0, 0, 0],
     [0, 0, 0],
 ];
+
+/// `NES_PALETTE` expanded with the 8 combinations of the color emphasis bits, indexed by the
+/// 9-bit pixels of `Nes::get_screen`.
+pub const NES_PALETTE_512: [[u8; 3]; 512] = expand_emphasis(&NES_PALETTE);
+
+/// Expand a 64-color palette to 512 colors. Each emphasis bit darkens the other two channels to about 3/4.
+pub const fn expand_emphasis(palette: &[[u8; 3]; 64]) -> [[u8; 3]; 512] {
+    let mut expanded = [[0; 3]; 512];
+    let mut i = 0;
+    while i < 512 {
+        let color = palette[i & 0x3F];
+        let emphasis = i >> 6;
+        let mut channel = 0;
+        while channel < 3 {
+            let value = color[channel] as u16;
+            //他の色が強調されていたら暗くする
+            expanded[i][channel] = if emphasis & !(1 << channel) != 0 {
+                (value * 3 / 4) as u8
+            } else {
+                value as u8
+            };
+            channel += 1;
+        }
+        i += 1;
+    }
+    expanded
+}
@@ -1,6 +1,8 @@
 use wasm_bindgen::prelude::*;
 use y_nes::nes::*;
-use y_nes::util::NES_PALETTE;
+use y_nes::ntsc;
+use y_nes::palette::{Palette, PalettePreset};
+use y_nes::scale::{self, Scaler};
 extern crate console_error_panic_hook;
 
 #[wasm_bindgen]
@@ -8,6 +10,7 @@ pub struct WasmNes {
     instance: Nes,
     /// RGBA pixel buffer (256×240×4 = 245760 bytes)
     pixel_buffer: Vec<u8>,
+    palette: Palette,
 }
 
 #[wasm_bindgen]
@@ -56,7 +59,11 @@ pub fn pad_new() -> WasmPadInput {
 #[wasm_bindgen]
 pub fn nes_new(rom: Vec<u8>) -> WasmNes {
     console_error_panic_hook::set_once();
-    WasmNes { instance: Nes::new(rom.as_slice()).unwrap(), pixel_buffer: vec![0u8; 256 * 240 * 4] }
+    WasmNes {
+        instance: Nes::new(rom.as_slice()).unwrap(),
+        pixel_buffer: vec![0u8; 256 * 240 * 4],
+        palette: Palette::default(),
+    }
 }
 
 /// Execute one full frame and return audio samples (f32 array at 44100 Hz).
@@ -73,8 +80,8 @@ pub fn nes_clock_frame(nes: &mut WasmNes, pad1: &WasmPadInput) -> Vec<f32> {
 pub fn nes_get_screen_rgba(nes: &mut WasmNes) -> Vec<u8> {
     let screen = nes.instance.get_screen();
     let buf = &mut nes.pixel_buffer;
-    for (i, &color_index) in screen.iter().enumerate() {
-        let color = NES_PALETTE[color_index as usize & 0x3F];
+    for (i, &pixel) in screen.iter().enumerate() {
+        let color = nes.palette.rgb(pixel);
         let offset = i * 4;
         buf[offset] = color[0]; // R
         buf[offset + 1] = color[1]; // G
@@ -84,6 +91,171 @@ pub fn nes_get_screen_rgba(nes: &mut WasmNes) -> Vec<u8> {
     buf.clone()
 }
 
+/// Get the current screen through the NTSC filter as RGBA pixels (602×240×4 bytes).
+/// `burst_phase` should advance every frame for dot crawl; the other parameters range from -1 to 1.
+#[wasm_bindgen]
+pub fn nes_get_screen_ntsc_rgba(
+    nes: &WasmNes,
+    burst_phase: usize,
+    sharpness: f64,
+    saturation: f64,
+    hue: f64,
+) -> Vec<u8> {
+    let settings = ntsc::FilterSettings { sharpness, saturation, hue, ..Default::default() };
+    let rgb = ntsc::filter(nes.instance.get_screen(), burst_phase % 3, &settings);
+    rgb.iter().flat_map(|&[r, g, b]| [r, g, b, 255]).collect()
+}
+
+/// Names of the upscalers, in the order used by nes_get_screen_scaled_rgba
+#[wasm_bindgen]
+pub fn get_scalers() -> Vec<String> {
+    Scaler::ALL.iter().map(|scaler| scaler.name().into()).collect()
+}
+
+/// How many times wider and taller than 256×240 the output of a scaler is
+#[wasm_bindgen]
+pub fn get_scaler_factor(index: usize) -> usize {
+    Scaler::ALL.get(index).map_or(1, |scaler| scaler.factor())
+}
+
+/// Get the current screen upscaled as RGBA pixels ((256×factor)×(240×factor)×4 bytes).
+/// An unknown `index` returns the unscaled screen.
+#[wasm_bindgen]
+pub fn nes_get_screen_scaled_rgba(nes: &WasmNes, index: usize) -> Vec<u8> {
+    let rgb: Vec<[u8; 3]> = nes
+        .instance
+        .get_screen()
+        .iter()
+        .map(|&pixel| nes.palette.rgb(pixel))
+        .collect();
+    let rgb = match Scaler::ALL.get(index) {
+        Some(&scaler) => scale::scale(&rgb, 256, 240, scaler),
+        None => rgb,
+    };
+    rgb.iter().flat_map(|&[r, g, b]| [r, g, b, 255]).collect()
+}
+
+/// Names of the built-in palettes, in the order used by nes_set_palette_preset
+#[wasm_bindgen]
+pub fn get_palette_presets() -> Vec<String> {
+    PalettePreset::ALL.iter().map(|preset| preset.name().into()).collect()
+}
+
+#[wasm_bindgen]
+pub fn nes_set_palette_preset(nes: &mut WasmNes, index: usize) {
+    if let Some(&preset) = PalettePreset::ALL.get(index) {
+        nes.palette = Palette::preset(preset);
+    }
+}
+
+/// Use the colors of a .pal file (192 or 1536 bytes)
+#[wasm_bindgen]
+pub fn nes_load_palette(nes: &mut WasmNes, data: Vec<u8>) -> Result<(), JsValue> {
+    nes.palette = Palette::from_pal(&data).map_err(|err| JsValue::from_str(&err.to_string()))?;
+    Ok(())
+}
+
+/// Battery-backed save RAM (.sav contents), or undefined if the cartridge has no battery.
+#[wasm_bindgen]
+pub fn nes_get_save_ram(nes: &WasmNes) -> Option<Vec<u8>> {
+    nes.instance.save_ram().map(|ram| ram.to_vec())
+}
+
+/// Restore battery-backed save RAM.
+#[wasm_bindgen]
+pub fn nes_load_save_ram(nes: &mut WasmNes, data: Vec<u8>) {
+    nes.instance.load_save_ram(&data);
+}
+
+/// True if the save RAM changed since it was last loaded or persisted.
+#[wasm_bindgen]
+pub fn nes_is_save_ram_dirty(nes: &WasmNes) -> bool {
+    nes.instance.is_save_ram_dirty()
+}
+
+#[wasm_bindgen]
+pub fn nes_clear_save_ram_dirty(nes: &mut WasmNes) {
+    nes.instance.clear_save_ram_dirty();
+}
+
+/// Start recording rewind snapshots (up to `capacity`, one every `interval` frames).
+#[wasm_bindgen]
+pub fn nes_enable_rewind(nes: &mut WasmNes, capacity: usize, interval: u32) {
+    nes.instance.enable_rewind(capacity, interval);
+}
+
+/// Roll back at least `frames` frames. Returns the number of frames rewound (0 when the buffer is empty).
+#[wasm_bindgen]
+pub fn nes_rewind(nes: &mut WasmNes, frames: u32) -> u32 {
+    nes.instance.rewind(frames)
+}
+
+/// Press the reset button
+#[wasm_bindgen]
+pub fn nes_reset(nes: &mut WasmNes) {
+    nes.instance.reset();
+}
+
+/// Turn the console off and on again; internal RAM comes up filled with zeros
+#[wasm_bindgen]
+pub fn nes_power_cycle(nes: &mut WasmNes) {
+    nes.instance.power_cycle(RamPowerOnState::Zero);
+}
+
+/// Enable or disable the 8 sprites per line limit
+#[wasm_bindgen]
+pub fn nes_set_sprite_limit(nes: &mut WasmNes, enabled: bool) {
+    nes.instance.set_sprite_limit(enabled);
+}
+
+/// Read CPU memory without side effects (for RAM watch and cheat search)
+#[wasm_bindgen]
+pub fn nes_peek_cpu(nes: &WasmNes, addr: u16) -> u8 {
+    nes.instance.peek_cpu(addr)
+}
+
+/// Overwrite internal RAM or cartridge PRG-RAM. Returns false for other addresses.
+#[wasm_bindgen]
+pub fn nes_poke_cpu(nes: &mut WasmNes, addr: u16, value: u8) -> bool {
+    nes.instance.poke_cpu(addr, value)
+}
+
+/// Internal RAM ($0000-$07FF)
+#[wasm_bindgen]
+pub fn nes_get_wram(nes: &WasmNes) -> Vec<u8> {
+    nes.instance.wram().to_vec()
+}
+
+/// Names of the console regions, in the order used by nes_get_region and nes_set_region
+#[wasm_bindgen]
+pub fn get_regions() -> Vec<String> {
+    Region::ALL.iter().map(|region| region.name().into()).collect()
+}
+
+/// Index of the current region (chosen from the ROM header on load)
+#[wasm_bindgen]
+pub fn nes_get_region(nes: &WasmNes) -> usize {
+    Region::ALL
+        .iter()
+        .position(|&region| region == nes.instance.region())
+        .unwrap()
+}
+
+/// Switch to another region and power cycle
+#[wasm_bindgen]
+pub fn nes_set_region(nes: &mut WasmNes, index: usize) {
+    if let Some(&region) = Region::ALL.get(index) {
+        nes.instance.set_region(region);
+        nes.instance.power_cycle(RamPowerOnState::Zero);
+    }
+}
+
+/// Frames per second of the current region (about 60 for NTSC, 50 for PAL and Dendy)
+#[wasm_bindgen]
+pub fn nes_get_frame_rate(nes: &WasmNes) -> f64 {
+    nes.instance.region().frame_rate()
+}
+
 /// Legacy per-clock API
 #[wasm_bindgen]
 pub struct WasmClockResult {
@@ -98,8 +270,12 @@ pub fn nes_clock(nes: &mut WasmNes, pad1: &WasmPadInput) -> WasmClockResult {
     WasmClockResult { end_frame: result.0, apu_out: result.1 }
 }
 
-/// Legacy screen API (returns color index array)
+/// Legacy screen API (returns color index array, without emphasis bits)
 #[wasm_bindgen]
 pub fn nes_get_screen(nes: &mut WasmNes) -> Vec<u8> {
-    nes.instance.get_screen().to_vec()
+    nes.instance
+        .get_screen()
+        .iter()
+        .map(|&pixel| (pixel & 0x3F) as u8)
+        .collect()
 }
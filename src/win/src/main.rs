@@ -16,73 +16,7 @@ use windows::{
     },
 };
 use y_nes::nes::*;
-
-const COLORS: [[u8; 3]; 64] = [
-    [84, 84, 84],
-    [0, 30, 116],
-    [8, 16, 144],
-    [48, 0, 136],
-    [68, 0, 100],
-    [92, 0, 48],
-    [84, 4, 0],
-    [60, 24, 0],
-    [32, 42, 0],
-    [8, 58, 0],
-    [0, 64, 0],
-    [0, 60, 0],
-    [0, 50, 60],
-    [0, 0, 0],
-    [0, 0, 0],
-    [0, 0, 0],
-    [152, 150, 152],
-    [8, 76, 196],
-    [48, 50, 236],
-    [92, 30, 228],
-    [136, 20, 176],
-    [160, 20, 100],
-    [152, 34, 32],
-    [120, 60, 0],
-    [84, 90, 0],
-    [40, 114, 0],
-    [8, 124, 0],
-    [0, 118, 40],
-    [0, 102, 120],
-    [0, 0, 0],
-    [0, 0, 0],
-    [0, 0, 0],
-    [236, 238, 236],
-    [76, 154, 236],
-    [120, 124, 236],
-    [176, 98, 236],
-    [228, 84, 236],
-    [236, 88, 180],
-    [236, 106, 100],
-    [212, 136, 32],
-    [160, 170, 0],
-    [116, 196, 0],
-    [76, 208, 32],
-    [56, 204, 108],
-    [56, 180, 204],
-    [60, 60, 60],
-    [0, 0, 0],
-    [0, 0, 0],
-    [236, 238, 236],
-    [168, 204, 236],
-    [188, 188, 236],
-    [212, 178, 236],
-    [236, 174, 236],
-    [236, 174, 212],
-    [236, 180, 176],
-    [228, 196, 144],
-    [204, 210, 120],
-    [180, 222, 120],
-    [168, 226, 144],
-    [152, 226, 180],
-    [160, 214, 228],
-    [160, 162, 160],
-    [0, 0, 0],
-    [0, 0, 0],
-];
+use y_nes::palette::{Palette, PalettePreset};
 
 const WINDOW_TITLE: PCSTR = PCSTR(b"yNES for Windows\0".as_ptr() as _);
 const WINDOW_TITLE_OVERLOAD: PCSTR = PCSTR(b"yNES for Windows - [overload!]\0".as_ptr() as _);
@@ -110,6 +44,9 @@ struct Window {
     occlusion: u32,
     frequency: i64,
     nes: Option<Nes>,
+    save_path: Option<std::path::PathBuf>,
+    last_save_time: i64,
+    palette: Palette,
     test_audio_out: Option<std::fs::File>,
     test_audio_count: u8,
     target_fps: u16,
@@ -152,6 +89,9 @@ impl Window {
             occlusion: 0,
             frequency,
             nes: None,
+            save_path: None,
+            last_save_time: 0,
+            palette: Palette::default(),
             test_audio_out: None,
             test_audio_count: 0,
             target_fps: 60,
@@ -258,7 +198,9 @@ impl Window {
             let current_time = get_time().unwrap();
             let time_diff = current_time - self.start_time;
             let time_diff_sec = (time_diff as f64) / (self.frequency as f64);
-            let current_frames = (time_diff_sec * self.target_fps as f64) as u64;
+            //target_fpsは60を等速とした速度なので、リージョンのフレームレートに合わせる
+            let frame_rate = self.target_fps as f64 * nes.region().frame_rate() / 60.0;
+            let current_frames = (time_diff_sec * frame_rate) as u64;
             let rendered_frames = self.rendered_frames;
             let need_render_frames = current_frames - rendered_frames;
             let overload_frames = need_render_frames.saturating_sub(5);
@@ -272,7 +214,17 @@ impl Window {
 
             if self.target_fps != 60 || self.audio_queue.size() <= 2978 * 4 * 3 {
                 let mut pcm_filled: usize = 0;
-                for _ in 0..std::cmp::min(need_render_frames, 5) {
+                //Backspace押下中は巻き戻す
+                let rewinding = unsafe { GetKeyState(VK_BACK.0.into()) < 0 };
+                let run_frames = if rewinding {
+                    if need_render_frames > 0 {
+                        nes.rewind(std::cmp::min(need_render_frames, 5) as u32);
+                    }
+                    0
+                } else {
+                    std::cmp::min(need_render_frames, 5)
+                };
+                for _ in 0..run_frames {
                     let mut end_frame = false;
                     while end_frame != true {
                         let result = nes.clock(&inputs);
@@ -305,12 +257,18 @@ impl Window {
                 let screen = nes.get_screen();
                 for (index, pixel) in screen.iter().enumerate() {
                     let index = index * 4;
-                    let color = COLORS[*pixel as usize];
+                    let color = self.palette.rgb(*pixel);
                     self.frame_buffer[index] = color[2]; //B
                     self.frame_buffer[index + 1] = color[1]; //G
                     self.frame_buffer[index + 2] = color[0]; //R
                     self.frame_buffer[index + 3] = 0xFF; //A
                 }
+
+                //バッテリーバックアップRAMは1秒ごとに書き出す
+                if current_time - self.last_save_time >= self.frequency {
+                    self.last_save_time = current_time;
+                    self.flush_save_ram();
+                }
             }
 
             let target = self.target.as_ref().unwrap();
@@ -463,6 +421,7 @@ impl Window {
                     LRESULT(0)
                 }
                 WM_DESTROY => {
+                    self.flush_save_ram();
                     PostQuitMessage(0);
                     LRESULT(0)
                 }
@@ -491,7 +450,23 @@ impl Window {
                                 println!("read file error");
                                 return LRESULT(0);
                             }
-                            self.nes = Some(Nes::new(contents.as_slice()).unwrap());
+                            match Nes::new(contents.as_slice()) {
+                                Ok(mut nes) => {
+                                    self.flush_save_ram();
+                                    //30秒分 (2フレームごとに保存)
+                                    nes.enable_rewind(900, 2);
+                                    let save_path = std::path::Path::new(file_path).with_extension("sav");
+                                    if let Ok(save) = std::fs::read(&save_path) {
+                                        nes.load_save_ram(&save);
+                                    }
+                                    self.nes = Some(nes);
+                                    self.save_path = Some(save_path);
+                                }
+                                Err(err) => {
+                                    println!("load rom error: {}", err);
+                                    return LRESULT(0);
+                                }
+                            }
                         }
                         self.start_time = get_time().unwrap();
                         self.rendered_frames = 0;
@@ -504,6 +479,8 @@ impl Window {
                             202 => self.set_window_size(3),
                             203 => self.set_window_size(4),
                             204 => self.set_window_size(5),
+                            210..=213 => self.palette = Palette::preset(PalettePreset::ALL[(param - 210) as usize]),
+                            219 => self.open_palette_file(),
                             _ => panic!(),
                         };
                         LRESULT(0)
@@ -532,6 +509,45 @@ impl Window {
         }
     }
 
+    fn flush_save_ram(&mut self) {
+        if let (Some(nes), Some(save_path)) = (self.nes.as_mut(), self.save_path.as_ref()) {
+            if !nes.is_save_ram_dirty() {
+                return;
+            }
+            if let Some(ram) = nes.save_ram() {
+                if std::fs::write(save_path, ram).is_err() {
+                    println!("write save file error");
+                    return;
+                }
+            }
+            nes.clear_save_ram_dirty();
+        }
+    }
+
+    fn open_palette_file(&mut self) {
+        let mut buffer: [u8; 1024] = [0; 1024];
+        let mut file = OPENFILENAMEA {
+            lStructSize: std::mem::size_of::<OPENFILENAMEA>() as _,
+            hwndOwner: self.handle,
+            lpstrFilter: PCSTR(b"Palette file (*.pal)\0*.pal\0\0".as_ptr() as _),
+            lpstrFile: PSTR(&mut buffer[0]),
+            nMaxFile: 1024,
+            ..Default::default()
+        };
+        unsafe { GetOpenFileNameA(&mut file) };
+        let file_path = unsafe { std::ffi::CStr::from_ptr(buffer.as_ptr() as _) }
+            .to_str()
+            .unwrap();
+        if file_path.is_empty() {
+            return;
+        }
+        match std::fs::read(file_path).map(|data| Palette::from_pal(&data)) {
+            Ok(Ok(palette)) => self.palette = palette,
+            Ok(Err(err)) => println!("load palette error: {}", err),
+            Err(_) => println!("read file error"),
+        }
+    }
+
     fn set_window_size(&self, multiply: i32) {
         let mut window_rect = RECT { ..Default::default() };
         let mut client_rect = RECT { ..Default::default() };